@@ -0,0 +1,163 @@
+// auth.rs
+
+use crate::config::AuthConfig;
+use crate::utils::log_message;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Returns the token file path: `auth.token_file` if set, otherwise `~/.browser-for-remote-token`.
+pub fn get_token_path(config: &AuthConfig) -> PathBuf {
+    if let Some(path) = &config.token_file {
+        return path.clone();
+    }
+    match env::var("HOME") {
+        Ok(home_dir) => PathBuf::from(format!("{}/.browser-for-remote-token", home_dir)),
+        Err(_) => PathBuf::from("/tmp/browser-for-remote-token"),
+    }
+}
+
+/// The token clients must present: `auth.token`, else the token file's, else a new one written
+/// to the token file readable only by the manager's user.
+pub fn load_or_create_token(config: &AuthConfig) -> Result<String, Box<dyn Error>> {
+    if let Some(token) = read_token(config) {
+        return Ok(token);
+    }
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    let path = get_token_path(config);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("Failed to create token file {}: {}", path.display(), e))?;
+    writeln!(file, "{}", token)?;
+    log_message(
+        &format!("Generated an access token in {}", path.display()),
+        "INFO",
+    );
+    Ok(token)
+}
+
+/// The configured token, or the token file's if there is one.
+pub fn read_token(config: &AuthConfig) -> Option<String> {
+    if let Some(token) = &config.token {
+        return Some(token.clone());
+    }
+    fs::read_to_string(get_token_path(config))
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Whether a request carries `token`, as `Authorization: Bearer <token>` or, for clients that
+/// can't set headers such as WebSocket libraries and `EventSource`, a `token` query parameter.
+pub fn is_authorized(headers: &HashMap<String, String>, path: &str, token: &str) -> bool {
+    let presented = headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| query_token(path));
+    presented.is_some_and(|presented| same_token(presented.trim(), token))
+}
+
+fn query_token(path: &str) -> Option<&str> {
+    let (_, query) = path.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+/// `path` without its `token` query parameter, so the token never reaches Chromium.
+pub fn strip_token(path: &str) -> String {
+    let Some((base, query)) = path.split_once('?') else {
+        return path.to_string();
+    };
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.starts_with("token="))
+        .collect();
+    if rest.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, rest.join("&"))
+    }
+}
+
+/// Compares in time independent of where the tokens differ.
+fn same_token(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_taken_from_the_header_or_the_query() {
+        let bearer =
+            HashMap::from([(String::from("authorization"), String::from("Bearer secret"))]);
+        assert!(is_authorized(&bearer, "/json", "secret"));
+        assert!(!is_authorized(&bearer, "/json", "other"));
+        assert!(is_authorized(
+            &HashMap::new(),
+            "/devtools/page/1?token=secret",
+            "secret"
+        ));
+        assert!(!is_authorized(&HashMap::new(), "/json", "secret"));
+        assert!(!is_authorized(
+            &HashMap::new(),
+            "/json?token=secre",
+            "secret"
+        ));
+
+        assert_eq!(
+            strip_token("/devtools/page/1?token=secret"),
+            "/devtools/page/1"
+        );
+        assert_eq!(
+            strip_token("/json/new?token=secret&about:blank"),
+            "/json/new?about:blank"
+        );
+    }
+
+    #[test]
+    fn token_file_is_created_once_and_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AuthConfig {
+            token_file: Some(dir.path().join("token")),
+            ..AuthConfig::default()
+        };
+        assert_eq!(read_token(&config), None);
+        let token = load_or_create_token(&config).unwrap();
+        assert_eq!(token.len(), 40);
+        assert_eq!(load_or_create_token(&config).unwrap(), token);
+        let mode = fs::metadata(dir.path().join("token"))
+            .unwrap()
+            .permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+
+        let fixed = AuthConfig {
+            token: Some(String::from("fixed")),
+            ..config
+        };
+        assert_eq!(load_or_create_token(&fixed).unwrap(), "fixed");
+    }
+}
@@ -0,0 +1,309 @@
+// browser.rs
+
+use crate::config::IdentityProfile;
+use crate::proxy::{apply_proxy_auth, ProxySettings};
+use crate::stealth::{apply_stealth, Fingerprint};
+use headless_chrome::protocol::cdp::types::Event;
+use headless_chrome::protocol::cdp::Browser::{
+    CancelDownload, SetDownloadBehavior, SetDownloadBehaviorBehaviorOption,
+};
+use headless_chrome::protocol::cdp::Page::AddScriptToEvaluateOnNewDocument;
+use headless_chrome::protocol::cdp::Target::CreateTarget;
+use headless_chrome::protocol::cdp::{Inspector, Log, Network, Runtime};
+use headless_chrome::{Browser, Tab};
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Receives the method and params of a tab's console events.
+pub type ConsoleListener = Box<dyn Fn(&str, &Value) + Send + Sync>;
+
+/// Receives the method and params of a tab's download events.
+pub type DownloadListener = Box<dyn Fn(&str, &Value) + Send + Sync>;
+
+/// What the manager needs from a tab. Implemented for headless_chrome's `Tab`, which the unit
+/// tests connect to the mock DevTools endpoint instead of Chromium.
+pub trait ManagedTab: Send + Sync {
+    fn target_id(&self) -> &str;
+    fn url(&self) -> String;
+    fn bring_to_front(&self) -> Result<(), Box<dyn Error>>;
+    fn activate(&self) -> Result<(), Box<dyn Error>>;
+    /// Closes the tab, letting it run its unload handlers.
+    fn close(&self) -> Result<(), Box<dyn Error>>;
+    /// The page's `document.readyState`.
+    fn ready_state(&self) -> Result<String, Box<dyn Error>>;
+    fn navigate(&self, url: &str) -> Result<(), Box<dyn Error>>;
+    /// Presents the identity's user agent, language and platform.
+    fn set_identity(&self, identity: &IdentityProfile) -> Result<(), Box<dyn Error>>;
+    /// Answers the proxy's auth challenges for this tab.
+    fn authenticate_proxy(&self, proxy: &ProxySettings) -> Result<(), Box<dyn Error>>;
+    /// Runs `expression` in the page, awaiting it if it is a promise, and returns its value.
+    fn evaluate(&self, expression: &str) -> Result<Value, Box<dyn Error>>;
+    /// Runs `source` in every document the tab loads from now on, before the page's scripts.
+    fn add_script_on_new_document(&self, source: &str) -> Result<(), Box<dyn Error>>;
+    fn reload(&self) -> Result<(), Box<dyn Error>>;
+    /// Calls `listener` whenever the tab's renderer crashes.
+    fn on_crash(&self, listener: Box<dyn Fn() + Send + Sync>) -> Result<(), Box<dyn Error>>;
+    /// Calls `listener` with the method and params of every `Runtime.consoleAPICalled`,
+    /// `Runtime.exceptionThrown` and `Log.entryAdded` event from the tab.
+    fn on_console(&self, listener: ConsoleListener) -> Result<(), Box<dyn Error>>;
+    /// Saves downloads from every tab in the browser context into `dir`, named by their GUID.
+    fn set_download_dir(&self, browser_context_id: &str, dir: &Path) -> Result<(), Box<dyn Error>>;
+    /// Calls `listener` with the method and params of every `Page.downloadWillBegin`,
+    /// `Page.downloadProgress` and `Network.responseReceived` event from the tab.
+    fn on_download(&self, listener: DownloadListener) -> Result<(), Box<dyn Error>>;
+    fn cancel_download(&self, browser_context_id: &str, guid: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// What the manager needs from the browser.
+pub trait ManagedBrowser {
+    type Tab: ManagedTab + 'static;
+
+    /// The open page tabs, in the order the browser reported them.
+    fn tabs(&self) -> Vec<Arc<Self::Tab>>;
+    /// Opens a tab on `url`, inside an existing browser context when one is given.
+    fn open_tab(
+        &self,
+        url: &str,
+        browser_context_id: Option<String>,
+    ) -> Result<Arc<Self::Tab>, Box<dyn Error>>;
+    /// Creates a browser context, without the profile's cookies and storage, and returns its
+    /// id. It lasts as long as the browser.
+    fn create_browser_context(&self) -> Result<String, Box<dyn Error>>;
+}
+
+impl ManagedTab for Tab {
+    fn target_id(&self) -> &str {
+        self.get_target_id()
+    }
+
+    fn url(&self) -> String {
+        self.get_url()
+    }
+
+    fn bring_to_front(&self) -> Result<(), Box<dyn Error>> {
+        Tab::bring_to_front(self)?;
+        Ok(())
+    }
+
+    fn activate(&self) -> Result<(), Box<dyn Error>> {
+        Tab::activate(self)?;
+        Ok(())
+    }
+
+    fn close(&self) -> Result<(), Box<dyn Error>> {
+        self.close_with_unload()?;
+        Ok(())
+    }
+
+    fn ready_state(&self) -> Result<String, Box<dyn Error>> {
+        let result = self.evaluate("document.readyState", false)?;
+        result
+            .value
+            .and_then(|value| value.as_str().map(String::from))
+            .ok_or_else(|| "document.readyState did not evaluate to a string".into())
+    }
+
+    fn navigate(&self, url: &str) -> Result<(), Box<dyn Error>> {
+        self.navigate_to(url)?;
+        Ok(())
+    }
+
+    fn set_identity(&self, identity: &IdentityProfile) -> Result<(), Box<dyn Error>> {
+        self.set_user_agent(
+            &identity.user_agent,
+            identity.accept_language.as_deref(),
+            identity.platform.as_deref(),
+        )?;
+        Ok(())
+    }
+
+    fn authenticate_proxy(&self, proxy: &ProxySettings) -> Result<(), Box<dyn Error>> {
+        apply_proxy_auth(self, proxy)
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<Value, Box<dyn Error>> {
+        Ok(Tab::evaluate(self, expression, true)?
+            .value
+            .unwrap_or(Value::Null))
+    }
+
+    fn add_script_on_new_document(&self, source: &str) -> Result<(), Box<dyn Error>> {
+        self.call_method(AddScriptToEvaluateOnNewDocument {
+            source: source.to_string(),
+            world_name: None,
+            include_command_line_api: None,
+            run_immediately: None,
+        })?;
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<(), Box<dyn Error>> {
+        Tab::reload(self, false, None)?;
+        Ok(())
+    }
+
+    fn on_crash(&self, listener: Box<dyn Fn() + Send + Sync>) -> Result<(), Box<dyn Error>> {
+        self.add_event_listener(Arc::new(move |event: &Event| {
+            if let Event::InspectorTargetCrashed(_) = event {
+                listener();
+            }
+        }))?;
+        self.call_method(Inspector::Enable(None))?;
+        Ok(())
+    }
+
+    fn on_console(&self, listener: ConsoleListener) -> Result<(), Box<dyn Error>> {
+        self.add_event_listener(Arc::new(move |event: &Event| {
+            let (method, params) = match event {
+                Event::RuntimeConsoleAPICalled(event) => (
+                    "Runtime.consoleAPICalled",
+                    serde_json::to_value(&event.params),
+                ),
+                Event::RuntimeExceptionThrown(event) => (
+                    "Runtime.exceptionThrown",
+                    serde_json::to_value(&event.params),
+                ),
+                Event::LogEntryAdded(event) => {
+                    ("Log.entryAdded", serde_json::to_value(&event.params))
+                }
+                _ => return,
+            };
+            if let Ok(params) = params {
+                listener(method, &params);
+            }
+        }))?;
+        self.call_method(Runtime::Enable(None))?;
+        self.call_method(Log::Enable(None))?;
+        Ok(())
+    }
+
+    fn set_download_dir(&self, browser_context_id: &str, dir: &Path) -> Result<(), Box<dyn Error>> {
+        self.call_method(SetDownloadBehavior {
+            behavior: SetDownloadBehaviorBehaviorOption::AllowAndName,
+            browser_context_id: Some(browser_context_id.to_string()),
+            download_path: Some(dir.to_string_lossy().to_string()),
+            events_enabled: None,
+        })?;
+        Ok(())
+    }
+
+    fn on_download(&self, listener: DownloadListener) -> Result<(), Box<dyn Error>> {
+        self.add_event_listener(Arc::new(move |event: &Event| {
+            let (method, params) = match event {
+                Event::PageDownloadWillBegin(event) => (
+                    "Page.downloadWillBegin",
+                    serde_json::to_value(&event.params),
+                ),
+                Event::PageDownloadProgress(event) => {
+                    ("Page.downloadProgress", serde_json::to_value(&event.params))
+                }
+                Event::NetworkResponseReceived(event) => (
+                    "Network.responseReceived",
+                    serde_json::to_value(&event.params),
+                ),
+                _ => return,
+            };
+            if let Ok(params) = params {
+                listener(method, &params);
+            }
+        }))?;
+        // The response's MIME type is only reported with the Network domain on
+        self.call_method(Network::Enable {
+            max_total_buffer_size: None,
+            max_resource_buffer_size: None,
+            max_post_data_size: None,
+            report_direct_socket_traffic: None,
+            enable_durable_messages: None,
+        })?;
+        Ok(())
+    }
+
+    fn cancel_download(&self, browser_context_id: &str, guid: &str) -> Result<(), Box<dyn Error>> {
+        self.call_method(CancelDownload {
+            guid: guid.to_string(),
+            browser_context_id: Some(browser_context_id.to_string()),
+        })?;
+        Ok(())
+    }
+}
+
+/// Readies a tab the manager takes on, before it navigates: proxy auth, then the identity,
+/// with the fingerprint patches when stealth is on. Returns the fingerprint if it patched one.
+pub fn prepare_tab(
+    tab: &dyn ManagedTab,
+    identity: Option<&IdentityProfile>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) -> Result<Option<Fingerprint>, Box<dyn Error>> {
+    if let Some(proxy) = proxy.filter(|proxy| proxy.has_credentials()) {
+        tab.authenticate_proxy(proxy)
+            .map_err(|e| format!("Failed to set up proxy auth: {}", e))?;
+    }
+    if stealth {
+        let fingerprint =
+            apply_stealth(tab, identity).map_err(|e| format!("Failed to apply stealth: {}", e))?;
+        return Ok(Some(fingerprint));
+    }
+    if let Some(identity) = identity {
+        tab.set_identity(identity)
+            .map_err(|e| format!("Failed to apply identity: {}", e))?;
+    }
+    Ok(None)
+}
+
+/// Opens a tab on `about:blank`, prepares it with `prepare_tab` and only then navigates it to
+/// `url`, so the first page already sees the identity and patches. The tab is closed again if
+/// preparing it fails.
+pub fn open_prepared_tab<B: ManagedBrowser>(
+    browser: &B,
+    url: &str,
+    browser_context_id: Option<String>,
+    identity: Option<&IdentityProfile>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) -> Result<Arc<B::Tab>, Box<dyn Error>> {
+    let tab = browser.open_tab("about:blank", browser_context_id)?;
+    if let Err(e) = prepare_tab(tab.as_ref(), identity, proxy, stealth) {
+        let _ = tab.close();
+        return Err(e);
+    }
+    if url != "about:blank" {
+        tab.navigate(url)?;
+    }
+    Ok(tab)
+}
+
+impl ManagedBrowser for Browser {
+    type Tab = Tab;
+
+    fn tabs(&self) -> Vec<Arc<Tab>> {
+        self.get_tabs().lock().unwrap().clone()
+    }
+
+    fn open_tab(
+        &self,
+        url: &str,
+        browser_context_id: Option<String>,
+    ) -> Result<Arc<Tab>, Box<dyn Error>> {
+        Ok(self.new_tab_with_options(CreateTarget {
+            url: url.to_string(),
+            left: None,
+            top: None,
+            width: None,
+            height: None,
+            window_state: None,
+            browser_context_id,
+            enable_begin_frame_control: None,
+            new_window: None,
+            background: None,
+            for_tab: None,
+            hidden: None,
+        })?)
+    }
+
+    fn create_browser_context(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.new_context()?.get_id().to_string())
+    }
+}
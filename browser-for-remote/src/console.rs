@@ -0,0 +1,351 @@
+// console.rs
+
+use crate::browser::ManagedTab;
+use crate::config::LiveConfig;
+use crate::utils::log_message;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A console message, uncaught exception or browser log entry from a page.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConsoleEntry {
+    /// `console`, `exception` or `log`.
+    pub source: &'static str,
+    /// `debug`, `info`, `warning` or `error`.
+    pub level: &'static str,
+    pub text: String,
+    /// Script or resource the entry came from, with its line, when the browser says.
+    pub location: Option<String>,
+    /// Page the tab was showing.
+    pub page_url: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Console levels, lowest first.
+pub const LEVELS: [&str; 4] = ["debug", "info", "warning", "error"];
+
+/// Rank of a console level in `LEVELS`; unknown levels rank as `error`.
+pub fn level_rank(level: &str) -> u8 {
+    match level {
+        "debug" => 0,
+        "info" => 1,
+        "warning" => 2,
+        _ => 3,
+    }
+}
+
+/// The manager log level a console level is written at.
+fn log_level(level: &str) -> &'static str {
+    match level {
+        "debug" => "DEBUG",
+        "info" => "INFO",
+        "warning" => "WARN",
+        _ => "ERROR",
+    }
+}
+
+/// Turns one of the events `ManagedTab::on_console` reports into an entry.
+pub fn entry_from_event(method: &str, params: &Value, page_url: &str) -> Option<ConsoleEntry> {
+    let (source, level, text, location) = match method {
+        "Runtime.consoleAPICalled" => {
+            let level = match params["type"].as_str().unwrap_or_default() {
+                "debug" => "debug",
+                "warning" => "warning",
+                "error" | "assert" => "error",
+                _ => "info",
+            };
+            let text = params["args"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(remote_object_text)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let frame = &params["stackTrace"]["callFrames"][0];
+            (
+                "console",
+                level,
+                text,
+                location(&frame["url"], &frame["lineNumber"]),
+            )
+        }
+        "Runtime.exceptionThrown" => {
+            let details = &params["exceptionDetails"];
+            let text = details["exception"]["description"]
+                .as_str()
+                .or_else(|| details["text"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            (
+                "exception",
+                "error",
+                text,
+                location(&details["url"], &details["lineNumber"]),
+            )
+        }
+        "Log.entryAdded" => {
+            let entry = &params["entry"];
+            let level = match entry["level"].as_str().unwrap_or_default() {
+                "verbose" => "debug",
+                "info" => "info",
+                "warning" => "warning",
+                _ => "error",
+            };
+            let text = entry["text"].as_str().unwrap_or_default().to_string();
+            (
+                "log",
+                level,
+                text,
+                location(&entry["url"], &entry["lineNumber"]),
+            )
+        }
+        _ => return None,
+    };
+    Some(ConsoleEntry {
+        source,
+        level,
+        text,
+        location,
+        page_url: page_url.to_string(),
+        timestamp: Utc::now(),
+    })
+}
+
+/// How DevTools would print a console argument.
+fn remote_object_text(object: &Value) -> String {
+    match &object["value"] {
+        Value::String(text) => text.clone(),
+        Value::Null => object["description"]
+            .as_str()
+            .or_else(|| object["unserializableValue"].as_str())
+            .or_else(|| object["type"].as_str())
+            .unwrap_or_default()
+            .to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// `url:line`, with DevTools' 0-based `line` shown 1-based.
+fn location(url: &Value, line: &Value) -> Option<String> {
+    let url = url.as_str().filter(|url| !url.is_empty())?;
+    Some(match line.as_u64() {
+        Some(line) => format!("{}:{}", url, line + 1),
+        None => url.to_string(),
+    })
+}
+
+/// The most recent console entries of each watched tab, oldest first.
+#[derive(Debug, Default)]
+pub struct ConsoleBuffers {
+    tabs: HashMap<String, VecDeque<ConsoleEntry>>,
+}
+
+impl ConsoleBuffers {
+    /// Starts a buffer for `tab_id`; only watched tabs have one.
+    pub fn watch(&mut self, tab_id: &str) {
+        self.tabs.entry(tab_id.to_string()).or_default();
+    }
+
+    /// Adds `entry`, dropping the oldest entries beyond `capacity`.
+    pub fn push(&mut self, tab_id: &str, entry: ConsoleEntry, capacity: usize) {
+        let Some(buffer) = self.tabs.get_mut(tab_id) else {
+            return;
+        };
+        buffer.push_back(entry);
+        while buffer.len() > capacity {
+            buffer.pop_front();
+        }
+    }
+
+    /// The tab's entries at `min_level` or above, or `None` if the tab isn't watched.
+    pub fn recent(&self, tab_id: &str, min_level: &str) -> Option<Vec<ConsoleEntry>> {
+        let buffer = self.tabs.get(tab_id)?;
+        Some(
+            buffer
+                .iter()
+                .filter(|entry| level_rank(entry.level) >= level_rank(min_level))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Drops the buffers of tabs `keep` rejects.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.tabs.retain(|tab_id, _| keep(tab_id));
+    }
+}
+
+/// Subscribes to the tab's console and errors. Each entry goes into `buffers` and, at
+/// `console.log_level` or above, into the manager's log with the tab's id and URL.
+pub fn capture_console(
+    tab: &Arc<dyn ManagedTab>,
+    buffers: &Arc<Mutex<ConsoleBuffers>>,
+    live: &Arc<RwLock<LiveConfig>>,
+) -> Result<(), Box<dyn Error>> {
+    let tab_id = tab.target_id().to_string();
+    buffers.lock().unwrap().watch(&tab_id);
+    // The tab owns the listener, so a strong handle would keep the tab alive forever
+    let weak_tab = Arc::downgrade(tab);
+    let (buffers, live) = (Arc::clone(buffers), Arc::clone(live));
+    tab.on_console(Box::new(move |method, params| {
+        let page_url = weak_tab.upgrade().map(|tab| tab.url()).unwrap_or_default();
+        let Some(entry) = entry_from_event(method, params, &page_url) else {
+            return;
+        };
+        let config = live.read().unwrap().config.console.clone();
+        if !config.enabled {
+            return;
+        }
+        if level_rank(entry.level) >= level_rank(&config.log_level) {
+            log_message(
+                &format!(
+                    "Console {} in tab {} ({}): {}{}",
+                    entry.source,
+                    tab_id,
+                    entry.page_url,
+                    entry.text.lines().next().unwrap_or_default(),
+                    entry
+                        .location
+                        .as_ref()
+                        .map(|location| format!(" at {}", location))
+                        .unwrap_or_default()
+                ),
+                log_level(entry.level),
+            );
+        }
+        buffers
+            .lock()
+            .unwrap()
+            .push(&tab_id, entry, config.buffer_size);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, live_config};
+    use mock_devtools::{wait_until, MockDevTools};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn console_calls_join_their_arguments() {
+        let entry = entry_from_event(
+            "Runtime.consoleAPICalled",
+            &json!({
+                "type": "warning",
+                "args": [
+                    { "type": "string", "value": "retrying" },
+                    { "type": "number", "value": 3 },
+                    { "type": "object", "description": "Object" },
+                ],
+                "stackTrace": { "callFrames": [{ "url": "https://app.test/app.js", "lineNumber": 9 }] },
+            }),
+            "https://app.test/",
+        )
+        .unwrap();
+        assert_eq!(entry.source, "console");
+        assert_eq!(entry.level, "warning");
+        assert_eq!(entry.text, "retrying 3 Object");
+        assert_eq!(
+            entry.location.as_deref(),
+            Some("https://app.test/app.js:10")
+        );
+    }
+
+    #[test]
+    fn exceptions_and_log_entries_are_errors_and_levels() {
+        let exception = entry_from_event(
+            "Runtime.exceptionThrown",
+            &json!({ "exceptionDetails": {
+                "text": "Uncaught",
+                "lineNumber": 0,
+                "url": "https://app.test/boot.js",
+                "exception": { "type": "object", "description": "TypeError: x is not a function\n    at boot.js:1" },
+            }}),
+            "https://app.test/",
+        )
+        .unwrap();
+        assert_eq!(exception.level, "error");
+        assert!(exception.text.starts_with("TypeError: x is not a function"));
+        assert_eq!(
+            exception.location.as_deref(),
+            Some("https://app.test/boot.js:1")
+        );
+
+        let log = entry_from_event(
+            "Log.entryAdded",
+            &json!({ "entry": { "source": "network", "level": "verbose", "text": "slow", "timestamp": 0 } }),
+            "https://app.test/",
+        )
+        .unwrap();
+        assert_eq!(log.level, "debug");
+        assert_eq!(log.location, None);
+    }
+
+    #[test]
+    fn buffers_keep_the_newest_entries_and_filter_by_level() {
+        let entry = |level, text: &str| ConsoleEntry {
+            source: "console",
+            level,
+            text: text.to_string(),
+            location: None,
+            page_url: String::new(),
+            timestamp: Utc::now(),
+        };
+        let mut buffers = ConsoleBuffers::default();
+        buffers.push("unwatched", entry("error", "lost"), 2);
+        buffers.watch("tab");
+        buffers.push("tab", entry("info", "one"), 2);
+        buffers.push("tab", entry("error", "two"), 2);
+        buffers.push("tab", entry("info", "three"), 2);
+
+        assert_eq!(buffers.recent("unwatched", "debug"), None);
+        let texts = |level| -> Vec<String> {
+            buffers
+                .recent("tab", level)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.text)
+                .collect()
+        };
+        assert_eq!(texts("debug"), vec!["two", "three"]);
+        assert_eq!(texts("warning"), vec!["two"]);
+    }
+
+    #[test]
+    fn captured_events_land_in_the_tab_buffer() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("https://app.test/");
+        let (_browser, tabs) = connect(&mock, 1);
+        let tab = Arc::clone(&tabs[0]) as Arc<dyn ManagedTab>;
+        let buffers = Arc::new(Mutex::new(ConsoleBuffers::default()));
+        let live = Arc::new(live_config(json!({ "console": { "enabled": true } })));
+
+        capture_console(&tab, &buffers, &live).unwrap();
+        assert_eq!(mock.calls_to("Runtime.enable").len(), 1);
+        mock.emit(
+            &target_id,
+            "Runtime.consoleAPICalled",
+            json!({
+                "type": "error",
+                "args": [{ "type": "string", "value": "boom" }],
+                "executionContextId": 1,
+                "timestamp": 0,
+            }),
+        );
+
+        assert!(wait_until(Duration::from_secs(5), || buffers
+            .lock()
+            .unwrap()
+            .recent(&target_id, "error")
+            .is_some_and(|entries| !entries.is_empty())));
+        let entries = buffers.lock().unwrap().recent(&target_id, "debug").unwrap();
+        assert_eq!(entries[0].text, "boom");
+        assert_eq!(entries[0].page_url, "https://app.test/");
+    }
+}
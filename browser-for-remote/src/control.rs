@@ -0,0 +1,555 @@
+// control.rs
+
+use crate::auth::is_authorized;
+use crate::config::{IdentityProfile, LiveConfig};
+use crate::console::{ConsoleBuffers, LEVELS};
+use crate::events;
+use crate::jobs::JobHistory;
+use crate::leases::{Lease, LeaseFocus, LeaseTable, NewLease};
+use crate::utils::log_message;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// Work the control server hands to the control request task because it needs the browser.
+pub enum ControlRequest {
+    /// Open a tab, optionally inside an existing browser context, and reply with the lease
+    /// granted on it. The lease is released again if the reply finds nobody waiting.
+    OpenTab {
+        lease: NewLease,
+        identity: Option<IdentityProfile>,
+        reply: oneshot::Sender<Result<Lease, String>>,
+    },
+    /// Open a throwaway tab set up like a managed one and reply with what a page can detect.
+    StealthCheck {
+        identity: Option<IdentityProfile>,
+        url: Option<String>,
+        reply: oneshot::Sender<Result<Value, String>>,
+    },
+}
+
+/// Everything a control connection needs, cloned into each connection's task.
+#[derive(Clone)]
+pub struct ControlState {
+    pub leases: Arc<Mutex<LeaseTable>>,
+    pub config: Arc<RwLock<LiveConfig>>,
+    pub console: Arc<Mutex<ConsoleBuffers>>,
+    pub jobs: Arc<Mutex<JobHistory>>,
+    pub requests: UnboundedSender<ControlRequest>,
+    /// Required of every request when auth is on.
+    pub token: Option<String>,
+}
+
+/// A parsed HTTP/1.1 request. Only what the control interface needs.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// Names lowercased.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Body of `POST /leases`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct LeaseRequest {
+    client: Option<String>,
+    ttl_secs: Option<u64>,
+    focus: Option<LeaseFocus>,
+    browser_context_id: Option<String>,
+    url: Option<String>,
+    /// Name of a configured identity profile for the tab to present.
+    identity: Option<String>,
+}
+
+/// Body of `POST /stealth/check`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct StealthCheckRequest {
+    identity: Option<String>,
+    /// Page to run the checks on; a blank page when unset.
+    url: Option<String>,
+}
+
+/// Starts the control interface on `address`, one task per connection.
+pub async fn spawn_control_server(
+    address: &str,
+    state: ControlState,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(address).await?;
+    log_message(
+        &format!("Control interface listening on {}", address),
+        "INFO",
+    );
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move { handle_connection(stream, &state).await });
+                }
+                Err(e) => log_message(&format!("Control accept failed: {}", e), "ERROR"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &ControlState) {
+    let request = match timeout(Duration::from_secs(10), read_request(&mut stream)).await {
+        Ok(Ok(request)) => Ok(request),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("request timed out")),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = write_json(&mut stream, 400, &json!({ "error": e })).await;
+            return;
+        }
+    };
+    if let Some(token) = &state.token {
+        if !is_authorized(&request.headers, &request.path, token) {
+            let body = json!({ "error": "missing or invalid token" });
+            let _ = write_json(&mut stream, 401, &body).await;
+            return;
+        }
+    }
+    if request.method == "GET" && request.path.split('?').next() == Some("/events") {
+        stream_events(stream, &request).await;
+        return;
+    }
+    let (status, body) = route(&request, state).await;
+    if let Err(e) = write_json(&mut stream, status, &body).await {
+        log_message(&format!("Failed to write control response: {}", e), "WARN");
+    }
+}
+
+/// Sends lifecycle events as Server-Sent Events until the client goes away, only the types
+/// listed in `?types=` when given.
+async fn stream_events(mut stream: TcpStream, request: &HttpRequest) {
+    let types: Option<Vec<&str>> =
+        query_param(&request.path, "types").map(|types| types.split(',').collect());
+    let mut events = events::subscribe();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n";
+    if stream.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+    loop {
+        // A comment now and then keeps proxies from timing the stream out and notices closed
+        // clients
+        let message = match timeout(Duration::from_secs(15), events.recv()).await {
+            Ok(Ok(event)) => {
+                if types
+                    .as_ref()
+                    .is_some_and(|types| !types.contains(&event.kind))
+                {
+                    continue;
+                }
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event.kind,
+                    serde_json::to_string(&event).unwrap_or_default()
+                )
+            }
+            Ok(Err(RecvError::Lagged(missed))) => format!(": missed {} events\n\n", missed),
+            Ok(Err(RecvError::Closed)) => return,
+            Err(_) => String::from(": keep-alive\n\n"),
+        };
+        if stream.write_all(message.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The value of `name` in the request path's query string.
+fn query_param<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = path.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+async fn route(request: &HttpRequest, state: &ControlState) -> (u16, Value) {
+    let segments: Vec<&str> = request
+        .path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["leases"]) => {
+            let leases = state.leases.lock().unwrap().list();
+            (
+                200,
+                json!(leases.iter().map(|l| l.to_json()).collect::<Vec<_>>()),
+            )
+        }
+        ("POST", ["leases"]) => grant_lease(request, state).await,
+        ("POST", ["leases", lease_id, "renew"]) => {
+            let mut leases = state.leases.lock().unwrap();
+            match leases.renew(lease_id) {
+                Some(lease) => (200, lease.to_json()),
+                None if leases.contains(lease_id) => (410, json!({ "error": "lease expired" })),
+                None => (404, json!({ "error": "unknown or expired lease" })),
+            }
+        }
+        ("DELETE", ["leases", lease_id]) => match state.leases.lock().unwrap().release(lease_id) {
+            Some(lease) => {
+                log_message(
+                    &format!("Lease {} released by {}", lease.id, lease.client),
+                    "INFO",
+                );
+                (200, lease.to_json())
+            }
+            None => (404, json!({ "error": "unknown or expired lease" })),
+        },
+        ("POST", ["config", "reload"]) | ("POST", ["rules", "reload"]) => reload_config(state),
+        ("POST", ["stealth", "check"]) => stealth_check(request, state).await,
+        ("GET", ["jobs"]) => {
+            let live = state.config.read().unwrap();
+            let history = state.jobs.lock().unwrap();
+            let now = Utc::now();
+            (
+                200,
+                json!(live
+                    .jobs
+                    .iter()
+                    .map(|job| history.to_json(job, now, false))
+                    .collect::<Vec<_>>()),
+            )
+        }
+        ("GET", ["jobs", name]) => {
+            let live = state.config.read().unwrap();
+            match live.jobs.iter().find(|job| job.config.name == *name) {
+                Some(job) => (
+                    200,
+                    state.jobs.lock().unwrap().to_json(job, Utc::now(), true),
+                ),
+                None => (404, json!({ "error": "unknown job" })),
+            }
+        }
+        ("GET", ["tabs", tab_id, "console"]) => tab_console(request, tab_id, state),
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+/// Re-reads the config file; the old config stays in place on error.
+fn reload_config(state: &ControlState) -> (u16, Value) {
+    let mut live = state.config.write().unwrap();
+    match live.reload() {
+        Ok(()) => (
+            200,
+            json!({
+                "rules": live.rules.count(),
+                "identities": live.config.identities.len(),
+                "jobs": live.jobs.len(),
+                "restart_pending": live.restart_pending,
+            }),
+        ),
+        Err(e) => {
+            log_message(&format!("Failed to reload config: {}", e), "ERROR");
+            (400, json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+/// The tab's buffered console entries, filtered by an optional `?level=` minimum.
+fn tab_console(request: &HttpRequest, tab_id: &str, state: &ControlState) -> (u16, Value) {
+    let level = query_param(&request.path, "level").unwrap_or("debug");
+    if !LEVELS.contains(&level) {
+        return (400, json!({ "error": format!("unknown level {}", level) }));
+    }
+    match state.console.lock().unwrap().recent(tab_id, level) {
+        Some(entries) => (200, json!(entries)),
+        None => (404, json!({ "error": "no console captured for that tab" })),
+    }
+}
+
+async fn grant_lease(request: &HttpRequest, state: &ControlState) -> (u16, Value) {
+    let lease_request: LeaseRequest = if request.body.is_empty() {
+        LeaseRequest::default()
+    } else {
+        match serde_json::from_slice(&request.body) {
+            Ok(lease_request) => lease_request,
+            Err(e) => return (400, json!({ "error": e.to_string() })),
+        }
+    };
+
+    let identity = match resolve_identity(state, lease_request.identity.as_deref()) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    let lease_config = state.config.read().unwrap().config.lease.clone();
+
+    let (reply, response) = oneshot::channel();
+    let open_tab = ControlRequest::OpenTab {
+        lease: NewLease {
+            url: lease_request
+                .url
+                .unwrap_or_else(|| String::from("about:blank")),
+            browser_context_id: lease_request.browser_context_id,
+            client: lease_request
+                .client
+                .unwrap_or_else(|| String::from("anonymous")),
+            ttl_secs: lease_request.ttl_secs,
+            focus: lease_request.focus,
+            identity: lease_request.identity,
+            config: lease_config,
+        },
+        identity,
+        reply,
+    };
+    if state.requests.send(open_tab).is_err() {
+        return (503, json!({ "error": "manager is shutting down" }));
+    }
+    // Dropping `response` on timeout tells the request task to release the lease
+    let lease = match timeout(Duration::from_secs(30), response).await {
+        Ok(Ok(Ok(lease))) => lease,
+        Ok(Ok(Err(e))) => return (503, json!({ "error": e })),
+        Ok(Err(_)) => return (503, json!({ "error": "manager is shutting down" })),
+        Err(_) => return (503, json!({ "error": "timed out waiting for a tab" })),
+    };
+    log_message(
+        &format!(
+            "Lease {} granted to {} on tab {} for {}s",
+            lease.id,
+            lease.client,
+            lease.tab_id,
+            lease.ttl.as_secs()
+        ),
+        "INFO",
+    );
+    (201, lease.to_json())
+}
+
+/// Looks up a configured identity profile by name.
+fn resolve_identity(
+    state: &ControlState,
+    name: Option<&str>,
+) -> Result<Option<IdentityProfile>, (u16, Value)> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    match state.config.read().unwrap().config.identities.get(name) {
+        Some(profile) => Ok(Some(profile.clone())),
+        None => Err((
+            400,
+            json!({ "error": format!("unknown identity {}", name) }),
+        )),
+    }
+}
+
+async fn stealth_check(request: &HttpRequest, state: &ControlState) -> (u16, Value) {
+    let check_request: StealthCheckRequest = if request.body.is_empty() {
+        StealthCheckRequest::default()
+    } else {
+        match serde_json::from_slice(&request.body) {
+            Ok(check_request) => check_request,
+            Err(e) => return (400, json!({ "error": e.to_string() })),
+        }
+    };
+    let identity = match resolve_identity(state, check_request.identity.as_deref()) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let (reply, response) = oneshot::channel();
+    let check = ControlRequest::StealthCheck {
+        identity,
+        url: check_request.url,
+        reply,
+    };
+    if state.requests.send(check).is_err() {
+        return (503, json!({ "error": "manager is shutting down" }));
+    }
+    match timeout(Duration::from_secs(60), response).await {
+        Ok(Ok(Ok(report))) => (200, report),
+        Ok(Ok(Err(e))) => (503, json!({ "error": e })),
+        Ok(Err(_)) => (503, json!({ "error": "manager is shutting down" })),
+        Err(_) => (503, json!({ "error": "timed out waiting for the check" })),
+    }
+}
+
+/// Reads the request line, headers and a `Content-Length` body.
+pub async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, Box<dyn Error>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().ok_or("empty request")?.to_string();
+    let path = parts.next().ok_or("request line has no path")?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length: usize = headers
+        .get("content-length")
+        .map(|length| length.parse())
+        .transpose()?
+        .unwrap_or(0);
+    if content_length > 1024 * 1024 {
+        return Err("request body too large".into());
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub async fn write_json(stream: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        410 => "Gone",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle_control_request;
+    use crate::testing::{connect, live_config};
+    use mock_devtools::MockDevTools;
+    use std::time::Instant;
+    use tokio::runtime::Runtime;
+    use tokio::sync::mpsc;
+
+    /// Control state whose tab requests are served from `mock` on a thread of their own.
+    fn serve(mock: &MockDevTools) -> ControlState {
+        let (browser, _) = connect(mock, 0);
+        let (requests, mut incoming) = mpsc::unbounded_channel();
+        let state = ControlState {
+            leases: Arc::new(Mutex::new(LeaseTable::default())),
+            config: Arc::new(live_config(json!({}))),
+            console: Arc::new(Mutex::new(ConsoleBuffers::default())),
+            jobs: Arc::new(Mutex::new(JobHistory::default())),
+            requests,
+            token: None,
+        };
+        let leases = state.leases.clone();
+        std::thread::spawn(move || {
+            let tab_metadata = Mutex::new(HashMap::new());
+            while let Some(request) = incoming.blocking_recv() {
+                handle_control_request(&browser, request, &tab_metadata, &leases, None, false);
+            }
+        });
+        state
+    }
+
+    fn request(method: &str, path: &str, body: Value) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: HashMap::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    #[test]
+    fn leases_are_granted_renewed_and_released_over_the_control_interface() {
+        let mock = MockDevTools::start();
+        let state = serve(&mock);
+        let runtime = Runtime::new().unwrap();
+        let call = |method, path: &str, body| {
+            runtime.block_on(route(&request(method, path, body), &state))
+        };
+
+        let (status, lease) = call(
+            "POST",
+            "/leases",
+            json!({ "client": "crawler", "ttl_secs": 60 }),
+        );
+        assert_eq!(status, 201);
+        assert_eq!(lease["client"], "crawler");
+        assert_eq!(lease["ttl_secs"], 60);
+        let lease_id = lease["lease_id"].as_str().unwrap().to_string();
+        let tab_id = lease["tab_id"].as_str().unwrap();
+        assert_eq!(mock.target_ids(), [tab_id]);
+        assert!(state.leases.lock().unwrap().lease_for_tab(tab_id).is_some());
+
+        let (status, renewed) = call("POST", &format!("/leases/{}/renew", lease_id), Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(renewed["lease_id"], lease_id.as_str());
+
+        let (status, _) = call("DELETE", &format!("/leases/{}", lease_id), Value::Null);
+        assert_eq!(status, 200);
+        assert!(state.leases.lock().unwrap().list().is_empty());
+
+        let (status, _) = call("DELETE", &format!("/leases/{}", lease_id), Value::Null);
+        assert_eq!(status, 404);
+        let (status, _) = call("POST", &format!("/leases/{}/renew", lease_id), Value::Null);
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn renewing_an_expired_lease_is_gone_and_unknown_routes_are_not_found() {
+        let mock = MockDevTools::start();
+        let state = serve(&mock);
+        let runtime = Runtime::new().unwrap();
+        state.leases.lock().unwrap().restore(Lease {
+            id: String::from("expired"),
+            tab_id: String::from("tab"),
+            client: String::from("crawler"),
+            focus: LeaseFocus::Rotate,
+            identity: None,
+            ttl: Duration::from_secs(60),
+            expires_at: Instant::now(),
+        });
+
+        let renew = request("POST", "/leases/expired/renew", Value::Null);
+        let (status, body) = runtime.block_on(route(&renew, &state));
+        assert_eq!(status, 410);
+        assert_eq!(body["error"], "lease expired");
+
+        let unknown = request("GET", "/nowhere", Value::Null);
+        assert_eq!(runtime.block_on(route(&unknown, &state)).0, 404);
+    }
+}
@@ -0,0 +1,625 @@
+// devtools.rs
+//
+// The DevTools gateway. With auth on, Chromium listens on a random loopback port and clients
+// reach it through here, with the token or over the unix socket. The port is not a secret from
+// local users, who can find it and drive Chromium without the token.
+
+use crate::auth::{is_authorized, strip_token};
+use crate::config::{AuthConfig, RemoteGatewayConfig};
+use crate::tasks::Session;
+use crate::utils::log_message;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{copy, copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Largest request head the gateway reads.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// What a gateway connection needs.
+#[derive(Clone)]
+struct Gateway {
+    /// `None` on the unix socket, where file permissions do the job.
+    token: Option<Arc<str>>,
+    /// Served over TLS, so the WebSocket URLs handed out are `wss://`.
+    secure: bool,
+    session: watch::Receiver<Option<Arc<Session>>>,
+}
+
+/// Starts the gateway on `auth.devtools_address` and, if set, `auth.unix_socket`.
+pub async fn spawn_devtools_gateway(
+    config: &AuthConfig,
+    token: &str,
+    session: watch::Receiver<Option<Arc<Session>>>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(&config.devtools_address).await?;
+    log_message(
+        &format!("DevTools gateway listening on {}", config.devtools_address),
+        "INFO",
+    );
+    let gateway = Gateway {
+        token: Some(Arc::from(token)),
+        secure: false,
+        session: session.clone(),
+    };
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, gateway.clone()));
+                }
+                Err(e) => log_message(&format!("DevTools accept failed: {}", e), "ERROR"),
+            }
+        }
+    });
+
+    if let Some(path) = &config.unix_socket {
+        // A socket left behind by an earlier run would make the bind fail
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        log_message(
+            &format!("DevTools gateway listening on {}", path.display()),
+            "INFO",
+        );
+        let gateway = Gateway {
+            token: None,
+            secure: false,
+            session: session.clone(),
+        };
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve(stream, gateway.clone()));
+                    }
+                    Err(e) => log_message(&format!("DevTools accept failed: {}", e), "ERROR"),
+                }
+            }
+        });
+    }
+
+    if let Some(remote) = &config.remote {
+        let acceptor = tls_acceptor(remote)?;
+        let listener = TcpListener::bind(&remote.address).await?;
+        log_message(
+            &format!("DevTools gateway listening on {} (TLS)", remote.address),
+            "INFO",
+        );
+        let gateway = Gateway {
+            token: Some(Arc::from(token)),
+            secure: true,
+            session,
+        };
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log_message(&format!("DevTools accept failed: {}", e), "ERROR");
+                        continue;
+                    }
+                };
+                let (acceptor, gateway) = (acceptor.clone(), gateway.clone());
+                tokio::spawn(async move {
+                    match timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => serve(stream, gateway).await,
+                        Ok(Err(e)) => log_message(
+                            &format!("TLS handshake with {} failed: {}", peer, e),
+                            "DEBUG",
+                        ),
+                        Err(_) => {
+                            log_message(&format!("TLS handshake with {} timed out", peer), "DEBUG")
+                        }
+                    }
+                });
+            }
+        });
+    } else if !is_loopback(&config.devtools_address) {
+        log_message(
+            &format!(
+                "DevTools gateway on {} sends the token in the clear; set auth.remote for TLS",
+                config.devtools_address
+            ),
+            "WARN",
+        );
+    }
+    Ok(())
+}
+
+/// Loads the remote listener's certificate chain and key.
+fn tls_acceptor(remote: &RemoteGatewayConfig) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut read(&remote.cert_file)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", remote.cert_file.display()).into());
+    }
+    let key = rustls_pemfile::private_key(&mut read(&remote.key_file)?.as_slice())?
+        .ok_or_else(|| format!("No private key in {}", remote.key_file.display()))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn is_loopback(address: &str) -> bool {
+    address
+        .parse::<SocketAddr>()
+        .is_ok_and(|address| address.ip().is_loopback())
+}
+
+/// The request line and headers of a client's request.
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    path: String,
+    version: String,
+    /// In the order sent, names as sent.
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split_whitespace();
+        let (method, path, version) = (
+            request_line.next()?.to_string(),
+            request_line.next()?.to_string(),
+            request_line.next()?.to_string(),
+        );
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Some(RequestHead {
+            method,
+            path,
+            version,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_websocket(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+
+    fn content_length(&self) -> usize {
+        self.header("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// The head to send Chromium: no token, its own address as the host (it refuses others)
+    /// and, for plain HTTP, one request per connection.
+    fn forwarded(&self, upstream: &str) -> String {
+        let mut head = format!(
+            "{} {} {}\r\nHost: {}\r\n",
+            self.method,
+            strip_token(&self.path),
+            self.version,
+            upstream
+        );
+        let websocket = self.is_websocket();
+        for (name, value) in &self.headers {
+            let skip = ["host", "authorization"]
+                .iter()
+                .any(|skipped| name.eq_ignore_ascii_case(skipped))
+                || (!websocket && name.eq_ignore_ascii_case("connection"));
+            if !skip {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if !websocket {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(mut client: S, gateway: Gateway) {
+    let (head, body_start) = match timeout(Duration::from_secs(10), read_head(&mut client)).await {
+        Ok(Ok(head)) => head,
+        _ => return,
+    };
+    let Some(request) = RequestHead::parse(&head) else {
+        let _ = respond(&mut client, 400, "malformed request").await;
+        return;
+    };
+    if let Some(token) = &gateway.token {
+        let headers: HashMap<String, String> = request
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .collect();
+        if !is_authorized(&headers, &request.path, token) {
+            log_message(
+                &format!(
+                    "Refused DevTools request without a valid token: {}",
+                    strip_token(&request.path)
+                ),
+                "WARN",
+            );
+            let _ = respond(&mut client, 401, "missing or invalid token").await;
+            return;
+        }
+    }
+    let port = gateway
+        .session
+        .borrow()
+        .as_ref()
+        .map(|session| session.devtools_port);
+    let Some(port) = port else {
+        let _ = respond(&mut client, 503, "browser is not running").await;
+        return;
+    };
+    let upstream_address = format!("127.0.0.1:{}", port);
+    let mut upstream = match TcpStream::connect(&upstream_address).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let _ = respond(&mut client, 503, &format!("browser unreachable: {}", e)).await;
+            return;
+        }
+    };
+
+    let result = async {
+        upstream
+            .write_all(request.forwarded(&upstream_address).as_bytes())
+            .await?;
+        upstream.write_all(&body_start).await?;
+        if request.is_websocket() {
+            copy_bidirectional(&mut client, &mut upstream).await?;
+            return Ok(());
+        }
+        let remaining = request.content_length().saturating_sub(body_start.len());
+        copy(&mut (&mut client).take(remaining as u64), &mut upstream).await?;
+        let mut response = Vec::new();
+        upstream.read_to_end(&mut response).await?;
+        let public_host = request.header("host").unwrap_or(&upstream_address);
+        let response = rewrite_response(
+            &response,
+            &upstream_address,
+            public_host,
+            gateway.secure,
+            gateway.token.as_deref(),
+        );
+        client.write_all(&response).await?;
+        client.flush().await
+    }
+    .await;
+    if let Err(e) = result {
+        log_message(
+            &format!("DevTools gateway connection failed: {}", e),
+            "DEBUG",
+        );
+    }
+}
+
+/// Reads up to the end of the request head. Returns the head and any body bytes read with it.
+async fn read_head<S: AsyncRead + Unpin>(client: &mut S) -> std::io::Result<(String, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+            return Ok((head, buffer[end + 4..].to_vec()));
+        }
+        if buffer.len() > MAX_HEAD {
+            return Err(std::io::Error::other("request head too large"));
+        }
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Points the WebSocket URLs in a `/json` response at the gateway, `wss://` when it is
+/// `secure` and with the token when the client needed one, and fixes up `Content-Length`.
+/// Anything else passes through.
+fn rewrite_response(
+    response: &[u8],
+    upstream: &str,
+    public_host: &str,
+    secure: bool,
+    token: Option<&str>,
+) -> Vec<u8> {
+    let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return response.to_vec();
+    };
+    let Ok(body) = serde_json::from_slice::<Value>(&response[end + 4..]) else {
+        return response.to_vec();
+    };
+    let scheme = if secure { "wss" } else { "ws" };
+    let body = rewrite_urls(
+        body,
+        upstream,
+        &format!("{}://{}", scheme, public_host),
+        token,
+    );
+    let body = body.to_string();
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut rewritten: String = head
+        .split("\r\n")
+        .filter(|line| !line.to_ascii_lowercase().starts_with("content-length:"))
+        .map(|line| format!("{}\r\n", line))
+        .collect();
+    rewritten.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    rewritten.into_bytes()
+}
+
+/// `public` is the gateway's address with its scheme, such as `wss://host:9443`.
+fn rewrite_urls(value: Value, upstream: &str, public: &str, token: Option<&str>) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| rewrite_urls(item, upstream, public, token))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match (key.as_str(), value) {
+                        ("webSocketDebuggerUrl", Value::String(url)) => {
+                            let url = url.replacen(&format!("ws://{}", upstream), public, 1);
+                            json!(match token {
+                                Some(token) => format!("{}?token={}", url, token),
+                                None => url,
+                            })
+                        }
+                        // The frontend takes the socket as `ws=host/path` or `wss=host/path`
+                        ("devtoolsFrontendUrl", Value::String(url)) => json!(url
+                            .replace(&format!("ws={}", upstream), &public.replacen("://", "=", 1))),
+                        (_, value) => value,
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+async fn respond<S: AsyncWrite + Unpin>(
+    client: &mut S,
+    status: u16,
+    error: &str,
+) -> std::io::Result<()> {
+    let body = json!({ "error": error }).to_string();
+    let reason = match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headless_chrome::Browser;
+    use mock_devtools::MockDevTools;
+    use std::sync::Mutex;
+
+    #[test]
+    fn json_urls_point_at_the_gateway_with_the_token() {
+        let body = json!([{
+            "id": "A",
+            "url": "http://127.0.0.1:4000/",
+            "webSocketDebuggerUrl": "ws://127.0.0.1:4000/devtools/page/A",
+            "devtoolsFrontendUrl": "/devtools/inspector.html?ws=127.0.0.1:4000/devtools/page/A",
+        }]);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 999\r\nContent-Type: application/json\r\n\r\n{}",
+            body
+        );
+        let rewritten = String::from_utf8(rewrite_response(
+            response.as_bytes(),
+            "127.0.0.1:4000",
+            "localhost:9222",
+            false,
+            Some("secret"),
+        ))
+        .unwrap();
+        let (head, body) = rewritten.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body[0]["webSocketDebuggerUrl"],
+            "ws://localhost:9222/devtools/page/A?token=secret"
+        );
+        assert_eq!(
+            body[0]["devtoolsFrontendUrl"],
+            "/devtools/inspector.html?ws=localhost:9222/devtools/page/A"
+        );
+        // Page URLs are the page's business
+        assert_eq!(body[0]["url"], "http://127.0.0.1:4000/");
+
+        let secure = rewrite_response(
+            response.as_bytes(),
+            "127.0.0.1:4000",
+            "browser.example:9443",
+            true,
+            Some("secret"),
+        );
+        let secure = String::from_utf8(secure).unwrap();
+        let body: Value = serde_json::from_str(secure.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(
+            body[0]["webSocketDebuggerUrl"],
+            "wss://browser.example:9443/devtools/page/A?token=secret"
+        );
+        assert_eq!(
+            body[0]["devtoolsFrontendUrl"],
+            "/devtools/inspector.html?wss=browser.example:9443/devtools/page/A"
+        );
+    }
+
+    #[test]
+    fn gateway_needs_the_token_and_forwards_http_and_websockets() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://app.test/");
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        // A free port, so the test knows where the gateway is
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = AuthConfig {
+            devtools_address: address.to_string(),
+            unix_socket: Some(dir.path().join("devtools.sock")),
+            ..AuthConfig::default()
+        };
+        let (_session_tx, session) = watch::channel(Some(Arc::new(Session {
+            browser: Arc::new(Browser::connect(mock.ws_url()).unwrap()),
+            proxy: None,
+            devtools_port: mock.port(),
+            job_contexts: Mutex::default(),
+        })));
+        runtime
+            .block_on(spawn_devtools_gateway(&config, "secret", session))
+            .unwrap();
+
+        let url = format!("http://{}/json", address);
+        let client = reqwest::blocking::Client::new();
+        assert_eq!(client.get(&url).send().unwrap().status(), 401);
+        let tabs: Value = client
+            .get(&url)
+            .bearer_auth("secret")
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let ws_url = tabs[0]["webSocketDebuggerUrl"].as_str().unwrap();
+        assert!(ws_url.starts_with(&format!("ws://{}/", address)));
+        assert!(ws_url.ends_with("?token=secret"));
+
+        let version: Value = client
+            .get(format!("http://{}/json/version", address))
+            .bearer_auth("secret")
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let browser =
+            Browser::connect(version["webSocketDebuggerUrl"].as_str().unwrap().into()).unwrap();
+        assert!(mock_devtools::wait_until(Duration::from_secs(5), || {
+            browser
+                .get_tabs()
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|tab| tab.get_target_id() == &target_id)
+        }));
+
+        let mode = fs::metadata(dir.path().join("devtools.sock"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn remote_listener_serves_json_over_tls_with_wss_urls() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://app.test/");
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let (cert_file, key_file) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        fs::write(&cert_file, cert.cert.pem()).unwrap();
+        fs::write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let remote_port = free_port();
+        let config = AuthConfig {
+            devtools_address: format!("127.0.0.1:{}", free_port()),
+            remote: Some(RemoteGatewayConfig {
+                address: format!("127.0.0.1:{}", remote_port),
+                cert_file,
+                key_file,
+            }),
+            ..AuthConfig::default()
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (_session_tx, session) = watch::channel(Some(Arc::new(Session {
+            browser: Arc::new(Browser::connect(mock.ws_url()).unwrap()),
+            proxy: None,
+            devtools_port: mock.port(),
+            job_contexts: Mutex::default(),
+        })));
+        runtime
+            .block_on(spawn_devtools_gateway(&config, "secret", session))
+            .unwrap();
+
+        let client = reqwest::blocking::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(cert.cert.pem().as_bytes()).unwrap(),
+            )
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/json", remote_port);
+        assert_eq!(client.get(&url).send().unwrap().status(), 401);
+        let tabs: Value = client
+            .get(&url)
+            .bearer_auth("secret")
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        let ws_url = tabs[0]["webSocketDebuggerUrl"].as_str().unwrap();
+        assert!(
+            ws_url.starts_with(&format!("wss://localhost:{}/devtools/page/", remote_port)),
+            "{}",
+            ws_url
+        );
+        assert!(ws_url.ends_with("?token=secret"));
+        runtime.shutdown_background();
+    }
+}
@@ -0,0 +1,420 @@
+// discovery.rs
+
+use crate::config::{BrowserChannel, BrowserConfig, BrowserMode};
+use crate::sandbox::detect_sandbox;
+use crate::utils::log_message;
+use regex::Regex;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Executable names looked up on `PATH`, most specific first.
+const EXECUTABLE_NAMES: [&str; 8] = [
+    "google-chrome-stable",
+    "google-chrome",
+    "google-chrome-beta",
+    "google-chrome-unstable",
+    "chromium",
+    "chromium-browser",
+    "chrome",
+    "google-chrome-canary",
+];
+
+/// Install locations that are often missing from `PATH`.
+const WELL_KNOWN_PATHS: [&str; 6] = [
+    "/usr/bin/chrome",
+    "/usr/bin/chromium",
+    "/usr/bin/google-chrome",
+    "/usr/bin/chromium-browser",
+    "/opt/google/chrome/chrome",
+    "/snap/bin/chromium",
+];
+
+/// What `<binary> --version` reported.
+#[derive(Debug, Clone)]
+pub struct BrowserVersion {
+    /// The raw output, e.g. `Google Chrome 124.0.6367.60`.
+    pub description: String,
+    pub numbers: Vec<u32>,
+    pub channel: BrowserChannel,
+}
+
+impl BrowserVersion {
+    /// Parses `--version` output; `None` when it has no dotted version number.
+    pub fn parse(output: &str) -> Option<Self> {
+        let description = output.trim().to_string();
+        let version = Regex::new(r"\d+(\.\d+)+")
+            .unwrap()
+            .find(&description)?
+            .as_str()
+            .to_string();
+        let lower = description.to_ascii_lowercase();
+        let channel = if lower.contains("chromium") {
+            BrowserChannel::Chromium
+        } else if lower.contains("canary") {
+            BrowserChannel::Canary
+        } else if lower.contains("dev") || lower.contains("unstable") {
+            BrowserChannel::Dev
+        } else if lower.contains("beta") {
+            BrowserChannel::Beta
+        } else {
+            BrowserChannel::Stable
+        };
+        Some(BrowserVersion {
+            description,
+            numbers: parse_numbers(&version),
+            channel,
+        })
+    }
+
+    /// True when this version is at least `minimum`, compared component by component.
+    pub fn at_least(&self, minimum: &[u32]) -> bool {
+        for (index, wanted) in minimum.iter().enumerate() {
+            let have = self.numbers.get(index).copied().unwrap_or(0);
+            if have != *wanted {
+                return have > *wanted;
+            }
+        }
+        true
+    }
+}
+
+fn parse_numbers(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+/// A binary that was considered, with where it came from and what it reported.
+#[derive(Debug)]
+pub struct BrowserCandidate {
+    pub path: PathBuf,
+    pub source: &'static str,
+    pub version: Result<BrowserVersion, String>,
+}
+
+impl fmt::Display for BrowserCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Ok(version) => write!(
+                f,
+                "{} ({}): {}",
+                self.path.display(),
+                self.source,
+                version.description
+            ),
+            Err(e) => write!(f, "{} ({}): {}", self.path.display(), self.source, e),
+        }
+    }
+}
+
+/// Lists every candidate binary in order of preference: `browser.path`, `CHROME_PATH`,
+/// `PATH`, then the well-known install locations. Each is asked for its version.
+pub fn discover_browsers(config: &BrowserConfig) -> Vec<BrowserCandidate> {
+    let mut paths: Vec<(PathBuf, &'static str)> = Vec::new();
+    if let Some(path) = &config.path {
+        paths.push((PathBuf::from(path), "config"));
+    }
+    if let Ok(path) = env::var("CHROME_PATH") {
+        paths.push((PathBuf::from(path), "CHROME_PATH"));
+    }
+    if let Some(search_path) = env::var_os("PATH") {
+        for dir in env::split_paths(&search_path) {
+            for name in EXECUTABLE_NAMES {
+                let path = dir.join(name);
+                if path.is_file() {
+                    paths.push((path, "PATH"));
+                }
+            }
+        }
+    }
+    for path in WELL_KNOWN_PATHS {
+        paths.push((PathBuf::from(path), "well-known path"));
+    }
+
+    let mut candidates: Vec<BrowserCandidate> = Vec::new();
+    for (path, source) in paths {
+        let resolved = path.canonicalize().unwrap_or_else(|_| path.clone());
+        let seen = candidates.iter().any(|candidate| {
+            candidate
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| candidate.path.clone())
+                == resolved
+        });
+        if seen {
+            continue;
+        }
+        let version = probe_version(&path);
+        candidates.push(BrowserCandidate {
+            path,
+            source,
+            version,
+        });
+    }
+    candidates
+}
+
+fn probe_version(path: &Path) -> Result<BrowserVersion, String> {
+    if !path.exists() {
+        return Err(String::from("not found"));
+    }
+    let output = Command::new(path)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("failed to run: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    BrowserVersion::parse(&stdout).ok_or_else(|| {
+        format!(
+            "unrecognised --version output: {}",
+            stdout.trim().chars().take(80).collect::<String>()
+        )
+    })
+}
+
+/// Why `version` doesn't satisfy the configured requirements, if it doesn't.
+fn rejection(config: &BrowserConfig, path: &Path, version: &BrowserVersion) -> Option<String> {
+    if let Some(channel) = config.channel {
+        if version.channel != channel {
+            return Some(format!(
+                "channel is {:?}, need {:?}",
+                version.channel, channel
+            ));
+        }
+    }
+    if let Some(minimum) = &config.min_version {
+        if !version.at_least(&parse_numbers(minimum)) {
+            return Some(format!("older than required {}", minimum));
+        }
+    }
+    if config.mode == BrowserMode::Headless
+        && version.at_least(&[132])
+        && !is_headless_shell(path, version)
+    {
+        return Some(String::from(
+            "Chrome 132 and later have no old headless mode; set browser.path to \
+             chrome-headless-shell or browser.mode to new-headless",
+        ));
+    }
+    None
+}
+
+/// True for chrome-headless-shell, the build that keeps the old headless mode.
+fn is_headless_shell(path: &Path, version: &BrowserVersion) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().contains("headless-shell"))
+        || version
+            .description
+            .to_ascii_lowercase()
+            .contains("headless shell")
+}
+
+/// Picks the first candidate that runs and meets `min_version` and `channel`.
+///
+/// Fails with every path that was tried and why it was passed over.
+pub fn select_browser(config: &BrowserConfig) -> Result<(PathBuf, BrowserVersion), Box<dyn Error>> {
+    let candidates = discover_browsers(config);
+    let (candidate, version) = choose_browser(config, &candidates)?;
+    log_message(&format!("Using browser {}", candidate), "DEBUG");
+    Ok((candidate.path.clone(), version.clone()))
+}
+
+/// The first of `candidates` that runs and meets the requirements.
+fn choose_browser<'a>(
+    config: &BrowserConfig,
+    candidates: &'a [BrowserCandidate],
+) -> Result<(&'a BrowserCandidate, &'a BrowserVersion), String> {
+    let mut tried = Vec::new();
+    for candidate in candidates {
+        match &candidate.version {
+            Ok(version) => match rejection(config, &candidate.path, version) {
+                Some(reason) => tried.push(format!("  {}: {}", candidate, reason)),
+                None => return Ok((candidate, version)),
+            },
+            Err(_) => tried.push(format!("  {}", candidate)),
+        }
+    }
+    Err(format!(
+        "No usable Chrome/Chromium found. Tried:\n{}",
+        tried.join("\n")
+    ))
+}
+
+/// Prints every candidate with its version, marking the one the manager would use, and the
+/// sandbox it would get.
+pub fn print_browser_report(config: &BrowserConfig) {
+    let candidates = discover_browsers(config);
+    let selected = choose_browser(config, &candidates)
+        .ok()
+        .map(|(candidate, _)| &candidate.path);
+    for candidate in &candidates {
+        if candidate.version.is_err() && candidate.source == "well-known path" {
+            continue;
+        }
+        let marker = if Some(&candidate.path) == selected {
+            "*"
+        } else {
+            " "
+        };
+        let reason = candidate
+            .version
+            .as_ref()
+            .ok()
+            .and_then(|version| rejection(config, &candidate.path, version))
+            .map(|reason| format!(" [{}]", reason))
+            .unwrap_or_default();
+        println!("{} {}{}", marker, candidate, reason);
+    }
+    match selected {
+        Some(path) => println!("Sandbox: {}", detect_sandbox(path)),
+        None => println!("No usable browser found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn version_output_of_each_channel_is_parsed() {
+        let cases = [
+            (
+                "Chromium 124.0.6367.60 built on Debian 12.5, running on Debian 12.5\n",
+                vec![124, 0, 6367, 60],
+                BrowserChannel::Chromium,
+            ),
+            (
+                "Chromium 120.0.6099.224 snap\n",
+                vec![120, 0, 6099, 224],
+                BrowserChannel::Chromium,
+            ),
+            (
+                "Google Chrome 124.0.6367.60 \n",
+                vec![124, 0, 6367, 60],
+                BrowserChannel::Stable,
+            ),
+            (
+                "Google Chrome 125.0.6422.26 beta\n",
+                vec![125, 0, 6422, 26],
+                BrowserChannel::Beta,
+            ),
+            (
+                "Google Chrome 126.0.6439.0 dev\n",
+                vec![126, 0, 6439, 0],
+                BrowserChannel::Dev,
+            ),
+            (
+                "Google Chrome 127.0.6490.0 canary\n",
+                vec![127, 0, 6490, 0],
+                BrowserChannel::Canary,
+            ),
+        ];
+        for (output, numbers, channel) in cases {
+            let version = BrowserVersion::parse(output).unwrap();
+            assert_eq!(version.description, output.trim());
+            assert_eq!(version.numbers, numbers, "{}", output);
+            assert_eq!(version.channel, channel, "{}", output);
+        }
+        assert!(BrowserVersion::parse("Google Chrome\n").is_none());
+        assert!(BrowserVersion::parse("").is_none());
+    }
+
+    #[test]
+    fn versions_compare_component_by_component() {
+        let version = BrowserVersion::parse("Google Chrome 124.0.6367.60").unwrap();
+        assert!(version.at_least(&[]));
+        assert!(version.at_least(&[120]));
+        assert!(version.at_least(&[124]));
+        assert!(version.at_least(&[124, 0, 6367, 60]));
+        assert!(version.at_least(&[123, 9, 9999]));
+        assert!(!version.at_least(&[125]));
+        assert!(!version.at_least(&[124, 0, 6368]));
+        assert!(!version.at_least(&[124, 0, 6367, 60, 1]));
+    }
+
+    fn candidate(path: &str, output: &str) -> BrowserCandidate {
+        BrowserCandidate {
+            path: PathBuf::from(path),
+            source: "PATH",
+            version: BrowserVersion::parse(output).ok_or_else(|| String::from("not found")),
+        }
+    }
+
+    #[test]
+    fn the_first_candidate_meeting_the_requirements_is_chosen() {
+        let candidates = [
+            candidate("/usr/bin/missing", ""),
+            candidate(
+                "/usr/bin/google-chrome-beta",
+                "Google Chrome 125.0.6422.26 beta",
+            ),
+            candidate("/usr/bin/google-chrome", "Google Chrome 124.0.6367.60"),
+            candidate("/usr/bin/chromium", "Chromium 126.0.6478.126"),
+        ];
+        let config = |config: Value| -> BrowserConfig { serde_json::from_value(config).unwrap() };
+
+        let (chosen, _) = choose_browser(&config(json!({})), &candidates).unwrap();
+        assert_eq!(chosen.path, Path::new("/usr/bin/google-chrome-beta"));
+        let stable = config(json!({ "channel": "stable" }));
+        let (chosen, version) = choose_browser(&stable, &candidates).unwrap();
+        assert_eq!(chosen.path, Path::new("/usr/bin/google-chrome"));
+        assert_eq!(version.numbers[0], 124);
+        let recent = config(json!({ "min_version": "126" }));
+        let (chosen, _) = choose_browser(&recent, &candidates).unwrap();
+        assert_eq!(chosen.path, Path::new("/usr/bin/chromium"));
+
+        let none = config(json!({ "channel": "stable", "min_version": "125.0.6422" }));
+        let tried = [
+            "  /usr/bin/missing (PATH): not found",
+            "  /usr/bin/google-chrome-beta (PATH): Google Chrome 125.0.6422.26 beta: \
+             channel is Beta, need Stable",
+            "  /usr/bin/google-chrome (PATH): Google Chrome 124.0.6367.60: older than required \
+             125.0.6422",
+            "  /usr/bin/chromium (PATH): Chromium 126.0.6478.126: channel is Chromium, need Stable",
+        ];
+        assert_eq!(
+            choose_browser(&none, &candidates).unwrap_err(),
+            format!(
+                "No usable Chrome/Chromium found. Tried:\n{}",
+                tried.join("\n")
+            )
+        );
+    }
+
+    #[test]
+    fn old_headless_mode_needs_chrome_before_132_or_the_headless_shell() {
+        let chrome = || candidate("/usr/bin/google-chrome", "Google Chrome 132.0.6834.83");
+        let shell = candidate(
+            "/opt/chrome-headless-shell/chrome-headless-shell",
+            "Google Chrome 132.0.6834.83",
+        );
+        let older = candidate("/usr/bin/chromium", "Chromium 131.0.6778.204");
+        let chosen = |mode: &str, candidates: &[BrowserCandidate]| {
+            let config: BrowserConfig = serde_json::from_value(json!({ "mode": mode })).unwrap();
+            choose_browser(&config, candidates).map(|(candidate, _)| candidate.path.clone())
+        };
+
+        let error = chosen("headless", &[chrome()]).unwrap_err();
+        assert!(
+            error.contains("set browser.path to chrome-headless-shell"),
+            "{}",
+            error
+        );
+        assert_eq!(
+            chosen("headless", &[chrome(), shell]).unwrap(),
+            Path::new("/opt/chrome-headless-shell/chrome-headless-shell")
+        );
+        assert_eq!(
+            chosen("headless", &[chrome(), older]).unwrap(),
+            Path::new("/usr/bin/chromium")
+        );
+        assert_eq!(
+            chosen("new-headless", &[chrome()]).unwrap(),
+            Path::new("/usr/bin/google-chrome")
+        );
+    }
+}
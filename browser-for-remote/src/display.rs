@@ -0,0 +1,350 @@
+// display.rs
+
+use crate::config::{BrowserConfig, BrowserMode, NoDisplayPolicy, XvfbConfig};
+use crate::utils::log_message;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How Chromium ends up being started once the display situation is known.
+#[derive(Debug)]
+pub struct DisplaySetup {
+    pub mode: BrowserMode,
+    /// Extra Chromium flags, e.g. `--headless=new`.
+    pub args: Vec<OsString>,
+    /// Environment for the browser process, e.g. the Xvfb `DISPLAY`.
+    pub envs: Option<HashMap<String, String>>,
+}
+
+/// The displays the manager was started with.
+#[derive(Debug, Default)]
+pub struct DisplayEnv {
+    /// `DISPLAY`, for X.
+    pub x11: Option<OsString>,
+    /// `WAYLAND_DISPLAY`.
+    pub wayland: Option<OsString>,
+}
+
+impl DisplayEnv {
+    pub fn from_env() -> Self {
+        DisplayEnv {
+            x11: env::var_os("DISPLAY"),
+            wayland: env::var_os("WAYLAND_DISPLAY"),
+        }
+    }
+}
+
+/// The Xvfb server started for headful mode, shared across browser restarts.
+static XVFB: Mutex<Option<XvfbServer>> = Mutex::new(None);
+
+struct XvfbServer {
+    child: Child,
+    display: String,
+    size: (u32, u32),
+}
+
+/// Works out the mode to launch in. Headful needs `DISPLAY` or `WAYLAND_DISPLAY` in `env`;
+/// without either, `no_display` decides between headless, an Xvfb server, or failing.
+pub fn resolve_display(
+    config: &BrowserConfig,
+    window_size: (u32, u32),
+    env: &DisplayEnv,
+) -> Result<DisplaySetup, Box<dyn Error>> {
+    match config.mode {
+        BrowserMode::Headless => Ok(DisplaySetup {
+            mode: BrowserMode::Headless,
+            args: vec![OsString::from("--headless=old")],
+            envs: None,
+        }),
+        BrowserMode::NewHeadless => Ok(DisplaySetup {
+            mode: BrowserMode::NewHeadless,
+            args: vec![OsString::from("--headless=new")],
+            envs: None,
+        }),
+        BrowserMode::Headful => {
+            if config.xvfb.always {
+                return xvfb_setup(&config.xvfb, window_size);
+            }
+            if env.x11.is_some() {
+                return Ok(DisplaySetup {
+                    mode: BrowserMode::Headful,
+                    args: Vec::new(),
+                    envs: None,
+                });
+            }
+            if env.wayland.is_some() {
+                return Ok(DisplaySetup {
+                    mode: BrowserMode::Headful,
+                    args: vec![OsString::from("--ozone-platform=wayland")],
+                    envs: None,
+                });
+            }
+            match config.no_display {
+                NoDisplayPolicy::Headless => {
+                    log_message(
+                        "No DISPLAY or WAYLAND_DISPLAY, falling back to headless",
+                        "WARN",
+                    );
+                    Ok(DisplaySetup {
+                        mode: BrowserMode::NewHeadless,
+                        args: vec![OsString::from("--headless=new")],
+                        envs: None,
+                    })
+                }
+                NoDisplayPolicy::Xvfb => xvfb_setup(&config.xvfb, window_size),
+                NoDisplayPolicy::Fail => {
+                    Err("Headful mode needs DISPLAY or WAYLAND_DISPLAY and neither is set".into())
+                }
+            }
+        }
+    }
+}
+
+fn xvfb_setup(
+    config: &XvfbConfig,
+    window_size: (u32, u32),
+) -> Result<DisplaySetup, Box<dyn Error>> {
+    let display = ensure_xvfb(config, window_size)?;
+    Ok(DisplaySetup {
+        mode: BrowserMode::Headful,
+        args: Vec::new(),
+        envs: Some(HashMap::from([(String::from("DISPLAY"), display)])),
+    })
+}
+
+/// Returns the display of the running Xvfb server, starting one if there is none, it exited,
+/// or its screen doesn't match the window size picked for this launch.
+fn ensure_xvfb(config: &XvfbConfig, window_size: (u32, u32)) -> Result<String, Box<dyn Error>> {
+    let mut xvfb = XVFB.lock().unwrap();
+    if let Some(mut server) = xvfb.take() {
+        match server.child.try_wait()? {
+            None if server.size == window_size => {
+                let display = server.display.clone();
+                *xvfb = Some(server);
+                return Ok(display);
+            }
+            None => {
+                log_message(
+                    &format!(
+                        "Restarting Xvfb on {} at {}x{}",
+                        server.display, window_size.0, window_size.1
+                    ),
+                    "INFO",
+                );
+                kill_xvfb(&mut server);
+            }
+            Some(status) => log_message(
+                &format!(
+                    "Xvfb on {} exited ({}), starting a new one",
+                    server.display, status
+                ),
+                "WARN",
+            ),
+        }
+    }
+    let server = start_xvfb(config, window_size)?;
+    let display = server.display.clone();
+    *xvfb = Some(server);
+    Ok(display)
+}
+
+fn start_xvfb(
+    config: &XvfbConfig,
+    (width, height): (u32, u32),
+) -> Result<XvfbServer, Box<dyn Error>> {
+    let number = match config.display {
+        Some(number) => number,
+        None => free_display_number(Path::new("/tmp"))
+            .ok_or("No free X display number between :99 and :199")?,
+    };
+    let display = format!(":{}", number);
+    let child = Command::new(&config.binary)
+        .arg(&display)
+        .args([
+            "-screen",
+            "0",
+            &format!("{}x{}x{}", width, height, config.depth),
+        ])
+        .args(["-nolisten", "tcp"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start {}: {}", config.binary, e))?;
+    let mut server = XvfbServer {
+        child,
+        display,
+        size: (width, height),
+    };
+
+    // Xvfb is ready once its socket exists
+    let socket = format!("/tmp/.X11-unix/X{}", number);
+    let started = Instant::now();
+    while !Path::new(&socket).exists() {
+        if let Some(status) = server.child.try_wait()? {
+            return Err(format!("Xvfb exited during startup: {}", status).into());
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            kill_xvfb(&mut server);
+            return Err("Xvfb did not come up within 10s".into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    log_message(
+        &format!("Started Xvfb on {} at {}x{}", server.display, width, height),
+        "INFO",
+    );
+    Ok(server)
+}
+
+/// The first display number from 99 to 199 with neither a lock file nor a socket under `tmp`.
+fn free_display_number(tmp: &Path) -> Option<u32> {
+    (99..200).find(|number| {
+        !tmp.join(format!(".X{}-lock", number)).exists()
+            && !tmp.join(format!(".X11-unix/X{}", number)).exists()
+    })
+}
+
+fn kill_xvfb(server: &mut XvfbServer) {
+    let _ = server.child.kill();
+    let _ = server.child.wait();
+}
+
+/// True when the manager started an Xvfb server that has since exited.
+pub fn xvfb_exited() -> bool {
+    match XVFB.lock().unwrap().as_mut() {
+        Some(server) => !matches!(server.child.try_wait(), Ok(None)),
+        None => false,
+    }
+}
+
+/// Stops the managed Xvfb server, if any.
+pub fn stop_xvfb() {
+    if let Some(mut server) = XVFB.lock().unwrap().take() {
+        log_message(&format!("Stopping Xvfb on {}", server.display), "INFO");
+        kill_xvfb(&mut server);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SIZE: (u32, u32) = (1920, 1080);
+
+    fn config(mode: BrowserMode, no_display: NoDisplayPolicy) -> BrowserConfig {
+        let mut config = BrowserConfig {
+            mode,
+            no_display,
+            ..BrowserConfig::default()
+        };
+        // Never starts: a launch that goes for Xvfb fails saying so
+        config.xvfb.binary = String::from("/nonexistent/Xvfb");
+        config.xvfb.display = Some(150);
+        config
+    }
+
+    fn env(x11: bool, wayland: bool) -> DisplayEnv {
+        DisplayEnv {
+            x11: x11.then(|| OsString::from(":0")),
+            wayland: wayland.then(|| OsString::from("wayland-0")),
+        }
+    }
+
+    const POLICIES: [NoDisplayPolicy; 3] = [
+        NoDisplayPolicy::Headless,
+        NoDisplayPolicy::Xvfb,
+        NoDisplayPolicy::Fail,
+    ];
+    const ENVS: [(bool, bool); 4] = [(false, false), (true, false), (false, true), (true, true)];
+
+    #[test]
+    fn headless_modes_ignore_the_display_and_name_their_implementation() {
+        for policy in POLICIES {
+            for (x11, wayland) in ENVS {
+                let env = env(x11, wayland);
+                let old =
+                    resolve_display(&config(BrowserMode::Headless, policy), SIZE, &env).unwrap();
+                assert_eq!(old.mode, BrowserMode::Headless);
+                assert_eq!(old.args, ["--headless=old"]);
+                assert!(old.envs.is_none());
+
+                let new =
+                    resolve_display(&config(BrowserMode::NewHeadless, policy), SIZE, &env).unwrap();
+                assert_eq!(new.mode, BrowserMode::NewHeadless);
+                assert_eq!(new.args, ["--headless=new"]);
+                assert!(new.envs.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn headful_mode_uses_the_display_it_finds() {
+        for policy in POLICIES {
+            let config = config(BrowserMode::Headful, policy);
+            for (x11, wayland) in [(true, false), (true, true)] {
+                let setup = resolve_display(&config, SIZE, &env(x11, wayland)).unwrap();
+                assert_eq!(setup.mode, BrowserMode::Headful);
+                assert!(setup.args.is_empty());
+                assert!(setup.envs.is_none());
+            }
+            let setup = resolve_display(&config, SIZE, &env(false, true)).unwrap();
+            assert_eq!(setup.mode, BrowserMode::Headful);
+            assert_eq!(setup.args, ["--ozone-platform=wayland"]);
+        }
+    }
+
+    #[test]
+    fn without_a_display_the_policy_decides() {
+        let none = env(false, false);
+        let headless = config(BrowserMode::Headful, NoDisplayPolicy::Headless);
+        let setup = resolve_display(&headless, SIZE, &none).unwrap();
+        assert_eq!(setup.mode, BrowserMode::NewHeadless);
+        assert_eq!(setup.args, ["--headless=new"]);
+
+        let xvfb = config(BrowserMode::Headful, NoDisplayPolicy::Xvfb);
+        let error = resolve_display(&xvfb, SIZE, &none).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Failed to start /nonexistent/Xvfb"));
+
+        let fail = config(BrowserMode::Headful, NoDisplayPolicy::Fail);
+        let error = resolve_display(&fail, SIZE, &none).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Headful mode needs DISPLAY or WAYLAND_DISPLAY and neither is set"
+        );
+
+        // xvfb.always goes for Xvfb even with a display
+        let mut always = config(BrowserMode::Headful, NoDisplayPolicy::Fail);
+        always.xvfb.always = true;
+        let error = resolve_display(&always, SIZE, &env(true, true)).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Failed to start /nonexistent/Xvfb"));
+    }
+
+    #[test]
+    fn the_first_display_without_a_lock_or_socket_is_free() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(free_display_number(tmp.path()), Some(99));
+
+        fs::create_dir(tmp.path().join(".X11-unix")).unwrap();
+        fs::write(tmp.path().join(".X99-lock"), "").unwrap();
+        fs::write(tmp.path().join(".X11-unix/X100"), "").unwrap();
+        assert_eq!(free_display_number(tmp.path()), Some(101));
+
+        for number in 101..199 {
+            fs::write(tmp.path().join(format!(".X{}-lock", number)), "").unwrap();
+        }
+        assert_eq!(free_display_number(tmp.path()), Some(199));
+        fs::write(tmp.path().join(".X199-lock"), "").unwrap();
+        assert_eq!(free_display_number(tmp.path()), None);
+    }
+}
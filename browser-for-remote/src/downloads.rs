@@ -0,0 +1,441 @@
+// downloads.rs
+//
+// Downloads started by job tabs. Each such job runs in a browser context of its own, whose
+// downloads Chromium saves, named by GUID, into a private staging directory for the run; when
+// the job ends its finished downloads are checked against its limits and moved into its own
+// directory under the names the pages suggested.
+
+use crate::browser::ManagedTab;
+use crate::utils::log_message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Responses remembered per tab to look up a download's MIME type.
+const MIME_TYPES_KEPT: usize = 256;
+
+/// What a job does with the files its page downloads.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadConfig {
+    /// Where finished downloads are moved.
+    pub dir: PathBuf,
+    /// Larger downloads are cancelled, or deleted if they finish first.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Accepted MIME types, such as `application/pdf` or `image/*`. Empty accepts any.
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    /// How long the job waits, once it is otherwise done, for downloads still in progress.
+    #[serde(default = "default_wait")]
+    pub wait_secs: u64,
+}
+
+fn default_wait() -> u64 {
+    60
+}
+
+/// A download in a job's result.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DownloadedFile {
+    pub url: String,
+    /// The name the page suggested.
+    pub file_name: String,
+    /// Where the file was saved, for completed downloads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    pub bytes: u64,
+    /// `completed`, `too_large`, `type_not_allowed`, `canceled` (by the page or the user) or
+    /// `incomplete` (still going when the job ended).
+    pub state: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DownloadState {
+    InProgress,
+    Completed,
+    Canceled,
+}
+
+#[derive(Debug)]
+struct Download {
+    guid: String,
+    url: String,
+    file_name: String,
+    bytes: u64,
+    total: u64,
+    progress: DownloadState,
+    /// Set when the manager cancelled it.
+    cancelled_as: Option<&'static str>,
+}
+
+/// What the tab's download events have said so far.
+#[derive(Debug, Default)]
+struct Tracked {
+    /// In the order they began.
+    downloads: Vec<Download>,
+    /// Content type of each response, by URL.
+    mime_types: HashMap<String, String>,
+}
+
+impl Tracked {
+    fn handle(&mut self, method: &str, params: &Value) {
+        let text = |field: &str| params[field].as_str().unwrap_or_default().to_string();
+        match method {
+            "Page.downloadWillBegin" => self.downloads.push(Download {
+                guid: text("guid"),
+                url: text("url"),
+                file_name: text("suggestedFilename"),
+                bytes: 0,
+                total: 0,
+                progress: DownloadState::InProgress,
+                cancelled_as: None,
+            }),
+            "Page.downloadProgress" => {
+                let guid = text("guid");
+                let Some(download) = self.downloads.iter_mut().find(|d| d.guid == guid) else {
+                    return;
+                };
+                download.bytes = params["receivedBytes"].as_f64().unwrap_or(0.0) as u64;
+                download.total = params["totalBytes"].as_f64().unwrap_or(0.0) as u64;
+                download.progress = match params["state"].as_str() {
+                    Some("completed") => DownloadState::Completed,
+                    Some("canceled") => DownloadState::Canceled,
+                    _ => DownloadState::InProgress,
+                };
+            }
+            "Network.responseReceived" => {
+                let response = &params["response"];
+                if let (Some(url), Some(mime_type)) =
+                    (response["url"].as_str(), response["mimeType"].as_str())
+                {
+                    if self.mime_types.len() >= MIME_TYPES_KEPT {
+                        self.mime_types.clear();
+                    }
+                    self.mime_types
+                        .insert(url.to_string(), mime_type.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn in_progress(&self) -> bool {
+        self.downloads
+            .iter()
+            .any(|d| d.progress == DownloadState::InProgress && d.cancelled_as.is_none())
+    }
+}
+
+/// Download tracking for one job run.
+pub struct JobDownloads {
+    config: DownloadConfig,
+    browser_context_id: String,
+    /// Readable only by the manager's user, and removed with whatever is left in it when the
+    /// run's tracking is dropped.
+    staging: TempDir,
+    tracked: Arc<Mutex<Tracked>>,
+}
+
+impl JobDownloads {
+    /// Starts tracking `tab`'s downloads. The tab must be alone in its browser context, and not
+    /// yet navigated to the job's page.
+    pub fn watch(
+        tab: &dyn ManagedTab,
+        browser_context_id: &str,
+        config: &DownloadConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let staging = tempfile::Builder::new()
+            .prefix("browser-for-remote-downloads-")
+            .permissions(fs::Permissions::from_mode(0o700))
+            .tempdir()?;
+        fs::create_dir_all(&config.dir)
+            .map_err(|e| format!("Failed to create {}: {}", config.dir.display(), e))?;
+        let tracked = Arc::new(Mutex::new(Tracked::default()));
+        let events = Arc::clone(&tracked);
+        tab.on_download(Box::new(move |method, params| {
+            events.lock().unwrap().handle(method, params)
+        }))?;
+        tab.set_download_dir(browser_context_id, staging.path())?;
+        Ok(JobDownloads {
+            config: config.clone(),
+            browser_context_id: browser_context_id.to_string(),
+            staging,
+            tracked,
+        })
+    }
+
+    /// Waits up to `wait_secs`, and not past `deadline`, for downloads in progress to finish,
+    /// cancelling any that outgrows `max_bytes`.
+    pub fn settle(&self, tab: &dyn ManagedTab, deadline: Instant) {
+        let deadline = deadline.min(Instant::now() + Duration::from_secs(self.config.wait_secs));
+        loop {
+            self.cancel_oversized(tab);
+            if !self.tracked.lock().unwrap().in_progress() || Instant::now() >= deadline {
+                return;
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    fn cancel_oversized(&self, tab: &dyn ManagedTab) {
+        let Some(max_bytes) = self.config.max_bytes else {
+            return;
+        };
+        let oversized: Vec<String> = self
+            .tracked
+            .lock()
+            .unwrap()
+            .downloads
+            .iter()
+            .filter(|d| d.progress == DownloadState::InProgress && d.cancelled_as.is_none())
+            .filter(|d| d.bytes.max(d.total) > max_bytes)
+            .map(|d| d.guid.clone())
+            .collect();
+        for guid in oversized {
+            self.cancel(tab, &guid, "too_large");
+        }
+    }
+
+    fn cancel(&self, tab: &dyn ManagedTab, guid: &str, state: &'static str) {
+        if let Err(e) = tab.cancel_download(&self.browser_context_id, guid) {
+            log_message(
+                &format!("Failed to cancel download {}: {}", guid, e),
+                "WARN",
+            );
+        }
+        let mut tracked = self.tracked.lock().unwrap();
+        if let Some(download) = tracked.downloads.iter_mut().find(|d| d.guid == guid) {
+            download.cancelled_as = Some(state);
+        }
+    }
+
+    /// Cancels what is still going, moves the finished files that pass the limits into the
+    /// job's directory, deletes the rest and reports every download.
+    pub fn collect(&self, tab: &dyn ManagedTab) -> Vec<DownloadedFile> {
+        self.cancel_oversized(tab);
+        let unfinished: Vec<String> = self
+            .tracked
+            .lock()
+            .unwrap()
+            .downloads
+            .iter()
+            .filter(|d| d.progress == DownloadState::InProgress && d.cancelled_as.is_none())
+            .map(|d| d.guid.clone())
+            .collect();
+        for guid in unfinished {
+            self.cancel(tab, &guid, "incomplete");
+        }
+
+        let tracked = self.tracked.lock().unwrap();
+        tracked
+            .downloads
+            .iter()
+            .map(|download| {
+                let staged = self.staging.path().join(&download.guid);
+                let mime_type = tracked
+                    .mime_types
+                    .get(&download.url)
+                    .cloned()
+                    .or_else(|| guess_mime_type(&download.file_name).map(String::from));
+                let mut file = DownloadedFile {
+                    url: download.url.clone(),
+                    file_name: download.file_name.clone(),
+                    path: None,
+                    mime_type,
+                    bytes: download.bytes,
+                    state: download.cancelled_as.unwrap_or("canceled"),
+                };
+                if download.progress == DownloadState::Completed && download.cancelled_as.is_none()
+                {
+                    file.state = self.keep(&staged, &mut file);
+                }
+                if file.path.is_none() {
+                    let _ = fs::remove_file(&staged);
+                }
+                file
+            })
+            .collect()
+    }
+
+    /// Moves a finished download into the job's directory if it passes the limits. Returns
+    /// its state.
+    fn keep(&self, staged: &Path, file: &mut DownloadedFile) -> &'static str {
+        file.bytes = fs::metadata(staged)
+            .map(|metadata| metadata.len())
+            .unwrap_or(file.bytes);
+        if self.config.max_bytes.is_some_and(|max| file.bytes > max) {
+            return "too_large";
+        }
+        if !mime_type_allowed(&self.config.mime_types, file.mime_type.as_deref()) {
+            return "type_not_allowed";
+        }
+        let destination = unused_path(&self.config.dir, &file.file_name);
+        // The staging directory may be on another filesystem
+        let moved = fs::rename(staged, &destination)
+            .or_else(|_| fs::copy(staged, &destination).and_then(|_| fs::remove_file(staged)));
+        match moved {
+            Ok(()) => {
+                file.path = Some(destination);
+                "completed"
+            }
+            Err(e) => {
+                log_message(
+                    &format!("Failed to save download {}: {}", file.url, e),
+                    "ERROR",
+                );
+                "incomplete"
+            }
+        }
+    }
+}
+
+/// Whether `mime_type` is in `allowed`, where `type/*` accepts any subtype. An empty list
+/// accepts anything, an unknown type only an empty list.
+fn mime_type_allowed(allowed: &[String], mime_type: Option<&str>) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let Some(mime_type) = mime_type else {
+        return false;
+    };
+    let mime_type = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_suffix("/*") {
+            Some(kind) => mime_type.split('/').next() == Some(kind),
+            None => allowed == mime_type,
+        }
+    })
+}
+
+/// The MIME type usual for the file name's extension, for downloads without a response to
+/// go by, such as `blob:` and `data:` URLs.
+fn guess_mime_type(file_name: &str) -> Option<&'static str> {
+    let extension = Path::new(file_name)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "pdf" => "application/pdf",
+        "csv" => "text/csv",
+        "txt" | "log" => "text/plain",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        _ => return None,
+    })
+}
+
+/// `dir/file_name`, or `dir/stem (n).ext` if that is taken. Only the name's last component
+/// is used, so a page can't steer the file out of `dir`.
+fn unused_path(dir: &Path, file_name: &str) -> PathBuf {
+    let name = Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.is_empty() && *name != "..")
+        .unwrap_or("download");
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn events_track_each_download_and_its_mime_type() {
+        let mut tracked = Tracked::default();
+        tracked.handle(
+            "Network.responseReceived",
+            &json!({ "response": { "url": "http://app.test/r.pdf", "mimeType": "application/pdf" } }),
+        );
+        tracked.handle(
+            "Page.downloadWillBegin",
+            &json!({ "frameId": "A", "guid": "g1", "url": "http://app.test/r.pdf", "suggestedFilename": "r.pdf" }),
+        );
+        assert!(tracked.in_progress());
+        tracked.handle(
+            "Page.downloadProgress",
+            &json!({ "guid": "g1", "receivedBytes": 10.0, "totalBytes": 10.0, "state": "completed" }),
+        );
+        assert!(!tracked.in_progress());
+        assert_eq!(tracked.downloads[0].bytes, 10);
+        assert_eq!(
+            tracked.mime_types["http://app.test/r.pdf"],
+            "application/pdf"
+        );
+    }
+
+    #[test]
+    fn mime_types_match_exactly_or_by_kind() {
+        let allowed = vec![String::from("application/pdf"), String::from("image/*")];
+        assert!(mime_type_allowed(&allowed, Some("application/pdf")));
+        assert!(mime_type_allowed(&allowed, Some("Image/PNG")));
+        assert!(mime_type_allowed(
+            &allowed,
+            Some("application/pdf; charset=binary")
+        ));
+        assert!(!mime_type_allowed(&allowed, Some("text/csv")));
+        assert!(!mime_type_allowed(&allowed, None));
+        assert!(mime_type_allowed(&[], None));
+        assert_eq!(guess_mime_type("Report.CSV"), Some("text/csv"));
+        assert_eq!(guess_mime_type("README"), None);
+    }
+
+    #[test]
+    fn saved_names_stay_in_the_directory_and_never_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            unused_path(dir.path(), "report.pdf"),
+            dir.path().join("report.pdf")
+        );
+        fs::write(dir.path().join("report.pdf"), "").unwrap();
+        assert_eq!(
+            unused_path(dir.path(), "report.pdf"),
+            dir.path().join("report (1).pdf")
+        );
+        assert_eq!(
+            unused_path(dir.path(), "../../etc/passwd"),
+            dir.path().join("passwd")
+        );
+        assert_eq!(unused_path(dir.path(), ""), dir.path().join("download"));
+    }
+}
@@ -0,0 +1,237 @@
+// events.rs
+
+use crate::config::WebhookConfig;
+use crate::utils::log_message;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+
+/// Events buffered for each subscriber before the slowest starts missing them.
+const EVENT_BACKLOG: usize = 1024;
+
+static EVENTS: OnceLock<broadcast::Sender<LifecycleEvent>> = OnceLock::new();
+
+/// Something that happened to a tab or the browser.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LifecycleEvent {
+    /// `tab_opened`, `tab_navigated`, `tab_closed`, `tab_reaped`, `tab_crashed`, `tab_hung`,
+    /// `browser_started` or `browser_stopped`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tab_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Why it happened or what was done about it, such as a reap reason or a recovery.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn sender() -> &'static broadcast::Sender<LifecycleEvent> {
+    EVENTS.get_or_init(|| broadcast::channel(EVENT_BACKLOG).0)
+}
+
+/// Receives every event published from now on.
+pub fn subscribe() -> broadcast::Receiver<LifecycleEvent> {
+    sender().subscribe()
+}
+
+/// Publishes an event about a tab.
+pub fn tab_event(kind: &'static str, tab_id: &str, url: &str, detail: Option<String>) {
+    publish(LifecycleEvent {
+        kind,
+        tab_id: Some(tab_id.to_string()),
+        url: Some(url.to_string()),
+        detail,
+        timestamp: Utc::now(),
+    });
+}
+
+/// Publishes an event about the browser as a whole.
+pub fn browser_event(kind: &'static str, detail: Option<&str>) {
+    publish(LifecycleEvent {
+        kind,
+        tab_id: None,
+        url: None,
+        detail: detail.map(String::from),
+        timestamp: Utc::now(),
+    });
+}
+
+fn publish(event: LifecycleEvent) {
+    // Nobody listening is not an error
+    let _ = sender().send(event);
+}
+
+/// Appends `event` to the JSONL file at `path`.
+pub fn append_to_file(path: &Path, event: &LifecycleEvent) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(event)?)?;
+    Ok(())
+}
+
+/// Whether `webhook` asked for events of this type.
+pub fn wants(webhook: &WebhookConfig, event: &LifecycleEvent) -> bool {
+    webhook.events.is_empty() || webhook.events.iter().any(|kind| kind == event.kind)
+}
+
+/// POSTs `event` to `url`, retrying up to `retries` times, 1s apart and doubling, until it
+/// gets a success status.
+pub async fn deliver_webhook(
+    client: reqwest::Client,
+    url: String,
+    event: LifecycleEvent,
+    retries: u32,
+) {
+    let mut delay = Duration::from_secs(1);
+    for attempt in 0..=retries {
+        let reason = match client.post(&url).json(&event).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => response.status().to_string(),
+            Err(e) => e.to_string(),
+        };
+        if attempt == retries {
+            log_message(
+                &format!(
+                    "Gave up sending {} event to {}: {}",
+                    event.kind, url, reason
+                ),
+                "ERROR",
+            );
+            return;
+        }
+        log_message(
+            &format!(
+                "Failed to send {} event to {} ({}), retrying in {}s",
+                event.kind,
+                url,
+                reason,
+                delay.as_secs()
+            ),
+            "WARN",
+        );
+        sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn event(kind: &'static str) -> LifecycleEvent {
+        LifecycleEvent {
+            kind,
+            tab_id: Some(String::from("tab")),
+            url: Some(String::from("http://app.test/")),
+            detail: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn published_events_reach_subscribers_and_the_file() {
+        let mut events = subscribe();
+        tab_event("tab_opened", "events-test-tab", "http://app.test/", None);
+        let received = loop {
+            let event = events.try_recv().unwrap();
+            if event.tab_id.as_deref() == Some("events-test-tab") {
+                break event;
+            }
+        };
+        assert_eq!(received.kind, "tab_opened");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        append_to_file(&path, &received).unwrap();
+        append_to_file(&path, &event("tab_closed")).unwrap();
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["type"], "tab_opened");
+        assert_eq!(lines[1]["type"], "tab_closed");
+        assert!(lines[1].get("detail").is_none());
+    }
+
+    #[test]
+    fn webhooks_only_get_the_types_they_ask_for() {
+        let all = WebhookConfig {
+            url: String::new(),
+            events: Vec::new(),
+        };
+        let crashes = WebhookConfig {
+            url: String::new(),
+            events: vec![String::from("tab_crashed")],
+        };
+        assert!(wants(&all, &event("tab_opened")));
+        assert!(wants(&crashes, &event("tab_crashed")));
+        assert!(!wants(&crashes, &event("tab_opened")));
+    }
+
+    #[test]
+    fn failed_webhook_deliveries_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        // Fails the first delivery, accepts the second and returns what it was sent
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in ["500 Internal Server Error", "204 No Content"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:")
+                    {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            bodies
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(deliver_webhook(
+            reqwest::Client::new(),
+            url,
+            event("tab_crashed"),
+            2,
+        ));
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        let delivered: Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(delivered["type"], "tab_crashed");
+        assert_eq!(delivered["tab_id"], "tab");
+    }
+}
@@ -0,0 +1,354 @@
+// focus.rs
+
+use crate::browser::ManagedTab;
+use crate::config::{LiveConfig, RotationConfig, RotationPolicy};
+use crate::leases::{LeaseFocus, LeaseTable};
+use crate::utils::log_message;
+use crate::TabMetadata;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The tab that currently has focus and how long it is owed.
+struct Focused {
+    tab_id: String,
+    since: Instant,
+    dwell: Duration,
+}
+
+/// Decides which tab is in the foreground, one main-loop pass at a time.
+#[derive(Default)]
+pub struct FocusRotation {
+    current: Option<Focused>,
+    /// The last tab focused, so round-robin carries on after it.
+    last_tab_id: Option<String>,
+}
+
+/// A tab the rotation may focus, with its URL rule's weight.
+struct Candidate<'a> {
+    tab: &'a dyn ManagedTab,
+    weight: u32,
+}
+
+impl FocusRotation {
+    /// Moves focus on if the current tab has had its dwell time, per the configured policy.
+    pub fn tick<T: ManagedTab + 'static>(
+        &mut self,
+        tabs: &[Arc<T>],
+        tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+        leases: &Mutex<LeaseTable>,
+        live: &RwLock<LiveConfig>,
+    ) {
+        let (config, candidates) = {
+            let live = live.read().unwrap();
+            let leases = leases.lock().unwrap();
+            // The first tab is never rotated
+            let candidates: Vec<Candidate> = tabs
+                .iter()
+                .skip(1)
+                .filter_map(|tab| {
+                    let url = tab.url();
+                    if !live.rules.policy_for(&url).rotates() {
+                        return None;
+                    }
+                    // Leave leased tabs alone unless their lease allows rotation
+                    let lease = leases.lease_for_tab(tab.target_id());
+                    if lease.is_some_and(|lease| lease.focus == LeaseFocus::Never) {
+                        return None;
+                    }
+                    Some(Candidate {
+                        tab: tab.as_ref(),
+                        weight: live.rules.weight_for(&url),
+                    })
+                })
+                .collect();
+            (live.config.rotation.clone(), candidates)
+        };
+
+        if config.policy == RotationPolicy::Sweep {
+            sweep(&candidates, tab_metadata);
+            return;
+        }
+
+        if let Some(current) = &self.current {
+            let still_eligible = candidates
+                .iter()
+                .find(|candidate| candidate.tab.target_id() == current.tab_id);
+            if let Some(candidate) = still_eligible {
+                let elapsed = current.since.elapsed();
+                if elapsed < current.dwell {
+                    return;
+                }
+                if config.until_loaded
+                    && elapsed < Duration::from_millis(config.max_dwell_ms)
+                    && !is_loaded(candidate.tab)
+                {
+                    return;
+                }
+            }
+        }
+        self.release_current(tab_metadata);
+
+        let next = {
+            let tab_metadata = tab_metadata.lock().unwrap();
+            match config.policy {
+                RotationPolicy::Fair => pick_fairest(&candidates, &tab_metadata),
+                _ => self.pick_next_in_order(&candidates),
+            }
+        };
+        if let Some(next) = next {
+            self.focus(next, &config, tab_metadata);
+        }
+    }
+
+    /// Credits the outgoing tab with the time it spent in the foreground.
+    fn release_current(&mut self, tab_metadata: &Mutex<HashMap<String, TabMetadata>>) {
+        if let Some(current) = self.current.take() {
+            if let Some(metadata) = tab_metadata.lock().unwrap().get_mut(&current.tab_id) {
+                metadata.foreground_time += current.since.elapsed();
+            }
+        }
+    }
+
+    /// The candidate after the current one, wrapping round.
+    fn pick_next_in_order<'a>(&self, candidates: &'a [Candidate<'a>]) -> Option<&'a Candidate<'a>> {
+        let previous = self.last_tab_id.as_deref();
+        let position = previous.and_then(|tab_id| {
+            candidates
+                .iter()
+                .position(|candidate| candidate.tab.target_id() == tab_id)
+        });
+        match position {
+            Some(position) => candidates.get((position + 1) % candidates.len()),
+            None => candidates.first(),
+        }
+    }
+
+    fn focus(
+        &mut self,
+        candidate: &Candidate,
+        config: &RotationConfig,
+        tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    ) {
+        let tab_id = candidate.tab.target_id().to_string();
+        bring_to_front(candidate.tab);
+        if let Some(metadata) = tab_metadata.lock().unwrap().get_mut(&tab_id) {
+            metadata.last_focused = Some(Instant::now());
+        }
+        // Round-robin turns a rule's weight into a longer stay; fair already picks heavier tabs
+        // more often
+        let dwell = match config.policy {
+            RotationPolicy::RoundRobin => Duration::from_millis(config.dwell_ms) * candidate.weight,
+            _ => Duration::from_millis(config.dwell_ms),
+        };
+        log_message(
+            &format!("Focusing tab {} for {}ms", tab_id, dwell.as_millis()),
+            "DEBUG",
+        );
+        self.last_tab_id = Some(tab_id.clone());
+        self.current = Some(Focused {
+            tab_id,
+            since: Instant::now(),
+            dwell,
+        });
+    }
+}
+
+/// The candidate that has had the least foreground time for its weight; ties go to the one
+/// focused longest ago.
+fn pick_fairest<'a>(
+    candidates: &'a [Candidate<'a>],
+    tab_metadata: &HashMap<String, TabMetadata>,
+) -> Option<&'a Candidate<'a>> {
+    candidates.iter().min_by(|a, b| {
+        let share = |candidate: &Candidate| {
+            let metadata = tab_metadata.get(candidate.tab.target_id());
+            (
+                metadata.map_or(0.0, |m| m.foreground_time.as_secs_f64()) / candidate.weight as f64,
+                metadata.and_then(|m| m.last_focused),
+            )
+        };
+        let (a_share, a_focused) = share(a);
+        let (b_share, b_focused) = share(b);
+        a_share
+            .total_cmp(&b_share)
+            .then_with(|| a_focused.cmp(&b_focused))
+    })
+}
+
+/// The legacy behaviour: every eligible tab to the front, back to back.
+fn sweep(candidates: &[Candidate], tab_metadata: &Mutex<HashMap<String, TabMetadata>>) {
+    for candidate in candidates {
+        bring_to_front(candidate.tab);
+        if let Some(metadata) = tab_metadata
+            .lock()
+            .unwrap()
+            .get_mut(candidate.tab.target_id())
+        {
+            metadata.last_focused = Some(Instant::now());
+        }
+    }
+}
+
+fn is_loaded(tab: &dyn ManagedTab) -> bool {
+    tab.ready_state()
+        .map(|ready_state| ready_state == "complete")
+        // Don't hold focus on a tab that can't be asked
+        .unwrap_or(true)
+}
+
+fn bring_to_front(tab: &dyn ManagedTab) {
+    if let Err(e) = tab.bring_to_front() {
+        log_message(&format!("Failed to bring to front tab: {}", e), "ERROR");
+        if let Err(e) = tab.activate() {
+            log_message(&format!("Failed to activate tab: {}", e), "ERROR");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LeaseConfig;
+    use crate::testing::{connect, empty_state, live_config};
+    use crate::track_tabs;
+    use mock_devtools::MockDevTools;
+    use serde_json::json;
+
+    /// The tabs brought to the front so far, in order.
+    fn focused(mock: &MockDevTools) -> Vec<String> {
+        mock.calls_to("Page.bringToFront")
+            .into_iter()
+            .filter_map(|call| call.target_id)
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_takes_each_tab_in_turn_and_skips_the_first() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let second = mock.open_target("http://second.test/");
+        let third = mock.open_target("http://third.test/");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "rotation": { "dwell_ms": 0 } }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+
+        let mut rotation = FocusRotation::default();
+        for _ in 0..4 {
+            rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        }
+
+        assert_eq!(
+            focused(&mock),
+            vec![second.clone(), third.clone(), second, third]
+        );
+    }
+
+    #[test]
+    fn dwell_time_holds_focus() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let second = mock.open_target("http://second.test/");
+        mock.open_target("http://third.test/");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "rotation": { "dwell_ms": 60000 } }));
+
+        let mut rotation = FocusRotation::default();
+        for _ in 0..3 {
+            rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        }
+
+        assert_eq!(focused(&mock), vec![second]);
+    }
+
+    #[test]
+    fn leases_that_forbid_focus_are_skipped() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let leased = mock.open_target("http://leased.test/");
+        let free = mock.open_target("http://free.test/");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "rotation": { "dwell_ms": 0 } }));
+        leases.lock().unwrap().grant(
+            &leased,
+            "test",
+            None,
+            Some(LeaseFocus::Never),
+            None,
+            &LeaseConfig::default(),
+        );
+
+        let mut rotation = FocusRotation::default();
+        for _ in 0..3 {
+            rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        }
+
+        assert_eq!(focused(&mock), vec![free.clone(), free.clone(), free]);
+    }
+
+    #[test]
+    fn until_loaded_waits_for_the_page() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let loading = mock.open_target("http://loading.test/");
+        let next = mock.open_target("http://next.test/");
+        mock.set_ready_state(&loading, "loading");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({
+            "rotation": { "dwell_ms": 0, "until_loaded": true, "max_dwell_ms": 60000 }
+        }));
+
+        let mut rotation = FocusRotation::default();
+        rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        assert_eq!(focused(&mock), vec![loading.clone()]);
+
+        mock.set_ready_state(&loading, "complete");
+        rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        assert_eq!(focused(&mock), vec![loading, next]);
+    }
+
+    #[test]
+    fn crashed_tab_falls_back_to_activate() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let crashed = mock.open_target("http://crashed.test/");
+        let (_browser, tabs) = connect(&mock, 2);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "rotation": { "dwell_ms": 0 } }));
+        mock.crash(&crashed);
+
+        FocusRotation::default().tick(&tabs, &tab_metadata, &leases, &live);
+
+        let activated = mock.calls_to("Target.activateTarget");
+        assert_eq!(activated.len(), 1);
+        assert_eq!(activated[0].params["targetId"], crashed.as_str());
+    }
+
+    #[test]
+    fn fair_policy_credits_foreground_time() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let second = mock.open_target("http://second.test/");
+        mock.open_target("http://third.test/");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "rotation": { "policy": "fair", "dwell_ms": 20 } }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+
+        let mut rotation = FocusRotation::default();
+        rotation.tick(&tabs, &tab_metadata, &leases, &live);
+        std::thread::sleep(Duration::from_millis(30));
+        rotation.tick(&tabs, &tab_metadata, &leases, &live);
+
+        let tab_metadata = tab_metadata.lock().unwrap();
+        assert!(tab_metadata[&second].foreground_time >= Duration::from_millis(20));
+        // The tab that has had no time yet goes next
+        assert_eq!(focused(&mock).len(), 2);
+        assert_ne!(focused(&mock)[1], second);
+    }
+}
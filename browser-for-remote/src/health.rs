@@ -0,0 +1,361 @@
+// health.rs
+
+use crate::browser::{open_prepared_tab, ManagedBrowser, ManagedTab};
+use crate::config::LiveConfig;
+use crate::events::tab_event;
+use crate::leases::LeaseTable;
+use crate::proxy::ProxySettings;
+use crate::utils::log_message;
+use crate::TabMetadata;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// What happens to a tab whose renderer crashed or stopped answering.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Recovery {
+    /// Reload the page in place.
+    Reload,
+    /// Close the tab.
+    Close,
+    /// Open a fresh tab on the same URL and close the broken one.
+    Recreate,
+}
+
+/// Sends the tab's id on `crashes` whenever its renderer crashes.
+pub fn watch_for_crashes(
+    tab: &dyn ManagedTab,
+    crashes: UnboundedSender<String>,
+) -> Result<(), Box<dyn Error>> {
+    let tab_id = tab.target_id().to_string();
+    tab.on_crash(Box::new(move || {
+        let _ = crashes.send(tab_id.clone());
+    }))
+}
+
+/// Recovers a tab that crashed or hung as its URL rule, or else `health.on_crash`, says. A tab
+/// already recovered `health.max_recoveries` times, each within `health.reset_after_secs` of
+/// the one before, is closed instead.
+pub fn recover_tab<B: ManagedBrowser>(
+    browser: &B,
+    tab_id: &str,
+    problem: &str,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    live: &RwLock<LiveConfig>,
+    proxy: Option<&ProxySettings>,
+) {
+    let healthy_period = Duration::from_secs(live.read().unwrap().config.health.reset_after_secs);
+    let (tab, url, crash_count) = {
+        let mut tab_metadata_lock = tab_metadata.lock().unwrap();
+        let Some(metadata) = tab_metadata_lock.get_mut(tab_id) else {
+            return;
+        };
+        // A tab that stayed healthy long enough since its last recovery starts over
+        if metadata
+            .last_recovery
+            .is_some_and(|last| last.elapsed() >= healthy_period)
+        {
+            metadata.crash_count = 0;
+        }
+        metadata.crash_count += 1;
+        metadata.last_recovery = Some(Instant::now());
+        (
+            Arc::clone(&metadata.tab),
+            metadata.current_url.clone(),
+            metadata.crash_count,
+        )
+    };
+    let (recovery, max_recoveries) = {
+        let live = live.read().unwrap();
+        let health = &live.config.health;
+        let recovery = live.rules.recovery_for(&url).unwrap_or(health.on_crash);
+        (recovery, health.max_recoveries)
+    };
+    let recovery = if crash_count > max_recoveries {
+        log_message(
+            &format!(
+                "Tab {} {} again ({}), giving up after {} recoveries",
+                tab_id, problem, url, max_recoveries
+            ),
+            "WARN",
+        );
+        Recovery::Close
+    } else {
+        log_message(
+            &format!(
+                "Tab {} {} ({}), recovery {} of {}: {:?}",
+                tab_id, problem, url, crash_count, max_recoveries, recovery
+            ),
+            "WARN",
+        );
+        recovery
+    };
+    let kind = if problem == "crashed" {
+        "tab_crashed"
+    } else {
+        "tab_hung"
+    };
+    tab_event(
+        kind,
+        tab_id,
+        &url,
+        Some(format!("{:?}", recovery).to_lowercase()),
+    );
+
+    match recovery {
+        Recovery::Reload => {
+            if let Err(e) = tab.reload() {
+                log_message(&format!("Failed to reload tab {}: {}", tab_id, e), "ERROR");
+            }
+        }
+        Recovery::Close => {
+            tab_metadata.lock().unwrap().remove(tab_id);
+            if let Err(e) = tab.close() {
+                log_message(&format!("Failed to close tab: {}", e), "ERROR");
+            }
+        }
+        Recovery::Recreate => {
+            match recreate_tab(browser, tab_id, &url, tab_metadata, leases, live, proxy) {
+                Ok(new_tab_id) => {
+                    log_message(
+                        &format!("Replaced tab {} with {}", tab_id, new_tab_id),
+                        "INFO",
+                    );
+                    if let Err(e) = tab.close() {
+                        log_message(&format!("Failed to close tab: {}", e), "ERROR");
+                    }
+                }
+                Err(e) => log_message(
+                    &format!("Failed to recreate tab {}: {}", tab_id, e),
+                    "ERROR",
+                ),
+            }
+        }
+    }
+}
+
+/// Opens a tab on `url` set up like the one it replaces, moves the old tab's metadata and
+/// lease over to it and returns its id.
+fn recreate_tab<B: ManagedBrowser>(
+    browser: &B,
+    tab_id: &str,
+    url: &str,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    live: &RwLock<LiveConfig>,
+    proxy: Option<&ProxySettings>,
+) -> Result<String, Box<dyn Error>> {
+    let browser_context_id = tab_metadata
+        .lock()
+        .unwrap()
+        .get(tab_id)
+        .and_then(|metadata| metadata.browser_context_id.clone());
+    let (identity, stealth) = {
+        let live = live.read().unwrap();
+        let identity = leases
+            .lock()
+            .unwrap()
+            .lease_for_tab(tab_id)
+            .and_then(|lease| lease.identity.as_ref())
+            .and_then(|name| live.config.identities.get(name))
+            .cloned();
+        (identity, live.config.stealth.enabled)
+    };
+    // In the old tab's context, so an incognito or proxied tab stays that way
+    let tab = open_prepared_tab(
+        browser,
+        url,
+        browser_context_id,
+        identity.as_ref(),
+        proxy,
+        stealth,
+    )?;
+    let new_tab_id = tab.target_id().to_string();
+
+    let mut tab_metadata_lock = tab_metadata.lock().unwrap();
+    let old = tab_metadata_lock.remove(tab_id);
+    tab_metadata_lock.insert(
+        new_tab_id.clone(),
+        TabMetadata {
+            open_time: old.as_ref().map_or_else(Instant::now, |old| old.open_time),
+            last_url_change_time: Instant::now(),
+            current_url: url.to_string(),
+            tab: tab as Arc<dyn ManagedTab>,
+            foreground_time: old
+                .as_ref()
+                .map_or(Duration::ZERO, |old| old.foreground_time),
+            last_focused: None,
+            crash_count: old.as_ref().map_or(0, |old| old.crash_count),
+            last_recovery: old.as_ref().and_then(|old| old.last_recovery),
+            browser_context_id: old.and_then(|old| old.browser_context_id),
+        },
+    );
+    drop(tab_metadata_lock);
+    leases.lock().unwrap().move_tab(tab_id, &new_tab_id);
+    tab_event(
+        "tab_opened",
+        &new_tab_id,
+        url,
+        Some(format!("replaces {}", tab_id)),
+    );
+    Ok(new_tab_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LeaseConfig;
+    use crate::testing::{connect, empty_state, live_config};
+    use crate::track_tabs;
+    use mock_devtools::{wait_until, MockDevTools};
+    use serde_json::json;
+
+    fn crash_count(tab_metadata: &Mutex<HashMap<String, TabMetadata>>, tab_id: &str) -> u32 {
+        tab_metadata.lock().unwrap()[tab_id].crash_count
+    }
+
+    #[test]
+    fn crashed_tab_is_reloaded_by_default() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://app.test/");
+        let (browser, tabs) = connect(&mock, 1);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({}));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        mock.crash(&target_id);
+
+        recover_tab(
+            &browser,
+            &target_id,
+            "crashed",
+            &tab_metadata,
+            &leases,
+            &live,
+            None,
+        );
+
+        assert_eq!(mock.calls_to("Page.reload").len(), 1);
+        assert_eq!(crash_count(&tab_metadata, &target_id), 1);
+        assert_eq!(mock.target_ids(), vec![target_id]);
+    }
+
+    #[test]
+    fn url_rule_can_recreate_the_tab_and_keep_its_lease() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://app.test/inbox");
+        let (browser, tabs) = connect(&mock, 1);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({
+            "url_rules": [{ "host": "app.test", "action": "pin", "on_crash": "recreate" }],
+        }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        let lease = leases.lock().unwrap().grant(
+            &target_id,
+            "client",
+            None,
+            None,
+            None,
+            &LeaseConfig::default(),
+        );
+        tab_metadata
+            .lock()
+            .unwrap()
+            .get_mut(&target_id)
+            .unwrap()
+            .browser_context_id = Some(String::from("incognito-context"));
+        mock.crash(&target_id);
+
+        recover_tab(
+            &browser,
+            &target_id,
+            "crashed",
+            &tab_metadata,
+            &leases,
+            &live,
+            None,
+        );
+
+        assert!(wait_until(Duration::from_secs(5), || !mock
+            .target_ids()
+            .contains(&target_id)));
+        let new_tab_id = mock.target_ids().pop().unwrap();
+        assert_eq!(
+            mock.target_url(&new_tab_id).as_deref(),
+            Some("http://app.test/inbox")
+        );
+        assert_eq!(crash_count(&tab_metadata, &new_tab_id), 1);
+        assert_eq!(
+            mock.calls_to("Target.createTarget")[0].params["browserContextId"],
+            "incognito-context"
+        );
+        assert_eq!(
+            leases.lock().unwrap().list()[0].tab_id,
+            new_tab_id,
+            "lease {} should follow the tab",
+            lease.id
+        );
+    }
+
+    #[test]
+    fn tab_is_closed_once_it_runs_out_of_recoveries() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://flaky.test/");
+        let (browser, tabs) = connect(&mock, 1);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "health": { "max_recoveries": 1 } }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+
+        recover_tab(
+            &browser,
+            &target_id,
+            "hung",
+            &tab_metadata,
+            &leases,
+            &live,
+            None,
+        );
+        recover_tab(
+            &browser,
+            &target_id,
+            "hung",
+            &tab_metadata,
+            &leases,
+            &live,
+            None,
+        );
+
+        assert_eq!(mock.calls_to("Page.reload").len(), 1);
+        assert!(mock.target_ids().is_empty());
+        assert!(tab_metadata.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recoveries_are_forgotten_after_a_healthy_period() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://flaky.test/");
+        let (browser, tabs) = connect(&mock, 1);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "health": { "max_recoveries": 1, "reset_after_secs": 0 } }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+
+        for _ in 0..3 {
+            recover_tab(
+                &browser,
+                &target_id,
+                "hung",
+                &tab_metadata,
+                &leases,
+                &live,
+                None,
+            );
+        }
+
+        assert_eq!(mock.calls_to("Page.reload").len(), 3);
+        assert_eq!(crash_count(&tab_metadata, &target_id), 1);
+    }
+}
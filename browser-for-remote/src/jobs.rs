@@ -0,0 +1,489 @@
+// jobs.rs
+
+use crate::browser::{ManagedBrowser, ManagedTab};
+use crate::config::{LeaseConfig, LiveConfig};
+use crate::downloads::{DownloadConfig, DownloadedFile, JobDownloads};
+use crate::leases::{Lease, LeaseFocus, LeaseTable, NewLease};
+use crate::proxy::ProxySettings;
+use crate::{open_tab, TabMetadata};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs kept per job for `GET /jobs/<name>`.
+const HISTORY_LEN: usize = 20;
+/// How late a run may start and still count as on time.
+const ON_TIME: chrono::Duration = chrono::Duration::seconds(60);
+/// Longest `max_runtime_secs` a job may ask for: a day.
+const MAX_RUNTIME_SECS: u64 = 24 * 60 * 60;
+
+/// A job the manager runs on a schedule in a tab it leases to itself.
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobConfig {
+    pub name: String,
+    /// Cron expression: `min hour day month weekday`, or with a leading seconds field.
+    pub schedule: String,
+    pub url: String,
+    /// JavaScript run once the page has loaded; its value, awaited if a promise, is recorded.
+    pub script: Option<String>,
+    /// Identity profile the tab presents.
+    pub identity: Option<String>,
+    /// How long the tab stays open after the script.
+    #[serde(default)]
+    pub stay_secs: u64,
+    /// A run still going after this long is abandoned and its tab closed.
+    #[serde(default = "default_max_runtime")]
+    pub max_runtime_secs: u64,
+    #[serde(default)]
+    pub focus: LeaseFocus,
+    #[serde(default)]
+    pub missed: MissedRuns,
+    /// Where the page's downloads go, and which are kept. Without it downloads are left to
+    /// the browser.
+    #[serde(default)]
+    pub downloads: Option<DownloadConfig>,
+}
+
+fn default_max_runtime() -> u64 {
+    300
+}
+
+/// What happens to runs whose time came while the job couldn't run: the manager was down,
+/// the browser was restarting or the previous run was still going.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    /// Record them as skipped and wait for the next scheduled time.
+    #[default]
+    Skip,
+    /// Run once as soon as the job can, however many were missed.
+    RunOnce,
+}
+
+/// A job with its parsed schedule.
+#[derive(Debug, Clone)]
+pub struct ScheduledJob {
+    pub config: JobConfig,
+    pub schedule: Schedule,
+}
+
+/// Parses each job's schedule, rejecting bad expressions, duplicate names and runtimes over a
+/// day.
+pub fn compile_jobs(jobs: &[JobConfig]) -> Result<Vec<ScheduledJob>, Box<dyn Error>> {
+    let mut names = HashSet::new();
+    jobs.iter()
+        .map(|job| {
+            if !names.insert(job.name.as_str()) {
+                return Err(format!("duplicate job name {}", job.name).into());
+            }
+            if job.max_runtime_secs > MAX_RUNTIME_SECS {
+                return Err(format!(
+                    "job {}: max_runtime_secs must be at most {}",
+                    job.name, MAX_RUNTIME_SECS
+                )
+                .into());
+            }
+            Ok(ScheduledJob {
+                config: job.clone(),
+                schedule: parse_schedule(&job.schedule)
+                    .map_err(|e| format!("job {}: {}", job.name, e))?,
+            })
+        })
+        .collect()
+}
+
+/// Parses a cron expression, taking the usual five fields to mean second 0.
+fn parse_schedule(expression: &str) -> Result<Schedule, Box<dyn Error>> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&expression)
+        .map_err(|e| format!("invalid schedule {}: {}", expression, e).into())
+}
+
+/// One run of a job, or a scheduled time it skipped.
+#[derive(Serialize, Debug, Clone)]
+pub struct JobRun {
+    pub scheduled_for: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// `ok`, `failed`, `timed_out` or `skipped`.
+    pub outcome: &'static str,
+    /// The error, or why the run was skipped.
+    pub detail: Option<String>,
+    /// What the job's script returned.
+    pub result: Option<Value>,
+    /// The page's downloads, under `downloads`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub downloads: Vec<DownloadedFile>,
+}
+
+#[derive(Debug, Default)]
+struct JobState {
+    /// Latest scheduled time that has been run, skipped or queued.
+    last_fire: Option<DateTime<Utc>>,
+    running: bool,
+    /// A missed run waiting to start, under `missed: run_once`.
+    pending: Option<DateTime<Utc>>,
+    /// Newest first.
+    runs: VecDeque<JobRun>,
+}
+
+impl JobState {
+    fn record(&mut self, run: JobRun) {
+        self.runs.push_front(run);
+        self.runs.truncate(HISTORY_LEN);
+    }
+}
+
+/// Where each job is in its schedule, and its recent runs.
+#[derive(Debug, Default)]
+pub struct JobHistory {
+    jobs: HashMap<String, JobState>,
+}
+
+impl JobHistory {
+    /// Picks up the last scheduled times saved in the state file, so runs missed while the
+    /// manager was down count as missed.
+    pub fn restore(&mut self, last_fires: HashMap<String, DateTime<Utc>>) {
+        for (name, last_fire) in last_fires {
+            self.jobs.entry(name).or_default().last_fire = Some(last_fire);
+        }
+    }
+
+    /// The last scheduled time of each job, for the state file.
+    pub fn last_fires(&self) -> HashMap<String, DateTime<Utc>> {
+        self.jobs
+            .iter()
+            .filter_map(|(name, state)| Some((name.clone(), state.last_fire?)))
+            .collect()
+    }
+
+    /// Decides whether `job` starts a run at `now`, recording the times it has to skip.
+    /// Returns the scheduled time of the run to start, and marks the job running.
+    pub fn plan(
+        &mut self,
+        job: &ScheduledJob,
+        now: DateTime<Utc>,
+        browser_up: bool,
+    ) -> Option<DateTime<Utc>> {
+        let state = self.jobs.entry(job.config.name.clone()).or_default();
+        // A job seen for the first time starts from now rather than catching up on its past
+        let last_fire = *state.last_fire.get_or_insert(now);
+        let can_run = browser_up && !state.running;
+
+        let mut fires = 0;
+        let mut latest = None;
+        for fire in job
+            .schedule
+            .after(&last_fire)
+            .take_while(|fire| *fire <= now)
+        {
+            fires += 1;
+            latest = Some(fire);
+        }
+        if let Some(latest) = latest {
+            state.last_fire = Some(latest);
+            let run_now = can_run && now - latest <= ON_TIME;
+            let missed = fires - usize::from(run_now);
+            if missed > 0 {
+                match job.config.missed {
+                    // The run about to start stands in for the missed ones
+                    MissedRuns::RunOnce if run_now => {}
+                    MissedRuns::RunOnce => state.pending = Some(latest),
+                    MissedRuns::Skip => {
+                        let reason = if !browser_up {
+                            "browser not running"
+                        } else if state.running {
+                            "previous run still running"
+                        } else {
+                            "manager not running"
+                        };
+                        state.record(JobRun {
+                            scheduled_for: latest,
+                            started_at: None,
+                            finished_at: None,
+                            outcome: "skipped",
+                            detail: Some(format!("{} run(s) missed: {}", missed, reason)),
+                            result: None,
+                            downloads: Vec::new(),
+                        });
+                    }
+                }
+            }
+            if run_now {
+                state.pending = None;
+                state.running = true;
+                return Some(latest);
+            }
+        }
+        if can_run {
+            if let Some(pending) = state.pending.take() {
+                state.running = true;
+                return Some(pending);
+            }
+        }
+        None
+    }
+
+    /// Records a run that ended. The job still counts as running until `finish`.
+    pub fn record_run(&mut self, name: &str, run: JobRun) {
+        self.jobs.entry(name.to_string()).or_default().record(run);
+    }
+
+    /// Lets the job run again.
+    pub fn finish(&mut self, name: &str) {
+        self.jobs.entry(name.to_string()).or_default().running = false;
+    }
+
+    /// A job's schedule, next run and last run, plus every recorded run with `all_runs`.
+    pub fn to_json(&self, job: &ScheduledJob, now: DateTime<Utc>, all_runs: bool) -> Value {
+        let state = self.jobs.get(&job.config.name);
+        let runs = state.map(|state| &state.runs);
+        let mut summary = json!({
+            "name": job.config.name,
+            "schedule": job.config.schedule,
+            "url": job.config.url,
+            "running": state.is_some_and(|state| state.running),
+            "next_run": job.schedule.after(&now).next(),
+            "last_run": runs.and_then(|runs| runs.front()),
+        });
+        if all_runs {
+            summary["runs"] = json!(runs);
+        }
+        summary
+    }
+}
+
+/// A job's tab while it runs.
+pub struct JobTab {
+    pub lease: Lease,
+    pub tab: Arc<dyn ManagedTab>,
+    /// Tracks the page's downloads, under `downloads`.
+    pub downloads: Option<Arc<JobDownloads>>,
+}
+
+/// Opens the job's page in a tab leased to `job:<name>` for its whole runtime. A job with
+/// `downloads` gets a browser context of its own, kept in `job_contexts` for its later runs.
+pub fn start_job<B: ManagedBrowser>(
+    browser: &B,
+    job: &JobConfig,
+    job_contexts: &Mutex<HashMap<String, String>>,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    live: &RwLock<LiveConfig>,
+    proxy: Option<&ProxySettings>,
+) -> Result<JobTab, Box<dyn Error>> {
+    let (identity, lease_config, stealth) = {
+        let live = live.read().unwrap();
+        let identity = match &job.identity {
+            Some(name) => Some(
+                live.config
+                    .identities
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown identity {}", name))?,
+            ),
+            None => None,
+        };
+        (
+            identity,
+            live.config.lease.clone(),
+            live.config.stealth.enabled,
+        )
+    };
+    // Download settings apply to a whole browser context, so the job's tab needs one that no
+    // other tab uses. Runs of a job never overlap, so they can share it.
+    let browser_context_id = match job.downloads {
+        Some(_) => {
+            let mut job_contexts = job_contexts.lock().unwrap();
+            let id = match job_contexts.get(&job.name) {
+                Some(id) => id.clone(),
+                None => browser.create_browser_context()?,
+            };
+            job_contexts.insert(job.name.clone(), id.clone());
+            Some(id)
+        }
+        None => None,
+    };
+    // Downloads are tracked from before the page loads, as it may start one right away
+    let first_url = match job.downloads {
+        Some(_) => "about:blank",
+        None => &job.url,
+    };
+    let new_lease = NewLease {
+        url: first_url.to_string(),
+        browser_context_id: browser_context_id.clone(),
+        client: format!("job:{}", job.name),
+        ttl_secs: Some(job.max_runtime_secs.saturating_add(30)),
+        focus: Some(job.focus),
+        identity: job.identity.clone(),
+        // The limit is for clients; the job's own timeout bounds this lease
+        config: LeaseConfig {
+            max_ttl_secs: u64::MAX,
+            ..lease_config
+        },
+    };
+    let lease = open_tab(
+        browser,
+        &new_lease,
+        identity.as_ref(),
+        tab_metadata,
+        leases,
+        proxy,
+        stealth,
+    )?;
+    let Some(tab) = tab_metadata
+        .lock()
+        .unwrap()
+        .get(&lease.tab_id)
+        .map(|metadata| Arc::clone(&metadata.tab))
+    else {
+        leases.lock().unwrap().release(&lease.id);
+        return Err("job tab closed as soon as it opened".into());
+    };
+    let downloads = match (&job.downloads, &browser_context_id) {
+        (Some(config), Some(browser_context_id)) => {
+            let opened = JobDownloads::watch(tab.as_ref(), browser_context_id, config)
+                .and_then(|downloads| tab.navigate(&job.url).map(|_| downloads));
+            match opened {
+                Ok(downloads) => Some(Arc::new(downloads)),
+                Err(e) => {
+                    leases.lock().unwrap().release(&lease.id);
+                    return Err(e);
+                }
+            }
+        }
+        _ => None,
+    };
+    Ok(JobTab {
+        lease,
+        tab,
+        downloads,
+    })
+}
+
+/// Waits for the job's page to load, runs its script, keeps the tab open for `stay_secs` and
+/// waits for its downloads. Returns the script's value.
+pub fn run_job(
+    tab: &dyn ManagedTab,
+    job: &JobConfig,
+    downloads: Option<&JobDownloads>,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    let started = Instant::now();
+    let max_runtime = Duration::from_secs(job.max_runtime_secs);
+    while tab.ready_state().ok().as_deref() != Some("complete") {
+        if started.elapsed() > max_runtime {
+            return Err("page did not finish loading".into());
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    let result = job
+        .script
+        .as_deref()
+        .map(|script| tab.evaluate(script))
+        .transpose()?;
+    thread::sleep(Duration::from_secs(job.stay_secs));
+    if let Some(downloads) = downloads {
+        downloads.settle(tab, started + max_runtime);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn job(schedule: &str, missed: MissedRuns) -> ScheduledJob {
+        let config: JobConfig = serde_json::from_value(json!({
+            "name": "ping",
+            "schedule": schedule,
+            "url": "https://app.test/",
+        }))
+        .unwrap();
+        let mut job = compile_jobs(&[config]).unwrap().remove(0);
+        job.config.missed = missed;
+        job
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 19, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn schedules_take_five_or_six_fields_names_are_unique_and_runtimes_bounded() {
+        assert!(parse_schedule("*/15 * * * *").is_ok());
+        assert!(parse_schedule("30 */15 * * * *").is_ok());
+        assert!(parse_schedule("every minute").is_err());
+        let mut config = job("0 * * * *", MissedRuns::Skip).config;
+        assert!(compile_jobs(&[config.clone(), config.clone()]).is_err());
+        config.max_runtime_secs = u64::MAX;
+        assert!(compile_jobs(&[config]).is_err());
+    }
+
+    #[test]
+    fn overlapping_runs_are_skipped() {
+        let job = job("0 * * * *", MissedRuns::Skip);
+        let mut history = JobHistory::default();
+        assert_eq!(history.plan(&job, at(9, 30), true), None);
+        assert_eq!(history.plan(&job, at(10, 0), true), Some(at(10, 0)));
+        // Still running at the next hour
+        assert_eq!(history.plan(&job, at(11, 0), true), None);
+        let summary = history.to_json(&job, at(11, 0), true);
+        assert_eq!(summary["running"], true);
+        assert_eq!(summary["runs"][0]["outcome"], "skipped");
+        assert_eq!(
+            summary["runs"][0]["detail"],
+            "1 run(s) missed: previous run still running"
+        );
+
+        history.record_run(
+            "ping",
+            JobRun {
+                scheduled_for: at(10, 0),
+                started_at: Some(at(10, 0)),
+                finished_at: Some(at(11, 5)),
+                outcome: "ok",
+                detail: None,
+                result: None,
+                downloads: Vec::new(),
+            },
+        );
+        // Until its thread has stopped, a run that timed out still holds the job
+        assert_eq!(history.to_json(&job, at(11, 5), false)["running"], true);
+        history.finish("ping");
+        assert_eq!(history.plan(&job, at(11, 6), true), None);
+        assert_eq!(history.plan(&job, at(12, 0), true), Some(at(12, 0)));
+    }
+
+    #[test]
+    fn run_once_catches_up_on_runs_missed_while_down() {
+        let job = job("0 * * * *", MissedRuns::RunOnce);
+        let mut history = JobHistory::default();
+        history.restore(HashMap::from([(String::from("ping"), at(6, 0))]));
+
+        // Browser still launching: the missed runs wait
+        assert_eq!(history.plan(&job, at(9, 30), false), None);
+        assert_eq!(history.plan(&job, at(9, 31), true), Some(at(9, 0)));
+        assert_eq!(history.last_fires()["ping"], at(9, 0));
+
+        let mut skipping = JobHistory::default();
+        let job = self::job("0 * * * *", MissedRuns::Skip);
+        skipping.restore(HashMap::from([(String::from("ping"), at(6, 0))]));
+        assert_eq!(skipping.plan(&job, at(9, 30), true), None);
+        assert_eq!(
+            skipping.to_json(&job, at(9, 30), false)["last_run"]["detail"],
+            "3 run(s) missed: manager not running"
+        );
+    }
+}
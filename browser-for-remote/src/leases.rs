@@ -0,0 +1,227 @@
+// leases.rs
+
+use crate::config::LeaseConfig;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Whether the rotation loop may bring a leased tab to the front.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFocus {
+    #[default]
+    Rotate,
+    Never,
+}
+
+/// A tab handed out to a client. The manager leaves it alone, apart from what
+/// `focus` allows, until the lease is released or expires.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub id: String,
+    pub tab_id: String,
+    pub client: String,
+    pub focus: LeaseFocus,
+    /// Identity profile the tab was set up with.
+    pub identity: Option<String>,
+    pub ttl: Duration,
+    pub expires_at: Instant,
+}
+
+impl Lease {
+    pub fn to_json(&self) -> Value {
+        let remaining = self.expires_at.saturating_duration_since(Instant::now());
+        json!({
+            "lease_id": self.id,
+            "tab_id": self.tab_id,
+            "client": self.client,
+            "focus": self.focus,
+            "identity": self.identity,
+            "ttl_secs": self.ttl.as_secs(),
+            "expires_in_secs": remaining.as_secs(),
+            "expires_at": (chrono::Utc::now()
+                + chrono::Duration::from_std(remaining).unwrap_or_default())
+            .to_rfc3339(),
+        })
+    }
+}
+
+/// A tab to open for a client or a job, and the lease to hold it under.
+#[derive(Debug, Clone)]
+pub struct NewLease {
+    pub url: String,
+    pub browser_context_id: Option<String>,
+    pub client: String,
+    pub ttl_secs: Option<u64>,
+    pub focus: Option<LeaseFocus>,
+    /// Identity profile the tab is set up with.
+    pub identity: Option<String>,
+    pub config: LeaseConfig,
+}
+
+/// All active leases, keyed by lease id.
+#[derive(Debug, Default)]
+pub struct LeaseTable {
+    leases: HashMap<String, Lease>,
+    /// Tabs whose lease was released and that the main loop still has to close.
+    released_tabs: Vec<String>,
+}
+
+impl LeaseTable {
+    /// Records a lease on `tab_id`, clamping the requested TTL to the configured maximum.
+    pub fn grant(
+        &mut self,
+        tab_id: &str,
+        client: &str,
+        ttl_secs: Option<u64>,
+        focus: Option<LeaseFocus>,
+        identity: Option<String>,
+        config: &LeaseConfig,
+    ) -> Lease {
+        let ttl = Duration::from_secs(
+            ttl_secs
+                .unwrap_or(config.default_ttl_secs)
+                .min(config.max_ttl_secs)
+                .max(1),
+        );
+        let id: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let lease = Lease {
+            id: id.clone(),
+            tab_id: tab_id.to_string(),
+            client: client.to_string(),
+            focus: focus.unwrap_or(config.default_focus),
+            identity,
+            ttl,
+            expires_at: Instant::now() + ttl,
+        };
+        self.leases.insert(id, lease.clone());
+        lease
+    }
+
+    /// Puts back a lease carried over from before a restart.
+    pub fn restore(&mut self, lease: Lease) {
+        self.leases.insert(lease.id.clone(), lease);
+    }
+
+    /// Pushes the lease's expiry a full TTL into the future. A lease that has run out stays
+    /// expired even before its tab is reclaimed.
+    pub fn renew(&mut self, lease_id: &str) -> Option<Lease> {
+        let lease = self.leases.get_mut(lease_id)?;
+        let now = Instant::now();
+        if lease.expires_at <= now {
+            return None;
+        }
+        lease.expires_at = now + lease.ttl;
+        Some(lease.clone())
+    }
+
+    /// Ends the lease; its tab is closed on the next pass of the main loop.
+    pub fn release(&mut self, lease_id: &str) -> Option<Lease> {
+        let lease = self.leases.remove(lease_id)?;
+        self.released_tabs.push(lease.tab_id.clone());
+        Some(lease)
+    }
+
+    /// Whether the lease is still held, expired or not.
+    pub fn contains(&self, lease_id: &str) -> bool {
+        self.leases.contains_key(lease_id)
+    }
+
+    pub fn list(&self) -> Vec<Lease> {
+        self.leases.values().cloned().collect()
+    }
+
+    pub fn lease_for_tab(&self, tab_id: &str) -> Option<&Lease> {
+        self.leases.values().find(|lease| lease.tab_id == tab_id)
+    }
+
+    /// Moves the lease on `old_tab_id` to the tab that replaced it.
+    pub fn move_tab(&mut self, old_tab_id: &str, new_tab_id: &str) {
+        for lease in self.leases.values_mut() {
+            if lease.tab_id == old_tab_id {
+                lease.tab_id = new_tab_id.to_string();
+            }
+        }
+    }
+
+    /// Drops leases whose tab has gone away on its own.
+    pub fn forget_tab(&mut self, tab_id: &str) {
+        self.leases.retain(|_, lease| lease.tab_id != tab_id);
+    }
+
+    /// Removes expired leases and returns the tabs the manager should reclaim,
+    /// with the reason, including tabs of leases released since the last call.
+    pub fn take_reclaimable(&mut self) -> Vec<(String, &'static str)> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .leases
+            .values()
+            .filter(|lease| lease.expires_at <= now)
+            .map(|lease| lease.id.clone())
+            .collect();
+
+        let mut reclaimable = Vec::new();
+        for lease_id in expired {
+            if let Some(lease) = self.leases.remove(&lease_id) {
+                reclaimable.push((lease.tab_id, "lease expired"));
+            }
+        }
+        reclaimable.extend(
+            self.released_tabs
+                .drain(..)
+                .map(|tab_id| (tab_id, "lease released")),
+        );
+        reclaimable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requested_ttls_are_kept_between_one_second_and_the_maximum() {
+        let mut leases = LeaseTable::default();
+        let config = LeaseConfig::default();
+        let ttl = |leases: &mut LeaseTable, ttl_secs, config: &LeaseConfig| {
+            leases
+                .grant("tab", "client", ttl_secs, None, None, config)
+                .ttl
+                .as_secs()
+        };
+        assert_eq!(ttl(&mut leases, Some(0), &config), 1);
+        assert_eq!(ttl(&mut leases, Some(7200), &config), 3600);
+        assert_eq!(ttl(&mut leases, None, &config), 60);
+
+        let zero_max = LeaseConfig {
+            max_ttl_secs: 0,
+            ..LeaseConfig::default()
+        };
+        assert_eq!(ttl(&mut leases, Some(30), &zero_max), 1);
+    }
+
+    #[test]
+    fn expired_leases_cannot_be_renewed_before_they_are_reclaimed() {
+        let mut leases = LeaseTable::default();
+        let config = LeaseConfig::default();
+        let lease = leases.grant("tab", "client", Some(30), None, None, &config);
+        assert!(leases.renew(&lease.id).is_some());
+
+        leases.leases.get_mut(&lease.id).unwrap().expires_at = Instant::now();
+        assert!(leases.renew(&lease.id).is_none());
+        assert!(leases.contains(&lease.id));
+        assert_eq!(
+            leases.take_reclaimable(),
+            [(String::from("tab"), "lease expired")]
+        );
+        assert!(!leases.contains(&lease.id));
+        assert!(leases.renew("unknown").is_none());
+    }
+}
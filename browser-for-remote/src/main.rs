@@ -1,226 +1,837 @@
+mod auth;
+mod browser;
+mod config;
+mod console;
+mod control;
+mod devtools;
+mod discovery;
+mod display;
+mod downloads;
+mod events;
+mod focus;
+mod health;
+mod jobs;
+mod leases;
+mod proxy;
+mod rules;
+mod sandbox;
+mod state;
+mod stealth;
+mod tasks;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 use anyhow::Result;
-use headless_chrome::{Browser, Tab};
-use reqwest::blocking::get;
-use serde_json::Value;
+use auth::load_or_create_token;
+use browser::{prepare_tab, ManagedBrowser, ManagedTab};
+use config::{ConfigWatcher, IdentityProfile, LiveConfig};
+use console::ConsoleBuffers;
+use control::{spawn_control_server, ControlRequest, ControlState};
+use devtools::spawn_devtools_gateway;
+use discovery::{print_browser_report, select_browser};
+use display::{stop_xvfb, xvfb_exited};
+use events::{browser_event, tab_event};
+use jobs::JobHistory;
+use leases::{Lease, LeaseTable, NewLease};
+use proxy::ProxySettings;
+use state::{load_job_history, load_snapshot, restore_tabs, save_snapshot};
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
-use utils::{create_browser, get_profile_path, log_message};
+use tasks::{spawn_tasks, Session, Shared};
+use tokio::sync::{mpsc, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout};
+use utils::{create_browser, log_message, set_log_level};
+
+/// How often the supervisor checks that Chromium still answers.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How long Chromium has to answer before it counts as hung.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Struct to hold tab metadata
 struct TabMetadata {
     open_time: Instant,
     last_url_change_time: Instant,
     current_url: String,
-    tab: Arc<Tab>,
+    tab: Arc<dyn ManagedTab>,
+    /// Time spent as the focused tab, credited when focus moves on.
+    foreground_time: Duration,
+    last_focused: Option<Instant>,
+    /// Crashes and hangs the manager has recovered the tab from.
+    crash_count: u32,
+    last_recovery: Option<Instant>,
+    /// The browser context a lease asked for the tab to open in, such as a client's incognito
+    /// or per-proxy context. `None` for the default context.
+    browser_context_id: Option<String>,
+}
+
+/// Records tabs the manager has not seen yet and forgets the ones that closed.
+///
+/// New tabs are set up to answer the browser proxy's auth challenges and, when stealth is
+/// on, get the fingerprint patches. That happens after the metadata lock is released so other
+/// tasks aren't kept waiting on the new tabs.
+fn track_tabs<T: ManagedTab + 'static>(
+    tabs: &[Arc<T>],
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) {
+    let mut tab_metadata_lock = tab_metadata.lock().unwrap();
+
+    tab_metadata_lock.retain(|tab_id, metadata| {
+        let tab_still_open = tabs.iter().any(|tab| tab.target_id() == tab_id);
+        if !tab_still_open {
+            leases.lock().unwrap().forget_tab(tab_id);
+            log_message(
+                &format!(
+                    "Tab closed: {} ({}, open {}s, same URL for {}s)",
+                    tab_id,
+                    metadata.current_url,
+                    metadata.open_time.elapsed().as_secs(),
+                    metadata.last_url_change_time.elapsed().as_secs()
+                ),
+                "INFO",
+            );
+            tab_event("tab_closed", tab_id, &metadata.current_url, None);
+        }
+        tab_still_open
+    });
+
+    let mut new_tabs = Vec::new();
+    for tab in tabs {
+        match tab_metadata_lock.get_mut(tab.target_id()) {
+            Some(metadata) => {
+                let current_url = metadata.tab.url();
+                if current_url != metadata.current_url {
+                    tab_event("tab_navigated", tab.target_id(), &current_url, None);
+                    metadata.current_url = current_url;
+                    metadata.last_url_change_time = Instant::now();
+                }
+            }
+            None => {
+                tab_event("tab_opened", tab.target_id(), &tab.url(), None);
+                new_tabs.push(Arc::clone(tab));
+                tab_metadata_lock.insert(
+                    tab.target_id().to_string(),
+                    TabMetadata {
+                        open_time: Instant::now(),
+                        last_url_change_time: Instant::now(),
+                        current_url: tab.url(),
+                        tab: Arc::clone(tab) as Arc<dyn ManagedTab>,
+                        foreground_time: Duration::ZERO,
+                        last_focused: None,
+                        crash_count: 0,
+                        last_recovery: None,
+                        browser_context_id: None,
+                    },
+                );
+            }
+        }
+    }
+    drop(tab_metadata_lock);
+
+    for tab in new_tabs {
+        if let Err(e) = prepare_tab(tab.as_ref(), None, proxy, stealth) {
+            log_message(&e.to_string(), "ERROR");
+        }
+    }
+}
+
+/// Carries out a control request that needs the browser.
+fn handle_control_request<B: ManagedBrowser>(
+    browser: &B,
+    request: ControlRequest,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) {
+    match request {
+        ControlRequest::OpenTab {
+            lease,
+            identity,
+            reply,
+        } => {
+            let lease = open_tab(
+                browser,
+                &lease,
+                identity.as_ref(),
+                tab_metadata,
+                leases,
+                proxy,
+                stealth,
+            );
+            if let Err(Ok(lease)) = reply.send(lease.map_err(|e| e.to_string())) {
+                // The client stopped waiting, so nobody knows of the lease
+                log_message(
+                    &format!("Lease {} given up: its client stopped waiting", lease.id),
+                    "WARN",
+                );
+                leases.lock().unwrap().release(&lease.id);
+            }
+        }
+        ControlRequest::StealthCheck {
+            identity,
+            url,
+            reply,
+        } => {
+            let report =
+                stealth::run_check(browser, identity.as_ref(), url.as_deref(), proxy, stealth);
+            let _ = reply.send(report.map_err(|e| e.to_string()));
+        }
+    }
+}
+
+/// Opens a prepared tab for `lease` and tracks it straight away. The lease is granted as soon
+/// as the tab exists, before it is prepared, so the focus rotation and reaper never see it
+/// unleased.
+fn open_tab<B: ManagedBrowser>(
+    browser: &B,
+    lease: &NewLease,
+    identity: Option<&IdentityProfile>,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) -> Result<Lease, Box<dyn Error>> {
+    let (url, browser_context_id) = (&lease.url, lease.browser_context_id.clone());
+    let tab = browser.open_tab("about:blank", browser_context_id.clone())?;
+    let tab_id = tab.target_id().to_string();
+    let granted = leases.lock().unwrap().grant(
+        &tab_id,
+        &lease.client,
+        lease.ttl_secs,
+        lease.focus,
+        lease.identity.clone(),
+        &lease.config,
+    );
+    let opened = prepare_tab(tab.as_ref(), identity, proxy, stealth).and_then(|_| match url {
+        url if url == "about:blank" => Ok(()),
+        url => tab.navigate(url),
+    });
+    if let Err(e) = opened {
+        leases.lock().unwrap().forget_tab(&tab_id);
+        let _ = tab.close();
+        return Err(e);
+    }
+    tab_event(
+        "tab_opened",
+        &tab_id,
+        url,
+        Some(String::from("lease request")),
+    );
+    tab_metadata.lock().unwrap().insert(
+        tab_id.clone(),
+        TabMetadata {
+            open_time: Instant::now(),
+            last_url_change_time: Instant::now(),
+            current_url: url.to_string(),
+            tab: tab as Arc<dyn ManagedTab>,
+            foreground_time: Duration::ZERO,
+            last_focused: None,
+            crash_count: 0,
+            last_recovery: None,
+            browser_context_id,
+        },
+    );
+    Ok(granted)
+}
+
+fn close_tab(metadata: &TabMetadata, tab_id: &str, reason: &str) {
+    log_message(&format!("Closing tab ({}): {}", reason, tab_id), "INFO");
+    tab_event(
+        "tab_reaped",
+        tab_id,
+        &metadata.current_url,
+        Some(reason.to_string()),
+    );
+    if let Err(e) = metadata.tab.close() {
+        log_message(&format!("Failed to close tab: {}", e), "ERROR");
+    }
+}
+
+/// Closes tabs whose lease expired or was released.
+fn reclaim_leased_tabs(
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+) {
+    let reclaimable = leases.lock().unwrap().take_reclaimable();
+    let closing: Vec<_> = {
+        let mut tab_metadata_lock = tab_metadata.lock().unwrap();
+        reclaimable
+            .into_iter()
+            .filter_map(|(tab_id, reason)| {
+                let metadata = tab_metadata_lock.remove(&tab_id)?;
+                Some((tab_id, metadata, reason))
+            })
+            .collect()
+    };
+    for (tab_id, metadata, reason) in closing {
+        close_tab(&metadata, &tab_id, reason);
+    }
+}
+
+/// Closes unleased, reapable tabs that have stayed on the same URL for longer than `timeout`.
+fn reap_stale_tabs(
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    live: &RwLock<LiveConfig>,
+    timeout: Duration,
+) {
+    let stale: Vec<(String, TabMetadata)> = {
+        let leases = leases.lock().unwrap();
+        let rules = &live.read().unwrap().rules;
+        let mut tab_metadata_lock = tab_metadata.lock().unwrap();
+        let stale_ids: Vec<String> = tab_metadata_lock
+            .iter()
+            .filter(|(tab_id, metadata)| {
+                rules.policy_for(&metadata.current_url).reapable()
+                    && metadata.last_url_change_time.elapsed() > timeout
+                    && leases.lease_for_tab(tab_id).is_none()
+            })
+            .map(|(tab_id, _)| tab_id.clone())
+            .collect();
+        stale_ids
+            .into_iter()
+            .filter_map(|tab_id| {
+                let metadata = tab_metadata_lock.remove(&tab_id)?;
+                Some((tab_id, metadata))
+            })
+            .collect()
+    };
+    let reason = format!("on same URL for over {}s", timeout.as_secs());
+    for (tab_id, metadata) in stale {
+        close_tab(&metadata, &tab_id, &reason);
+    }
+}
+
+/// Snapshots the tabs and closes Chromium; the supervisor launches a new one unless it is exiting.
+fn stop_browser(
+    session: &watch::Sender<Option<Arc<Session>>>,
+    live: &RwLock<LiveConfig>,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    jobs: &Mutex<JobHistory>,
+    reason: &str,
+) {
+    log_message(reason, "INFO");
+    if session.borrow().is_none() {
+        return;
+    }
+    browser_event("browser_stopped", Some(reason));
+    let state_config = live.read().unwrap().config.state.clone();
+    if let Err(e) = save_snapshot(&state_config, tab_metadata, leases, jobs) {
+        log_message(&format!("Failed to save state: {}", e), "ERROR");
+    }
+    tab_metadata.lock().unwrap().clear();
+    // Chromium closes once the tasks drop their last handle to it
+    session.send_replace(None);
+}
+
+/// Stops the browser when it no longer answers within `wait`, because it exited or hung, so
+/// the supervisor relaunches it and restores its tabs.
+async fn check_browser(
+    session: &watch::Sender<Option<Arc<Session>>>,
+    live: &RwLock<LiveConfig>,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    jobs: &Mutex<JobHistory>,
+    wait: Duration,
+) {
+    let browser = match session.borrow().as_ref() {
+        Some(session) => Arc::clone(&session.browser),
+        None => return,
+    };
+    let version = spawn_blocking(move || browser.get_version().is_ok());
+    if let Ok(Ok(true)) = timeout(wait, version).await {
+        return;
+    }
+    stop_browser(
+        session,
+        live,
+        tab_metadata,
+        leases,
+        jobs,
+        "Browser exited or stopped responding, relaunching it",
+    );
+}
+
+/// Launches Chromium with the current settings and reopens the tabs from the last snapshot.
+fn launch_browser(
+    live: &RwLock<LiveConfig>,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+) -> Result<Session, Box<dyn Error>> {
+    // A fresh launch picks up the current settings, so nothing is left pending
+    let config = {
+        let mut live = live.write().unwrap();
+        live.restart_pending = false;
+        live.config.clone()
+    };
+    let session = create_browser(&config)?;
+    browser_event("browser_started", None);
+    match load_snapshot(&config.state) {
+        Ok(Some(snapshot)) => restore_tabs(
+            session.browser.as_ref(),
+            snapshot,
+            &config,
+            &live.read().unwrap().rules,
+            tab_metadata,
+            leases,
+            session.proxy.as_ref(),
+        ),
+        Ok(None) => {}
+        Err(e) => log_message(&format!("Failed to load state: {}", e), "ERROR"),
+    }
+    Ok(session)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut browser: Option<Arc<Browser>> = None;
-    let browser_profile_path = get_profile_path();
-    // Track tabs and their open times
-    let tab_metadata: Arc<Mutex<HashMap<String, TabMetadata>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    // let tmp_browser = &browser.as_ref().unwrap(); // Access the Arc<Browser>
+    let live = LiveConfig::load()?;
+    set_log_level(&live.config.log_level);
+    if std::env::args().any(|arg| arg == "--list-browsers") {
+        print_browser_report(&live.config.browser);
+        return Ok(());
+    }
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--stealth-check") {
+        let clean = stealth::print_check_report(&live.config, &args);
+        std::process::exit(if clean { 0 } else { 1 });
+    }
+    // Fail before starting anything if there is no browser to launch
+    match select_browser(&live.config.browser) {
+        Ok((path, version)) => log_message(
+            &format!("Found {} at {}", version.description, path.display()),
+            "INFO",
+        ),
+        Err(e) => {
+            log_message(&e.to_string(), "ERROR");
+            std::process::exit(1);
+        }
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let result = runtime.block_on(supervise(live));
+    // A task stuck on a hung tab must not keep the process alive
+    runtime.shutdown_timeout(Duration::from_secs(5));
+    result
+}
+
+/// Keeps Chromium running, restarting it when its launch settings change, it stops answering or
+/// Xvfb dies, while the tasks in `tasks` do the per-tab work. Returns once SIGTERM or SIGINT
+/// arrives.
+async fn supervise(live: LiveConfig) -> Result<(), Box<dyn Error>> {
+    let control_address = live.config.control.address.clone();
+    // Config and URL rules, swapped in place on SIGHUP, file change or POST /config/reload
+    let live = Arc::new(RwLock::new(live));
+    let mut config_watcher = ConfigWatcher::new()?;
+    // SIGTERM and SIGINT stop the supervisor so the browser and Xvfb are cleaned up
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&shutdown))?;
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&shutdown))?;
+    let (session_tx, session_rx) = watch::channel(None);
+    let shared = Shared {
+        live: Arc::clone(&live),
+        // Track tabs and their open times
+        tab_metadata: Arc::new(Mutex::new(HashMap::new())),
+        // Tabs handed out to clients through the control interface
+        leases: Arc::new(Mutex::new(LeaseTable::default())),
+        // Recent console output of each tab, when console capture is on
+        console: Arc::new(Mutex::new(ConsoleBuffers::default())),
+        // Where each scheduled job is, picking up from the last state file
+        jobs: Arc::new(Mutex::new(JobHistory::default())),
+        session: session_rx,
+    };
+    shared
+        .jobs
+        .lock()
+        .unwrap()
+        .restore(load_job_history(&live.read().unwrap().config.state));
+    // With auth on, the control interface and DevTools both want the token
+    let auth = live.read().unwrap().config.auth.clone();
+    let token = if auth.enabled {
+        let token = load_or_create_token(&auth)?;
+        spawn_devtools_gateway(&auth, &token, shared.session.clone()).await?;
+        Some(token)
+    } else {
+        log_message(
+            "Auth is disabled: the control interface and DevTools are open to anyone who can reach them",
+            "WARN",
+        );
+        None
+    };
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    spawn_control_server(
+        &control_address,
+        ControlState {
+            leases: Arc::clone(&shared.leases),
+            config: Arc::clone(&live),
+            console: Arc::clone(&shared.console),
+            jobs: Arc::clone(&shared.jobs),
+            requests: control_tx,
+            token,
+        },
+    )
+    .await?;
+    spawn_tasks(&shared, control_rx);
+    let tab_metadata = &shared.tab_metadata;
+    let leases = &shared.leases;
+    let jobs = &shared.jobs;
+    let mut last_snapshot = Instant::now();
+    let mut last_liveness_check = Instant::now();
     loop {
-        if browser.is_none() {
-            match create_browser() {
-                Ok(b) => {
-                    browser = Some(b);
-                    log_message(
-                        &format!(
-                            "Spawned new Chromium instance with profile: {}",
-                            browser_profile_path
-                        ),
-                        "INFO",
-                    );
+        if shutdown.load(Ordering::Relaxed) {
+            stop_browser(
+                &session_tx,
+                &live,
+                tab_metadata,
+                leases,
+                jobs,
+                "Shutting down",
+            );
+            stop_xvfb();
+            return Ok(());
+        }
+        if config_watcher.poll() {
+            if let Err(e) = live.write().unwrap().reload() {
+                log_message(&format!("Failed to reload config: {}", e), "ERROR");
+            }
+        }
+        let running = session_tx.borrow().is_some();
+        // Launch settings changed: restart Chromium once no client holds a tab
+        if running
+            && live.read().unwrap().restart_pending
+            && leases.lock().unwrap().list().is_empty()
+        {
+            stop_browser(
+                &session_tx,
+                &live,
+                tab_metadata,
+                leases,
+                jobs,
+                "Restarting browser to apply new launch settings",
+            );
+        }
+        // Chromium cannot outlive its X server; bring both back and restore the tabs
+        if running && xvfb_exited() {
+            stop_browser(
+                &session_tx,
+                &live,
+                tab_metadata,
+                leases,
+                jobs,
+                "Xvfb exited, restarting it and the browser",
+            );
+        }
+        // Chromium crashed, was killed or hung: bring it back and restore the tabs
+        if last_liveness_check.elapsed() >= LIVENESS_INTERVAL {
+            check_browser(
+                &session_tx,
+                &live,
+                tab_metadata,
+                leases,
+                jobs,
+                LIVENESS_TIMEOUT,
+            )
+            .await;
+            last_liveness_check = Instant::now();
+        }
+        if session_tx.borrow().is_none() {
+            let launch = {
+                let (live, tab_metadata, leases) = (
+                    Arc::clone(&live),
+                    Arc::clone(tab_metadata),
+                    Arc::clone(leases),
+                );
+                spawn_blocking(move || {
+                    launch_browser(&live, &tab_metadata, &leases).map_err(|e| e.to_string())
+                })
+            };
+            match launch.await? {
+                Ok(session) => {
+                    last_snapshot = Instant::now();
+                    session_tx.send_replace(Some(Arc::new(session)));
                 }
                 Err(e) => {
                     log_message(
                         &format!("Failed to create browser instance: {}", e),
                         "ERROR",
                     );
-                    // Optional: Add a delay or retry mechanism if desired
-                    std::thread::sleep(std::time::Duration::from_secs(5)); // Delay for retry
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
                 }
             }
         }
-        // if tmp_browser browser.is_some() {
-        //     tmp_browser = browser.clone().as_ref().unwrap(); // Access the Arc<Browser>
-        // }
-
-        // Fetch the JSON data from the localhost endpoint.
-        // let _response = get("http://127.0.0.1:9222/json")?;
-        // let data: Value = serde_json::from_str(&response.text()?)?;
-
-        // let external_tabs: Vec<&Value> = data
-        //     .as_array()
-        //     .unwrap()
-        //     .iter()
-        //     .filter(|tab| {
-        //         tab["type"] == "page"
-        //             && (tab["url"] != "chrome://newtab/" && tab["url"] != "about:blank")
-        //     })
-        //     .collect();
-        // // // Collect the URLs of the open tabs
-        // // let tab_urls: Vec<String> = external_tabs
-        // //     .iter()
-        // //     .filter_map(|tab| tab["url"].as_str().map(String::from))
-        // //     .collect();
-
-        // // // Wrap the list in an Arc<Mutex<_>>
-        // // let tab_urls_arc = Arc::new(Mutex::new(tab_urls));
-
-        // // // Let the user select a tab from the list
-        // // if let Some(selected_index) = select_tab(Arc::clone(&tab_urls_arc)) {
-        // //     let tab_urls_lock = tab_urls_arc.lock().unwrap();
-        // //     println!("User selected tab: {}", tab_urls_lock[selected_index]);
-        // // } else {
-        // //     println!("No tab selected.");
-        // // }
-
-        // // Lock the tab_metadata to safely update it
-        // let mut tab_metadata_lock = tab_metadata.lock().unwrap();
-
-        // // Iterate over tab metadata and close those open for more than 5 minutes or already closed
-        // tab_metadata_lock.retain(|tab_id, metadata| {
-        //     // Check if the tab is still in the current list of open tabs
-        //     let tab_still_open = external_tabs
-        //         .iter()
-        //         .any(|tab| tab["id"].as_str() == Some(tab_id));
-
-        //     if !tab_still_open {
-        //         log_message(&format!("Tab already closed: {}", tab_id), "INFO");
-        //         return false; // Remove from metadata since it's already closed
-        //     }
-
-        //     // Get the current URL of the tab
-        //     if let Some(tab_data) = external_tabs
-        //         .iter()
-        //         .find(|tab| tab["id"].as_str() == Some(tab_id))
-        //     {
-        //         if let Some(current_url) = tab_data["url"].as_str() {
-        //             // If the URL has changed, update the metadata
-        //             if current_url != metadata.current_url {
-        //                 metadata.current_url = current_url.to_string();
-        //                 metadata.last_url_change_time = Instant::now();
-        //             }
-        //         }
-        //     }
-
-        //     // Check if the tab has been on the same URL for more than 5 minutes
-        //     if metadata.last_url_change_time.elapsed() > Duration::from_secs(300) {
-        //         log_message(
-        //             &format!("Closing tab (on same URL for over 5 minutes): {}", tab_id),
-        //             "INFO",
-        //         );
-        //         if let Err(e) = metadata.tab.close_with_unload() {
-        //             log_message(&format!("Failed to close tab: {}", e), "ERROR");
-        //         }
-        //         return false; // Remove closed tab from metadata
-        //     }
-
-        //     true // Keep tab in metadata if it's still open and URL hasn't stayed unchanged for 5 minutes
-        // });
-
-        // for tab in &external_tabs {
-        //     if let Some(tab_id) = tab["id"].as_str() {
-        //         if !tab_metadata_lock.contains_key(tab_id) {
-        //             // If it's a new tab, add it to the metadata
-        //             let browser_tabs = browser
-        //                 .as_ref()
-        //                 .expect("Browser instance not available")
-        //                 .get_tabs()
-        //                 .lock()
-        //                 .unwrap();
-        //             if let Some(new_tab) = browser_tabs
-        //                 .iter()
-        //                 .find(|tab| tab.get_target_id() == tab_id)
-        //             {
-        //                 if let Some(current_url) = tab["url"].as_str() {
-        //                     tab_metadata_lock.insert(
-        //                         tab_id.to_string(),
-        //                         TabMetadata {
-        //                             open_time: Instant::now(),
-        //                             last_url_change_time: Instant::now(),
-        //                             current_url: current_url.to_string(),
-        //                             tab: Arc::clone(new_tab),
-        //                         },
-        //                     );
-        //                 }
-        //             }
-        //         }
-        //     }
-        // }
-
-        // for tab in &external_tabs {
-        //     if let Some(url) = tab["url"].as_str() {
-        //         log_message(&format!("URL: {}", url), "INFO");
-        //     }
-        // }
-        // Count the number of "page" tabs with "chrome://newtab/"
-        // let newtab_count = data
-        //     .as_array()
-        //     .unwrap()
-        //     .iter()
-        //     .filter(|tab| {
-        //         tab["type"] == "page"
-        //             && (tab["url"] == "chrome://newtab/" || tab["url"] == "about:blank")
-        //     })
-        //     .count();
-
-        std::thread::sleep(std::time::Duration::from_nanos(1));
-        let browser = browser.as_ref().unwrap(); // Access the Arc<Browser>
-        let tabs = browser.get_tabs().lock().unwrap();
-        // if tabs.len() > 2 {
-        // Create an iterator for the tabs, starting from the second tab (index > 1)
-        let mut tab_iter = tabs.iter().skip(1); // Skip the first tab (index 0)
-
-        // Use a `while let` loop to activate each remaining tab
-        while let Some(tab) = tab_iter.next() {
-            let url = tab.get_url();
-            if url == "chrome://newtab/" || url == "about:blank" {
-                continue;
-            }
 
-            if url == "https://duckduckgo.com/" {
-                continue;
-            }
-            // Check if the tab is still loading by executing JavaScript to get the document's readyState
-            // let loading_check = tab.evaluate("document.readyState", true);
-            // log_message(&format!("loading_check: {:?}", loading_check), "INFO");
-            // let is_loading = if let Ok(result) = loading_check {
-            //     // log_message(&format!("result.value: {:?}", result.value), "INFO");
-            //     if let Some(ready_state) = result.value {
-            //         ready_state.as_str() != Some("complete") // If it's not complete, we consider it loading
-            //     } else {
-            //         true // Assume loading if we can't retrieve the state
-            //     }
-            // } else {
-            //     true // Assume loading on evaluation error
-            // };
-            // if is_loading {
-            //     log_message(
-            //         &format!("Tab is still loading, skipping: {}", tab.get_url()),
-            //         "INFO",
-            //     );
-            //     continue;
-            // }
-
-            if let Err(e) = tab.bring_to_front() {
-                log_message(&format!("Failed to bring to front tab: {}", e), "ERROR");
-                if let Err(e) = tab.activate() {
-                    log_message(&format!("Failed to activate tab: {}", e), "ERROR");
-                }
+        let state_config = live.read().unwrap().config.state.clone();
+        if state_config.snapshot_interval_secs > 0
+            && last_snapshot.elapsed() >= Duration::from_secs(state_config.snapshot_interval_secs)
+        {
+            last_snapshot = Instant::now();
+            if let Err(e) = save_snapshot(&state_config, tab_metadata, leases, jobs) {
+                log_message(&format!("Failed to save state: {}", e), "ERROR");
             }
-            // thread::sleep(Duration::from_nanos(1));
         }
-        // } else {
-        //     std::thread::sleep(std::time::Duration::from_millis(100)); // Delay for retry
-        // }
+        sleep(Duration::from_millis(250)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{IdentityProfile, LeaseConfig};
+    use crate::testing::{connect, empty_state, live_config, new_lease};
+    use mock_devtools::{wait_until, MockDevTools};
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn track_tabs_follows_url_changes_and_closed_tabs() {
+        let mock = MockDevTools::start();
+        let first = mock.open_target("http://first.test/");
+        let second = mock.open_target("http://second.test/");
+        let (browser, tabs) = connect(&mock, 2);
+        let (tab_metadata, leases) = empty_state();
+        leases
+            .lock()
+            .unwrap()
+            .grant(&first, "test", None, None, None, &LeaseConfig::default());
+
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        assert_eq!(tab_metadata.lock().unwrap().len(), 2);
+
+        mock.set_url(&second, "http://second.test/next");
+        assert!(wait_until(Duration::from_secs(5), || {
+            tabs[1].get_url() == "http://second.test/next"
+        }));
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        assert_eq!(
+            tab_metadata.lock().unwrap()[&second].current_url,
+            "http://second.test/next"
+        );
+
+        mock.close_target(&first);
+        assert!(wait_until(Duration::from_secs(5), || browser.tabs().len() == 1));
+        track_tabs(&browser.tabs(), &tab_metadata, &leases, None, false);
+        assert!(!tab_metadata.lock().unwrap().contains_key(&first));
+        // The closed tab's lease goes with it
+        assert!(leases.lock().unwrap().list().is_empty());
+    }
+
+    #[test]
+    fn reaper_closes_stale_tabs_but_not_leased_or_ignored_ones() {
+        let mock = MockDevTools::start();
+        let stale = mock.open_target("http://stale.test/");
+        let leased = mock.open_target("http://leased.test/");
+        let ignored = mock.open_target("about:blank");
+        let (_browser, tabs) = connect(&mock, 3);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({}));
+        leases
+            .lock()
+            .unwrap()
+            .grant(&leased, "test", None, None, None, &LeaseConfig::default());
+
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        reap_stale_tabs(&tab_metadata, &leases, &live, Duration::ZERO);
+
+        assert_eq!(mock.target_ids(), vec![leased.clone(), ignored.clone()]);
+        let closed: Vec<_> = mock
+            .calls_to("Page.close")
+            .into_iter()
+            .filter_map(|call| call.target_id)
+            .collect();
+        assert_eq!(closed, vec![stale.clone()]);
+        let tracked = tab_metadata.lock().unwrap();
+        assert!(!tracked.contains_key(&stale));
+        assert!(tracked.contains_key(&leased) && tracked.contains_key(&ignored));
+    }
+
+    #[test]
+    fn reaper_leaves_tabs_that_changed_url_recently() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://busy.test/");
+        let (_browser, tabs) = connect(&mock, 1);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({}));
+
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        reap_stale_tabs(&tab_metadata, &leases, &live, Duration::from_secs(60));
+
+        assert_eq!(mock.target_ids().len(), 1);
+        assert!(mock.calls_to("Page.close").is_empty());
+    }
+
+    #[test]
+    fn open_tab_request_applies_the_identity_before_navigating() {
+        let mock = MockDevTools::start();
+        let (browser, _) = connect(&mock, 0);
+        let (tab_metadata, leases) = empty_state();
+        let (reply, replies) = tokio::sync::oneshot::channel();
+
+        handle_control_request(
+            &browser,
+            ControlRequest::OpenTab {
+                lease: new_lease("http://leased.test/"),
+                identity: Some(IdentityProfile {
+                    user_agent: String::from("TestAgent/1.0"),
+                    accept_language: Some(String::from("fr-FR")),
+                    platform: None,
+                    webgl_vendor: None,
+                    webgl_renderer: None,
+                }),
+                reply,
+            },
+            &tab_metadata,
+            &leases,
+            None,
+            false,
+        );
+
+        let lease = replies.blocking_recv().unwrap().unwrap();
+        let tab_id = lease.tab_id;
+        assert_eq!(lease.client, "test");
+        assert_eq!(
+            mock.target_url(&tab_id).as_deref(),
+            Some("http://leased.test/")
+        );
+        let methods: Vec<String> = mock.calls().into_iter().map(|call| call.method).collect();
+        let position = |method: &str| methods.iter().position(|m| m == method).unwrap();
+        assert!(position("Network.setUserAgentOverride") < position("Page.navigate"));
+        let overrides = mock.calls_to("Network.setUserAgentOverride");
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides[0].target_id.as_deref(), Some(tab_id.as_str()));
+        assert_eq!(overrides[0].params["userAgent"], "TestAgent/1.0");
+        assert_eq!(overrides[0].params["acceptLanguage"], "fr-FR");
+        // Already tracked, so the main loop won't set the tab up a second time
+        assert!(tab_metadata.lock().unwrap().contains_key(&tab_id));
+        assert!(leases.lock().unwrap().lease_for_tab(&tab_id).is_some());
+    }
+
+    #[test]
+    fn a_tab_nobody_waits_for_any_more_is_given_up() {
+        let mock = MockDevTools::start();
+        let (browser, _) = connect(&mock, 0);
+        let (tab_metadata, leases) = empty_state();
+        let (reply, replies) = tokio::sync::oneshot::channel();
+        drop(replies);
+
+        handle_control_request(
+            &browser,
+            ControlRequest::OpenTab {
+                lease: new_lease("http://late.test/"),
+                identity: None,
+                reply,
+            },
+            &tab_metadata,
+            &leases,
+            None,
+            false,
+        );
+
+        let tab_id = mock.target_ids().remove(0);
+        assert!(leases.lock().unwrap().list().is_empty());
+        reclaim_leased_tabs(&tab_metadata, &leases);
+        assert!(wait_until(Duration::from_secs(5), || mock
+            .target_ids()
+            .is_empty()));
+        assert!(!tab_metadata.lock().unwrap().contains_key(&tab_id));
+    }
+
+    #[test]
+    fn a_browser_that_stops_answering_is_relaunched_with_its_tabs() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://kept.test/");
+        let (browser, tabs) = connect(&mock, 1);
+        let dir = tempfile::tempdir().unwrap();
+        let live = live_config(json!({ "state": { "path": dir.path().join("state.json") } }));
+        let (tab_metadata, leases) = empty_state();
+        let jobs = Mutex::new(JobHistory::default());
+        track_tabs(&tabs, &tab_metadata, &leases, None, false);
+        let (session, _) = watch::channel(Some(Arc::new(Session {
+            browser: Arc::new(browser),
+            proxy: None,
+            devtools_port: 0,
+            job_contexts: Mutex::default(),
+        })));
+        let runtime = Runtime::new().unwrap();
+        let check = || {
+            runtime.block_on(check_browser(
+                &session,
+                &live,
+                &tab_metadata,
+                &leases,
+                &jobs,
+                Duration::from_secs(2),
+            ))
+        };
+
+        check();
+        assert!(session.borrow().is_some());
+
+        let mut events = crate::events::subscribe();
+        drop(mock);
+        check();
+        let stopped = std::iter::from_fn(|| events.try_recv().ok()).any(|event| {
+            event.kind == "browser_stopped"
+                && event.detail.as_deref()
+                    == Some("Browser exited or stopped responding, relaunching it")
+        });
+        assert!(stopped);
+        // With no session the supervisor launches a new browser, which restores the snapshot
+        assert!(session.borrow().is_none());
+        assert!(tab_metadata.lock().unwrap().is_empty());
+        let relaunched = MockDevTools::start();
+        let (browser, _) = connect(&relaunched, 0);
+        let live = live.read().unwrap();
+        let snapshot = load_snapshot(&live.config.state).unwrap().unwrap();
+        restore_tabs(
+            &browser,
+            snapshot,
+            &live.config,
+            &live.rules,
+            &tab_metadata,
+            &leases,
+            None,
+        );
+        let restored = relaunched.target_ids();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(
+            relaunched.target_url(&restored[0]).as_deref(),
+            Some("http://kept.test/")
+        );
+    }
+
+    #[test]
+    fn a_hung_browser_is_stopped() {
+        let mock = MockDevTools::start();
+        let (browser, _) = connect(&mock, 0);
+        let dir = tempfile::tempdir().unwrap();
+        let live = live_config(json!({ "state": { "path": dir.path().join("state.json") } }));
+        let (tab_metadata, leases) = empty_state();
+        let jobs = Mutex::new(JobHistory::default());
+        let (session, _) = watch::channel(Some(Arc::new(Session {
+            browser: Arc::new(browser),
+            proxy: None,
+            devtools_port: 0,
+            job_contexts: Mutex::default(),
+        })));
+        mock.delay("Browser.getVersion", Duration::from_secs(2));
+
+        Runtime::new().unwrap().block_on(check_browser(
+            &session,
+            &live,
+            &tab_metadata,
+            &leases,
+            &jobs,
+            Duration::from_millis(500),
+        ));
+        assert!(session.borrow().is_none());
     }
 }
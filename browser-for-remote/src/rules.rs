@@ -0,0 +1,287 @@
+// rules.rs
+
+use crate::health::Recovery;
+use glob::Pattern;
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use std::error::Error;
+
+/// What the manager may do with a tab whose URL matches a rule.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TabPolicy {
+    /// Brought to the front by the rotation and closed by the reaper when stale.
+    Rotate,
+    /// Brought to the front but never reaped.
+    Pin,
+    /// Left in the background but closed by the reaper when stale.
+    Reap,
+    /// Never touched.
+    Ignore,
+}
+
+impl TabPolicy {
+    pub fn rotates(self) -> bool {
+        matches!(self, TabPolicy::Rotate | TabPolicy::Pin)
+    }
+
+    pub fn reapable(self) -> bool {
+        matches!(self, TabPolicy::Rotate | TabPolicy::Reap)
+    }
+}
+
+/// How a rule matches a tab URL. Written in config as e.g. `{"prefix": "https://chatgpt.com/"}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlPattern {
+    Exact(String),
+    Prefix(String),
+    /// Shell-style pattern where `*` also matches `/`.
+    Glob(String),
+    Regex(String),
+    /// The URL's host, or any subdomain of it.
+    Host(String),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UrlRuleConfig {
+    #[serde(flatten)]
+    pub pattern: UrlPattern,
+    pub action: TabPolicy,
+    /// Relative share of focus for matching tabs.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// What happens to a matching tab whose renderer crashes or hangs; `health.on_crash`
+    /// when unset.
+    #[serde(default)]
+    pub on_crash: Option<Recovery>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Glob(Pattern),
+    Regex(Regex),
+    Host(String),
+}
+
+impl Matcher {
+    fn matches(&self, url: &str) -> bool {
+        match self {
+            Matcher::Exact(exact) => url == exact,
+            Matcher::Prefix(prefix) => url.starts_with(prefix.as_str()),
+            Matcher::Glob(pattern) => pattern.matches(url),
+            Matcher::Regex(regex) => regex.is_match(url),
+            Matcher::Host(host) => Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from))
+                .map(|url_host| url_host == *host || url_host.ends_with(&format!(".{}", host)))
+                .unwrap_or(false),
+        }
+    }
+}
+
+struct UrlRule {
+    matcher: Matcher,
+    policy: TabPolicy,
+    weight: u32,
+    on_crash: Option<Recovery>,
+}
+
+/// Ordered URL rules; the first match decides a tab's policy.
+pub struct UrlRules {
+    rules: Vec<UrlRule>,
+}
+
+impl UrlRules {
+    /// Compiles the configured rules, or the built-in ones when none are configured.
+    pub fn compile(rules: Option<&[UrlRuleConfig]>) -> Result<Self, Box<dyn Error>> {
+        let rules = match rules {
+            Some(rules) => rules.to_vec(),
+            None => default_rules(),
+        };
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let matcher = match rule.pattern {
+                UrlPattern::Exact(exact) => Matcher::Exact(exact),
+                UrlPattern::Prefix(prefix) => Matcher::Prefix(prefix),
+                UrlPattern::Glob(glob) => Matcher::Glob(
+                    Pattern::new(&glob).map_err(|e| format!("Invalid glob {}: {}", glob, e))?,
+                ),
+                UrlPattern::Regex(regex) => Matcher::Regex(
+                    Regex::new(&regex).map_err(|e| format!("Invalid regex {}: {}", regex, e))?,
+                ),
+                UrlPattern::Host(host) => Matcher::Host(host.to_ascii_lowercase()),
+            };
+            compiled.push(UrlRule {
+                matcher,
+                policy: rule.action,
+                weight: rule.weight.max(1),
+                on_crash: rule.on_crash,
+            });
+        }
+        Ok(UrlRules { rules: compiled })
+    }
+
+    fn rule_for(&self, url: &str) -> Option<&UrlRule> {
+        self.rules.iter().find(|rule| rule.matcher.matches(url))
+    }
+
+    /// Policy for `url`; tabs no rule matches are rotated and reapable.
+    pub fn policy_for(&self, url: &str) -> TabPolicy {
+        self.rule_for(url)
+            .map(|rule| rule.policy)
+            .unwrap_or(TabPolicy::Rotate)
+    }
+
+    /// Focus weight for `url`; tabs no rule matches weigh 1.
+    pub fn weight_for(&self, url: &str) -> u32 {
+        self.rule_for(url).map(|rule| rule.weight).unwrap_or(1)
+    }
+
+    /// Crash recovery for `url` from the first matching rule, if it sets one.
+    pub fn recovery_for(&self, url: &str) -> Option<Recovery> {
+        self.rule_for(url).and_then(|rule| rule.on_crash)
+    }
+
+    pub fn count(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// The exclusions the manager has always had: blank tabs are left alone and the
+/// DuckDuckGo home page is never brought to the front.
+fn default_rules() -> Vec<UrlRuleConfig> {
+    vec![
+        UrlRuleConfig {
+            pattern: UrlPattern::Exact(String::from("chrome://newtab/")),
+            action: TabPolicy::Ignore,
+            weight: 1,
+            on_crash: None,
+        },
+        UrlRuleConfig {
+            pattern: UrlPattern::Exact(String::from("about:blank")),
+            action: TabPolicy::Ignore,
+            weight: 1,
+            on_crash: None,
+        },
+        UrlRuleConfig {
+            pattern: UrlPattern::Exact(String::from("https://duckduckgo.com/")),
+            action: TabPolicy::Reap,
+            weight: 1,
+            on_crash: None,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn compile(rules: Value) -> Result<UrlRules, Box<dyn Error>> {
+        let rules: Vec<UrlRuleConfig> = serde_json::from_value(rules).unwrap();
+        UrlRules::compile(Some(&rules))
+    }
+
+    #[test]
+    fn each_pattern_kind_matches_its_urls() {
+        let rules = compile(json!([
+            { "exact": "https://exact.test/", "action": "pin" },
+            { "prefix": "https://prefix.test/app/", "action": "reap" },
+            { "glob": "https://*.glob.test/*/inbox", "action": "ignore" },
+            { "regex": "^https://regex\\.test/item/[0-9]+$", "action": "pin" },
+            { "host": "Host.test", "action": "reap" },
+        ]))
+        .unwrap();
+
+        assert_eq!(rules.policy_for("https://exact.test/"), TabPolicy::Pin);
+        assert_eq!(
+            rules.policy_for("https://exact.test/page"),
+            TabPolicy::Rotate
+        );
+        assert_eq!(
+            rules.policy_for("https://prefix.test/app/x"),
+            TabPolicy::Reap
+        );
+        assert_eq!(
+            rules.policy_for("https://prefix.test/other"),
+            TabPolicy::Rotate
+        );
+        assert_eq!(
+            rules.policy_for("https://mail.glob.test/u/0/inbox"),
+            TabPolicy::Ignore
+        );
+        assert_eq!(
+            rules.policy_for("https://mail.glob.test/u/0/sent"),
+            TabPolicy::Rotate
+        );
+        assert_eq!(
+            rules.policy_for("https://regex.test/item/42"),
+            TabPolicy::Pin
+        );
+        assert_eq!(
+            rules.policy_for("https://regex.test/item/new"),
+            TabPolicy::Rotate
+        );
+        assert_eq!(rules.policy_for("https://host.test/"), TabPolicy::Reap);
+        assert_eq!(rules.policy_for("http://www.host.test/a"), TabPolicy::Reap);
+        assert_eq!(rules.policy_for("https://ghost.test/"), TabPolicy::Rotate);
+        assert_eq!(rules.policy_for("not a url"), TabPolicy::Rotate);
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let rules = compile(json!([
+            { "prefix": "https://app.test/admin", "action": "ignore", "on_crash": "close" },
+            { "host": "app.test", "action": "pin", "weight": 3 },
+            { "prefix": "https://app.test/", "action": "reap", "weight": 5 },
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            rules.policy_for("https://app.test/admin/users"),
+            TabPolicy::Ignore
+        );
+        assert_eq!(rules.weight_for("https://app.test/admin/users"), 1);
+        assert_eq!(
+            rules.recovery_for("https://app.test/admin/users"),
+            Some(Recovery::Close)
+        );
+        assert_eq!(rules.policy_for("https://app.test/home"), TabPolicy::Pin);
+        assert_eq!(rules.weight_for("https://app.test/home"), 3);
+        assert_eq!(rules.recovery_for("https://app.test/home"), None);
+        assert_eq!(rules.weight_for("https://other.test/"), 1);
+    }
+
+    #[test]
+    fn default_rules_leave_blank_tabs_alone_and_never_rotate_duckduckgo() {
+        let rules = UrlRules::compile(None).unwrap();
+        assert_eq!(rules.count(), 3);
+        assert_eq!(rules.policy_for("chrome://newtab/"), TabPolicy::Ignore);
+        assert_eq!(rules.policy_for("about:blank"), TabPolicy::Ignore);
+        assert_eq!(rules.policy_for("https://duckduckgo.com/"), TabPolicy::Reap);
+        assert_eq!(
+            rules.policy_for("https://duckduckgo.com/?q=rust"),
+            TabPolicy::Rotate
+        );
+        assert_eq!(rules.policy_for("https://example.test/"), TabPolicy::Rotate);
+    }
+
+    #[test]
+    fn invalid_globs_and_regexes_are_rejected() {
+        let glob = compile(json!([{ "glob": "https://[.test/", "action": "pin" }]));
+        assert!(glob.err().unwrap().to_string().starts_with("Invalid glob"));
+        let regex = compile(json!([{ "regex": "https://(.test/", "action": "pin" }]));
+        assert!(regex
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Invalid regex"));
+    }
+}
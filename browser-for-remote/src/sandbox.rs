@@ -0,0 +1,179 @@
+// sandbox.rs
+//
+// Whether Chromium can sandbox its processes on this host: through unprivileged user
+// namespaces, or through a root-owned setuid `chrome-sandbox` helper next to the browser.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Kernel settings that turn off unprivileged user namespaces, and the value that does it.
+const USERNS_SWITCHES: [(&str, &str); 3] = [
+    ("/proc/sys/kernel/unprivileged_userns_clone", "0"),
+    ("/proc/sys/user/max_user_namespaces", "0"),
+    // Ubuntu 23.10 and later, unless an AppArmor profile grants the browser an exception
+    (
+        "/proc/sys/kernel/apparmor_restrict_unprivileged_userns",
+        "1",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SandboxSupport {
+    UserNamespaces,
+    Setuid(PathBuf),
+    /// Why neither works.
+    Unavailable(Vec<String>),
+}
+
+impl fmt::Display for SandboxSupport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SandboxSupport::UserNamespaces => write!(f, "user namespace sandbox"),
+            SandboxSupport::Setuid(path) => write!(f, "setuid sandbox {}", path.display()),
+            SandboxSupport::Unavailable(reasons) => {
+                write!(f, "no usable sandbox ({})", reasons.join("; "))
+            }
+        }
+    }
+}
+
+/// Checks what sandbox the browser at `browser_path` could use.
+pub fn detect_sandbox(browser_path: &Path) -> SandboxSupport {
+    // The owner of /proc/self is the process's effective user
+    let root = fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0);
+    let userns = userns_check(|path| fs::read_to_string(path).ok(), probe_userns());
+    assess(root, userns, find_setuid_sandbox(browser_path))
+}
+
+/// Picks the sandbox Chromium would use: user namespaces first, as Chromium does. Chromium
+/// refuses to start sandboxed as root.
+fn assess(
+    root: bool,
+    userns: Result<(), String>,
+    setuid: Result<PathBuf, String>,
+) -> SandboxSupport {
+    if root {
+        return SandboxSupport::Unavailable(vec![String::from(
+            "Chromium does not run sandboxed as root",
+        )]);
+    }
+    match (userns, setuid) {
+        (Ok(()), _) => SandboxSupport::UserNamespaces,
+        (Err(_), Ok(path)) => SandboxSupport::Setuid(path),
+        (Err(userns), Err(setuid)) => SandboxSupport::Unavailable(vec![userns, setuid]),
+    }
+}
+
+/// Whether unprivileged user namespaces are allowed, going by the kernel switches `read`
+/// returns and, when `unshare` could be run, whether it managed to create one.
+fn userns_check(read: impl Fn(&str) -> Option<String>, probe: Option<bool>) -> Result<(), String> {
+    for (path, off) in USERNS_SWITCHES {
+        if read(path).is_some_and(|value| value.trim() == off) {
+            return Err(format!("user namespaces are off ({} is {})", path, off));
+        }
+    }
+    match probe {
+        // Containers often block them with seccomp, which no setting shows
+        Some(false) => Err(String::from("creating a user namespace failed")),
+        _ => Ok(()),
+    }
+}
+
+/// Tries to create a user namespace with `unshare`, if it is installed.
+fn probe_userns() -> Option<bool> {
+    Command::new("unshare")
+        .args(["--user", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok()
+        .map(|status| status.success())
+}
+
+/// Looks for the setuid helper at `CHROME_DEVEL_SANDBOX`, then next to the browser binary.
+fn find_setuid_sandbox(browser_path: &Path) -> Result<PathBuf, String> {
+    let browser_dir = fs::canonicalize(browser_path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf));
+    let candidates: Vec<PathBuf> = env::var_os("CHROME_DEVEL_SANDBOX")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(
+            browser_dir
+                .iter()
+                .flat_map(|dir| [dir.join("chrome-sandbox"), dir.join("chrome_sandbox")]),
+        )
+        .collect();
+    for candidate in &candidates {
+        if let Ok(metadata) = fs::metadata(candidate) {
+            if setuid_allows(metadata.uid(), metadata.permissions().mode()) {
+                return Ok(candidate.clone());
+            }
+            return Err(format!(
+                "{} is not owned by root with mode 4755",
+                candidate.display()
+            ));
+        }
+    }
+    Err(String::from("no chrome-sandbox helper next to the browser"))
+}
+
+/// Whether a helper with this owner and mode can set up the sandbox.
+fn setuid_allows(uid: u32, mode: u32) -> bool {
+    uid == 0 && mode & 0o4000 != 0 && mode & 0o111 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_namespaces_win_and_root_never_sandboxes() {
+        let setuid = || Ok(PathBuf::from("/opt/chrome/chrome-sandbox"));
+        assert_eq!(
+            assess(false, Ok(()), setuid()),
+            SandboxSupport::UserNamespaces
+        );
+        assert_eq!(
+            assess(false, Err(String::from("off")), setuid()),
+            SandboxSupport::Setuid(PathBuf::from("/opt/chrome/chrome-sandbox"))
+        );
+        assert!(matches!(
+            assess(false, Err(String::from("off")), Err(String::from("none"))),
+            SandboxSupport::Unavailable(reasons) if reasons.len() == 2
+        ));
+        assert!(matches!(
+            assess(true, Ok(()), setuid()),
+            SandboxSupport::Unavailable(_)
+        ));
+    }
+
+    #[test]
+    fn kernel_switches_and_the_probe_can_rule_out_user_namespaces() {
+        let none = |_: &str| None;
+        assert_eq!(userns_check(none, None), Ok(()));
+        assert_eq!(userns_check(none, Some(true)), Ok(()));
+        assert!(userns_check(none, Some(false)).is_err());
+
+        let debian_off = |path: &str| {
+            (path == "/proc/sys/kernel/unprivileged_userns_clone").then(|| String::from("0\n"))
+        };
+        assert!(userns_check(debian_off, Some(true))
+            .unwrap_err()
+            .contains("unprivileged_userns_clone"));
+        let allowed = |_: &str| Some(String::from("15000\n"));
+        assert_eq!(userns_check(allowed, None), Ok(()));
+    }
+
+    #[test]
+    fn setuid_helper_must_be_root_owned_and_setuid() {
+        assert!(setuid_allows(0, 0o104755));
+        assert!(!setuid_allows(1000, 0o104755));
+        assert!(!setuid_allows(0, 0o100755));
+    }
+}
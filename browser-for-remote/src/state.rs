@@ -0,0 +1,297 @@
+// state.rs
+
+use crate::browser::{prepare_tab, ManagedBrowser, ManagedTab};
+use crate::config::{Config, RestorePolicy, StateConfig};
+use crate::events::tab_event;
+use crate::jobs::JobHistory;
+use crate::leases::{Lease, LeaseFocus, LeaseTable};
+use crate::proxy::ProxySettings;
+use crate::rules::{TabPolicy, UrlRules};
+use crate::utils::log_message;
+use crate::TabMetadata;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The managed tabs as last seen, written to the state file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateSnapshot {
+    pub saved_at: DateTime<Utc>,
+    pub tabs: Vec<TabSnapshot>,
+    /// Last scheduled time of each job.
+    #[serde(default)]
+    pub jobs: HashMap<String, DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TabSnapshot {
+    pub url: String,
+    pub opened_at: DateTime<Utc>,
+    pub identity: Option<String>,
+    pub lease: Option<LeaseSnapshot>,
+    /// Set for tabs opened in a browser context of their own. Such a context, and the proxy
+    /// its client created it with, is gone after a restart, so these tabs are not restored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browser_context_id: Option<String>,
+}
+
+/// Enough of a lease to hand the restored tab back to the same client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaseSnapshot {
+    pub lease_id: String,
+    pub client: String,
+    pub focus: LeaseFocus,
+    pub ttl_secs: u64,
+}
+
+/// Returns the state file path: `state.path` if set, otherwise `~/.browser-for-remote-state.json`.
+pub fn get_state_path(config: &StateConfig) -> PathBuf {
+    if let Some(path) = &config.path {
+        return PathBuf::from(path);
+    }
+    match env::var("HOME") {
+        Ok(home_dir) => PathBuf::from(format!("{}/.browser-for-remote-state.json", home_dir)),
+        Err(_) => PathBuf::from("/tmp/browser-for-remote-state.json"),
+    }
+}
+
+/// Writes the managed tabs and job schedule positions to the state file, via a temp file so
+/// a crash never leaves half a snapshot.
+pub fn save_snapshot(
+    config: &StateConfig,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    jobs: &Mutex<JobHistory>,
+) -> Result<(), Box<dyn Error>> {
+    let leases = leases.lock().unwrap();
+    let tabs = tab_metadata
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(tab_id, metadata)| {
+            let lease = leases.lease_for_tab(tab_id);
+            TabSnapshot {
+                url: metadata.current_url.clone(),
+                opened_at: Utc::now()
+                    - chrono::Duration::from_std(metadata.open_time.elapsed()).unwrap_or_default(),
+                identity: lease.and_then(|lease| lease.identity.clone()),
+                lease: lease.map(|lease| LeaseSnapshot {
+                    lease_id: lease.id.clone(),
+                    client: lease.client.clone(),
+                    focus: lease.focus,
+                    ttl_secs: lease.ttl.as_secs(),
+                }),
+                browser_context_id: metadata.browser_context_id.clone(),
+            }
+        })
+        .collect();
+    let snapshot = StateSnapshot {
+        saved_at: Utc::now(),
+        tabs,
+        jobs: jobs.lock().unwrap().last_fires(),
+    };
+
+    let path = get_state_path(config);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(&snapshot)?)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Reads the state file. A missing file, or one older than `max_age_secs`, gives `None`.
+pub fn load_snapshot(config: &StateConfig) -> Result<Option<StateSnapshot>, Box<dyn Error>> {
+    let path = get_state_path(config);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let snapshot: StateSnapshot = serde_json::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| format!("Invalid state file {}: {}", path.display(), e))?;
+    if let Some(max_age) = config.max_age_secs {
+        let age = (Utc::now() - snapshot.saved_at).num_seconds();
+        if age > max_age as i64 {
+            log_message(
+                &format!("State file is {}s old, not restoring tabs", age),
+                "INFO",
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(snapshot))
+}
+
+/// The job schedule positions from the state file, however old it is, so runs missed while
+/// the manager was down are noticed. Empty if there is no readable file.
+pub fn load_job_history(config: &StateConfig) -> HashMap<String, DateTime<Utc>> {
+    fs::read_to_string(get_state_path(config))
+        .ok()
+        .and_then(|contents| serde_json::from_str::<StateSnapshot>(&contents).ok())
+        .map(|snapshot| snapshot.jobs)
+        .unwrap_or_default()
+}
+
+/// Whether `tab` is worth reopening under the configured policy.
+fn should_restore(tab: &TabSnapshot, policy: RestorePolicy, url_policy: TabPolicy) -> bool {
+    if url_policy == TabPolicy::Ignore {
+        return false;
+    }
+    match policy {
+        RestorePolicy::None => false,
+        RestorePolicy::Leased => tab.lease.is_some(),
+        RestorePolicy::Pinned => tab.lease.is_some() || url_policy == TabPolicy::Pin,
+        RestorePolicy::All => true,
+    }
+}
+
+/// Reopens the tabs in `snapshot` that the restore policy keeps and rebuilds their metadata.
+///
+/// Leases are recreated under their old id with a full TTL so the owner can find the new tab
+/// through `GET /leases`; one that isn't renewed expires as usual.
+pub fn restore_tabs<B: ManagedBrowser>(
+    browser: &B,
+    snapshot: StateSnapshot,
+    config: &Config,
+    rules: &UrlRules,
+    tab_metadata: &Mutex<HashMap<String, TabMetadata>>,
+    leases: &Mutex<LeaseTable>,
+    proxy: Option<&ProxySettings>,
+) {
+    let mut restored = 0;
+    for saved in snapshot.tabs {
+        if !should_restore(&saved, config.state.restore, rules.policy_for(&saved.url)) {
+            continue;
+        }
+        if saved.browser_context_id.is_some() {
+            // Reopening it in the default context would hand its client the persistent
+            // profile and the manager's own proxy instead
+            log_message(
+                &format!(
+                    "Not restoring tab {}: its browser context did not survive the restart",
+                    saved.url
+                ),
+                "INFO",
+            );
+            continue;
+        }
+        let tab = match browser.open_tab("about:blank", None) {
+            Ok(tab) => tab,
+            Err(e) => {
+                log_message(
+                    &format!("Failed to restore tab {}: {}", saved.url, e),
+                    "ERROR",
+                );
+                continue;
+            }
+        };
+        let identity = saved
+            .identity
+            .as_ref()
+            .and_then(|name| config.identities.get(name));
+        if let Err(e) = prepare_tab(tab.as_ref(), identity, proxy, config.stealth.enabled) {
+            log_message(&e.to_string(), "ERROR");
+        }
+        if let Err(e) = tab.navigate(&saved.url) {
+            log_message(
+                &format!("Failed to restore tab {}: {}", saved.url, e),
+                "ERROR",
+            );
+            let _ = tab.close();
+            continue;
+        }
+
+        let tab_id = tab.target_id().to_string();
+        if let Some(lease) = saved.lease {
+            let ttl = Duration::from_secs(lease.ttl_secs.max(1));
+            leases.lock().unwrap().restore(Lease {
+                id: lease.lease_id,
+                tab_id: tab_id.clone(),
+                client: lease.client,
+                focus: lease.focus,
+                identity: saved.identity,
+                ttl,
+                expires_at: Instant::now() + ttl,
+            });
+        }
+        let age = (Utc::now() - saved.opened_at).to_std().unwrap_or_default();
+        tab_event(
+            "tab_opened",
+            &tab_id,
+            &saved.url,
+            Some(String::from("restored")),
+        );
+        tab_metadata.lock().unwrap().insert(
+            tab_id,
+            TabMetadata {
+                open_time: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                last_url_change_time: Instant::now(),
+                current_url: saved.url,
+                tab: tab as Arc<dyn ManagedTab>,
+                foreground_time: Duration::ZERO,
+                last_focused: None,
+                crash_count: 0,
+                last_recovery: None,
+                browser_context_id: None,
+            },
+        );
+        restored += 1;
+    }
+    if restored > 0 {
+        log_message(
+            &format!("Restored {} tabs from state file", restored),
+            "INFO",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, empty_state, live_config};
+    use mock_devtools::MockDevTools;
+    use serde_json::json;
+
+    #[test]
+    fn tabs_from_their_own_browser_context_are_not_restored() {
+        let mock = MockDevTools::start();
+        let (browser, _) = connect(&mock, 0);
+        let (tab_metadata, leases) = empty_state();
+        let live = live_config(json!({ "state": { "restore": "all" } }));
+        let lease = |lease_id: &str| json!({ "lease_id": lease_id, "client": "job", "focus": "rotate", "ttl_secs": 60 });
+        let snapshot: StateSnapshot = serde_json::from_value(json!({
+            "saved_at": Utc::now(),
+            "tabs": [
+                { "url": "http://profile.test/", "opened_at": Utc::now(), "identity": null,
+                  "lease": lease("in-profile") },
+                { "url": "http://incognito.test/", "opened_at": Utc::now(), "identity": null,
+                  "lease": lease("incognito"), "browser_context_id": "CTX1" },
+            ],
+        }))
+        .unwrap();
+
+        let live = live.read().unwrap();
+        restore_tabs(
+            &browser,
+            snapshot,
+            &live.config,
+            &live.rules,
+            &tab_metadata,
+            &leases,
+            None,
+        );
+
+        let targets = mock.target_ids();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(
+            mock.target_url(&targets[0]).as_deref(),
+            Some("http://profile.test/")
+        );
+        let leases = leases.lock().unwrap();
+        assert!(leases.lease_for_tab(&targets[0]).is_some());
+        assert_eq!(leases.list().len(), 1);
+    }
+}
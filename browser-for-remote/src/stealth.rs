@@ -0,0 +1,536 @@
+// stealth.rs
+
+use crate::auth::read_token;
+use crate::browser::{prepare_tab, ManagedBrowser, ManagedTab};
+use crate::config::{Config, IdentityProfile};
+use crate::proxy::ProxySettings;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Page the self-check loads when no URL is given.
+const BLANK_CHECK_PAGE: &str = "data:text/html,<title>stealth check</title>";
+
+/// Everything the patched page reports about its browser, worked out from one user agent so
+/// the headers, `navigator` and WebGL all tell the same story.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Fingerprint {
+    pub user_agent: String,
+    pub accept_language: String,
+    pub platform: String,
+    pub languages: Vec<String>,
+    pub webgl_vendor: String,
+    pub webgl_renderer: String,
+}
+
+impl Fingerprint {
+    /// Builds the fingerprint for `identity`, or for the browser's own user agent without its
+    /// `HeadlessChrome` token when the tab has no identity.
+    pub fn new(browser_user_agent: &str, identity: Option<&IdentityProfile>) -> Self {
+        let user_agent = match identity {
+            Some(identity) => identity.user_agent.clone(),
+            None => browser_user_agent.replace("HeadlessChrome", "Chrome"),
+        };
+        let accept_language = identity
+            .and_then(|identity| identity.accept_language.clone())
+            .unwrap_or_else(|| String::from("en-US,en;q=0.9"));
+        let (platform, webgl_vendor, webgl_renderer) = platform_defaults(&user_agent);
+        Fingerprint {
+            platform: identity
+                .and_then(|identity| identity.platform.clone())
+                .unwrap_or_else(|| platform.to_string()),
+            languages: parse_languages(&accept_language),
+            webgl_vendor: identity
+                .and_then(|identity| identity.webgl_vendor.clone())
+                .unwrap_or_else(|| webgl_vendor.to_string()),
+            webgl_renderer: identity
+                .and_then(|identity| identity.webgl_renderer.clone())
+                .unwrap_or_else(|| webgl_renderer.to_string()),
+            user_agent,
+            accept_language,
+        }
+    }
+}
+
+/// `navigator.platform` and a common WebGL vendor and renderer for the OS in `user_agent`.
+fn platform_defaults(user_agent: &str) -> (&'static str, &'static str, &'static str) {
+    if user_agent.contains("Windows") {
+        (
+            "Win32",
+            "Google Inc. (Intel)",
+            "ANGLE (Intel, Intel(R) UHD Graphics 630 Direct3D11 vs_5_0 ps_5_0, D3D11)",
+        )
+    } else if user_agent.contains("Macintosh") {
+        ("MacIntel", "Intel Inc.", "Intel Iris OpenGL Engine")
+    } else {
+        (
+            "Linux x86_64",
+            "Google Inc. (Intel)",
+            "ANGLE (Intel, Mesa Intel(R) UHD Graphics 630 (CFL GT2), OpenGL 4.6)",
+        )
+    }
+}
+
+/// The language tags in an `Accept-Language` value, in order, without their weights.
+fn parse_languages(accept_language: &str) -> Vec<String> {
+    accept_language
+        .split(',')
+        .map(|part| part.split(';').next().unwrap_or_default().trim())
+        .filter(|tag| !tag.is_empty() && *tag != "*")
+        .map(String::from)
+        .collect()
+}
+
+/// The script added to every new document in a stealth tab.
+pub fn stealth_script(fingerprint: &Fingerprint) -> String {
+    format!(
+        r#"(() => {{
+  const fp = {fingerprint};
+  const define = (target, name, value) =>
+    Object.defineProperty(target, name, {{ get: () => value, configurable: true }});
+  define(Navigator.prototype, 'webdriver', false);
+  define(Navigator.prototype, 'platform', fp.platform);
+  define(Navigator.prototype, 'language', fp.languages[0]);
+  define(Navigator.prototype, 'languages', Object.freeze([...fp.languages]));
+  if (navigator.userAgentData) {{
+    const brands = navigator.userAgentData.brands.map(({{ brand, version }}) =>
+      ({{ brand: brand.replace('HeadlessChrome', 'Google Chrome'), version }}));
+    define(NavigatorUAData.prototype, 'brands', Object.freeze(brands));
+  }}
+  if (navigator.plugins.length === 0) {{
+    const names = ['PDF Viewer', 'Chrome PDF Viewer', 'Chromium PDF Viewer',
+      'Microsoft Edge PDF Viewer', 'WebKit built-in PDF'];
+    const plugins = names.map((name) => Object.create(Plugin.prototype, {{
+      name: {{ value: name }},
+      filename: {{ value: 'internal-pdf-viewer' }},
+      description: {{ value: 'Portable Document Format' }},
+      length: {{ value: 0 }},
+    }}));
+    const list = Object.create(PluginArray.prototype, {{
+      length: {{ value: plugins.length }},
+      item: {{ value: (index) => plugins[index] || null }},
+      namedItem: {{ value: (name) => plugins.find((plugin) => plugin.name === name) || null }},
+    }});
+    plugins.forEach((plugin, index) => Object.defineProperty(list, index, {{ value: plugin }}));
+    define(Navigator.prototype, 'plugins', list);
+  }}
+  if (!window.chrome) {{
+    window.chrome = {{ app: {{ isInstalled: false }}, runtime: {{}}, csi() {{}}, loadTimes() {{}} }};
+  }}
+  for (const context of [window.WebGLRenderingContext, window.WebGL2RenderingContext]) {{
+    if (!context) continue;
+    const getParameter = context.prototype.getParameter;
+    context.prototype.getParameter = function (parameter) {{
+      if (parameter === 0x9245) return fp.webglVendor;
+      if (parameter === 0x9246) return fp.webglRenderer;
+      return getParameter.call(this, parameter);
+    }};
+  }}
+  if (navigator.permissions && window.Notification) {{
+    const query = navigator.permissions.query.bind(navigator.permissions);
+    navigator.permissions.query = (descriptor) =>
+      descriptor && descriptor.name === 'notifications'
+        ? Promise.resolve({{
+            state: Notification.permission === 'default' ? 'prompt' : Notification.permission,
+            onchange: null,
+          }})
+        : query(descriptor);
+  }}
+  if (window.outerWidth === 0) {{
+    define(window, 'outerWidth', window.innerWidth);
+    define(window, 'outerHeight', window.innerHeight + 85);
+  }}
+}})();"#,
+        fingerprint = json!(fingerprint)
+    )
+}
+
+/// Sets the tab's headers and adds the patches for its fingerprint. Must run before the tab
+/// navigates for the first page to see them.
+pub fn apply_stealth(
+    tab: &dyn ManagedTab,
+    identity: Option<&IdentityProfile>,
+) -> Result<Fingerprint, Box<dyn Error>> {
+    let browser_user_agent = match identity {
+        Some(_) => String::new(),
+        None => tab
+            .evaluate("navigator.userAgent")?
+            .as_str()
+            .map(String::from)
+            .ok_or("navigator.userAgent did not evaluate to a string")?,
+    };
+    let fingerprint = Fingerprint::new(&browser_user_agent, identity);
+    tab.set_identity(&IdentityProfile {
+        user_agent: fingerprint.user_agent.clone(),
+        accept_language: Some(fingerprint.accept_language.clone()),
+        platform: Some(fingerprint.platform.clone()),
+        webgl_vendor: None,
+        webgl_renderer: None,
+    })?;
+    tab.add_script_on_new_document(&stealth_script(&fingerprint))?;
+    Ok(fingerprint)
+}
+
+/// Collects what a page can see of the browser, as a JSON string.
+pub const DETECTION_SCRIPT: &str = r#"(async () => {
+  const gl = document.createElement('canvas').getContext('webgl');
+  const debug = gl && gl.getExtension('WEBGL_debug_renderer_info');
+  let notifications = null;
+  try {
+    notifications = (await navigator.permissions.query({ name: 'notifications' })).state;
+  } catch (e) {}
+  return JSON.stringify({
+    webdriver: navigator.webdriver === undefined ? null : navigator.webdriver,
+    userAgent: navigator.userAgent,
+    brands: navigator.userAgentData ? navigator.userAgentData.brands.map((b) => b.brand) : [],
+    platform: navigator.platform,
+    languages: navigator.languages,
+    plugins: navigator.plugins.length,
+    chrome: typeof window.chrome === 'object' && window.chrome !== null,
+    webglVendor: debug ? gl.getParameter(debug.UNMASKED_VENDOR_WEBGL) : null,
+    webglRenderer: debug ? gl.getParameter(debug.UNMASKED_RENDERER_WEBGL) : null,
+    notificationPermission: window.Notification ? Notification.permission : null,
+    notificationsQuery: notifications,
+    outerWidth: window.outerWidth,
+  });
+})()"#;
+
+/// One signal a page can read, and whether it gives the automation away.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub observed: Value,
+    pub detectable: bool,
+    pub reason: String,
+}
+
+/// Judges what `DETECTION_SCRIPT` observed, against `expected` when the tab had one.
+pub fn assess(observed: &Value, expected: Option<&Fingerprint>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut check = |check, observed: &Value, problem: Option<String>| {
+        findings.push(Finding {
+            check,
+            observed: observed.clone(),
+            detectable: problem.is_some(),
+            reason: problem.unwrap_or_default(),
+        })
+    };
+
+    let user_agent = observed["userAgent"].as_str().unwrap_or_default();
+    check(
+        "navigator.webdriver",
+        &observed["webdriver"],
+        (observed["webdriver"] == true).then(|| String::from("automation flag is set")),
+    );
+    check(
+        "navigator.userAgent",
+        &observed["userAgent"],
+        if user_agent.contains("Headless") {
+            Some(String::from("user agent says HeadlessChrome"))
+        } else {
+            expected
+                .filter(|expected| expected.user_agent != user_agent)
+                .map(|expected| format!("expected {}", expected.user_agent))
+        },
+    );
+    let brands = observed["brands"].as_array().cloned().unwrap_or_default();
+    check(
+        "navigator.userAgentData.brands",
+        &observed["brands"],
+        brands
+            .iter()
+            .any(|brand| brand.as_str().unwrap_or_default().contains("Headless"))
+            .then(|| String::from("brands include HeadlessChrome")),
+    );
+    let platform = observed["platform"].as_str().unwrap_or_default();
+    check(
+        "navigator.platform",
+        &observed["platform"],
+        match expected {
+            Some(expected) if expected.platform != platform => {
+                Some(format!("expected {}", expected.platform))
+            }
+            Some(_) => None,
+            None => {
+                let (consistent, _, _) = platform_defaults(user_agent);
+                (platform != consistent).then(|| format!("user agent suggests {}", consistent))
+            }
+        },
+    );
+    let languages = observed["languages"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    check(
+        "navigator.languages",
+        &observed["languages"],
+        if languages.is_empty() {
+            Some(String::from("no languages"))
+        } else {
+            expected
+                .filter(|expected| json!(expected.languages) != observed["languages"])
+                .map(|expected| format!("expected {}", expected.languages.join(",")))
+        },
+    );
+    check(
+        "navigator.plugins",
+        &observed["plugins"],
+        (observed["plugins"] == 0).then(|| String::from("no plugins, as in old headless mode")),
+    );
+    check(
+        "window.chrome",
+        &observed["chrome"],
+        (observed["chrome"] != true).then(|| String::from("window.chrome is missing")),
+    );
+    let renderer = observed["webglRenderer"].as_str().unwrap_or_default();
+    check(
+        "WebGL renderer",
+        &json!([observed["webglVendor"], observed["webglRenderer"]]),
+        if renderer.contains("SwiftShader") || renderer.contains("llvmpipe") {
+            Some(String::from("software renderer"))
+        } else {
+            expected
+                .filter(|expected| {
+                    !observed["webglRenderer"].is_null() && expected.webgl_renderer != renderer
+                })
+                .map(|expected| format!("expected {}", expected.webgl_renderer))
+        },
+    );
+    check(
+        "notification permission",
+        &json!([
+            observed["notificationPermission"],
+            observed["notificationsQuery"]
+        ]),
+        (observed["notificationPermission"] == "denied"
+            && observed["notificationsQuery"] == "prompt")
+            .then(|| String::from("Notification.permission and permissions.query disagree")),
+    );
+    check(
+        "window.outerWidth",
+        &observed["outerWidth"],
+        (observed["outerWidth"] == 0).then(|| String::from("no window frame")),
+    );
+    findings
+}
+
+/// Opens a throwaway tab prepared like a managed one, loads `url` and reports what the page
+/// can detect. The tab is closed again whatever happens.
+pub fn run_check<B: ManagedBrowser>(
+    browser: &B,
+    identity: Option<&IdentityProfile>,
+    url: Option<&str>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) -> Result<Value, Box<dyn Error>> {
+    let tab = browser.open_tab("about:blank", None)?;
+    let report = check_tab(tab.as_ref(), identity, url, proxy, stealth);
+    let _ = tab.close();
+    report
+}
+
+fn check_tab(
+    tab: &dyn ManagedTab,
+    identity: Option<&IdentityProfile>,
+    url: Option<&str>,
+    proxy: Option<&ProxySettings>,
+    stealth: bool,
+) -> Result<Value, Box<dyn Error>> {
+    let fingerprint = prepare_tab(tab, identity, proxy, stealth)?;
+    tab.navigate(url.unwrap_or(BLANK_CHECK_PAGE))?;
+    let started = Instant::now();
+    while tab.ready_state().ok().as_deref() != Some("complete") {
+        if started.elapsed() > Duration::from_secs(30) {
+            return Err("page did not finish loading within 30s".into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let observed: Value = serde_json::from_str(
+        tab.evaluate(DETECTION_SCRIPT)?
+            .as_str()
+            .ok_or("detection script returned nothing")?,
+    )?;
+    let findings = assess(&observed, fingerprint.as_ref());
+    Ok(json!({
+        "stealth": stealth,
+        "fingerprint": fingerprint,
+        "detectable": findings.iter().filter(|finding| finding.detectable).count(),
+        "findings": findings,
+    }))
+}
+
+/// `--stealth-check [--identity NAME] [--url URL]`: asks the running manager for a check and
+/// prints each finding. Returns false when something is detectable or the check failed.
+pub fn print_check_report(config: &Config, args: &[String]) -> bool {
+    let option = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|index| args.get(index + 1))
+    };
+    let body = json!({ "identity": option("--identity"), "url": option("--url") });
+    let mut request = reqwest::blocking::Client::new()
+        .post(format!("http://{}/stealth/check", config.control.address))
+        .json(&body);
+    if config.auth.enabled {
+        if let Some(token) = read_token(&config.auth) {
+            request = request.bearer_auth(token);
+        }
+    }
+    let response = request
+        .timeout(Duration::from_secs(90))
+        .send()
+        .and_then(|response| response.json::<Value>());
+    let report = match response {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Could not reach the manager's control interface: {}", e);
+            return false;
+        }
+    };
+    if let Some(error) = report["error"].as_str() {
+        println!("Check failed: {}", error);
+        return false;
+    }
+    println!(
+        "Stealth patches: {}",
+        if report["stealth"] == true {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    for finding in report["findings"].as_array().into_iter().flatten() {
+        let detectable = finding["detectable"] == true;
+        println!(
+            "{} {}: {}{}",
+            if detectable { "[!!]" } else { "[ok]" },
+            finding["check"].as_str().unwrap_or_default(),
+            finding["observed"],
+            if detectable {
+                format!(" ({})", finding["reason"].as_str().unwrap_or_default())
+            } else {
+                String::new()
+            }
+        );
+    }
+    report["detectable"] == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect;
+    use mock_devtools::{MockDevTools, HEADLESS_USER_AGENT};
+
+    fn windows_identity() -> IdentityProfile {
+        IdentityProfile {
+            user_agent: String::from(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+            ),
+            accept_language: Some(String::from("de-DE,de;q=0.9,en;q=0.8")),
+            platform: None,
+            webgl_vendor: None,
+            webgl_renderer: None,
+        }
+    }
+
+    #[test]
+    fn fingerprint_follows_the_identity() {
+        let fingerprint = Fingerprint::new(HEADLESS_USER_AGENT, Some(&windows_identity()));
+        assert_eq!(fingerprint.platform, "Win32");
+        assert_eq!(fingerprint.languages, vec!["de-DE", "de", "en"]);
+        assert!(fingerprint.webgl_renderer.contains("Direct3D11"));
+    }
+
+    #[test]
+    fn fingerprint_without_identity_drops_the_headless_token() {
+        let fingerprint = Fingerprint::new(HEADLESS_USER_AGENT, None);
+        assert!(!fingerprint.user_agent.contains("Headless"));
+        assert!(fingerprint.user_agent.contains("Chrome/124.0.0.0"));
+        assert_eq!(fingerprint.platform, "Linux x86_64");
+        assert_eq!(fingerprint.languages, vec!["en-US", "en"]);
+    }
+
+    #[test]
+    fn assess_flags_headless_giveaways() {
+        let expected = Fingerprint::new(HEADLESS_USER_AGENT, None);
+        let observed = json!({
+            "webdriver": true,
+            "userAgent": HEADLESS_USER_AGENT,
+            "brands": ["HeadlessChrome", "Chromium"],
+            "platform": "Linux x86_64",
+            "languages": ["en-US", "en"],
+            "plugins": 0,
+            "chrome": false,
+            "webglVendor": "Google Inc. (Google)",
+            "webglRenderer": "ANGLE (Google, Vulkan 1.3.0 (SwiftShader Device))",
+            "notificationPermission": "denied",
+            "notificationsQuery": "prompt",
+            "outerWidth": 0,
+        });
+        let detectable: Vec<_> = assess(&observed, Some(&expected))
+            .into_iter()
+            .filter(|finding| finding.detectable)
+            .map(|finding| finding.check)
+            .collect();
+        assert_eq!(
+            detectable,
+            vec![
+                "navigator.webdriver",
+                "navigator.userAgent",
+                "navigator.userAgentData.brands",
+                "navigator.plugins",
+                "window.chrome",
+                "WebGL renderer",
+                "notification permission",
+                "window.outerWidth",
+            ]
+        );
+    }
+
+    #[test]
+    fn assess_passes_a_consistent_page() {
+        let expected = Fingerprint::new("", Some(&windows_identity()));
+        let observed = json!({
+            "webdriver": false,
+            "userAgent": expected.user_agent,
+            "brands": ["Google Chrome", "Chromium"],
+            "platform": "Win32",
+            "languages": ["de-DE", "de", "en"],
+            "plugins": 5,
+            "chrome": true,
+            "webglVendor": expected.webgl_vendor,
+            "webglRenderer": expected.webgl_renderer,
+            "notificationPermission": "default",
+            "notificationsQuery": "prompt",
+            "outerWidth": 1280,
+        });
+        let findings = assess(&observed, Some(&expected));
+        assert!(findings.iter().all(|finding| !finding.detectable));
+    }
+
+    #[test]
+    fn apply_stealth_sets_the_headers_and_adds_the_patches() {
+        let mock = MockDevTools::start();
+        mock.open_target("about:blank");
+        let (_browser, tabs) = connect(&mock, 1);
+
+        let fingerprint = apply_stealth(tabs[0].as_ref(), None).unwrap();
+
+        let overrides = mock.calls_to("Network.setUserAgentOverride");
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(
+            overrides[0].params["userAgent"],
+            json!(fingerprint.user_agent)
+        );
+        assert_eq!(overrides[0].params["platform"], "Linux x86_64");
+        let scripts = mock.calls_to("Page.addScriptToEvaluateOnNewDocument");
+        assert_eq!(scripts.len(), 1);
+        let source = scripts[0].params["source"].as_str().unwrap();
+        assert!(source.contains(&json!(fingerprint).to_string()));
+    }
+}
@@ -0,0 +1,821 @@
+// tasks.rs
+//
+// The manager's recurring work, one tokio task per concern. DevTools calls block, so each task
+// makes them on the blocking pool: a slow tab holds up only the task talking to it.
+
+use crate::browser::{ManagedBrowser, ManagedTab};
+use crate::config::LiveConfig;
+use crate::console::{capture_console, ConsoleBuffers};
+use crate::control::ControlRequest;
+use crate::events::{self, append_to_file, deliver_webhook, wants, LifecycleEvent};
+use crate::focus::FocusRotation;
+use crate::health::{recover_tab, watch_for_crashes};
+use crate::jobs::{run_job, start_job, JobConfig, JobHistory, JobRun, JobTab};
+use crate::leases::LeaseTable;
+use crate::proxy::ProxySettings;
+use crate::utils::log_message;
+use crate::TabMetadata;
+use crate::{handle_control_request, reap_stale_tabs, reclaim_leased_tabs, track_tabs};
+use chrono::{DateTime, Utc};
+use headless_chrome::Browser;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout};
+
+/// How often tabs are listed and expired leases reclaimed.
+const TRACK_INTERVAL: Duration = Duration::from_millis(250);
+/// How often job schedules are checked.
+const JOB_INTERVAL: Duration = Duration::from_secs(1);
+/// Shortest gap between focus rotation passes, whatever `rotation.pass_delay_ms` says.
+const MIN_PASS_DELAY: Duration = Duration::from_millis(50);
+
+/// A running Chromium and the proxy it was launched with.
+pub struct Session {
+    pub browser: Arc<Browser>,
+    pub proxy: Option<ProxySettings>,
+    /// Where Chromium itself serves DevTools, on loopback.
+    pub devtools_port: u16,
+    /// The browser contexts of jobs with downloads, by job name.
+    pub job_contexts: Mutex<HashMap<String, String>>,
+}
+
+/// Everything the tasks share. The supervisor publishes each new browser on `session` and
+/// `None` while there is none.
+#[derive(Clone)]
+pub struct Shared {
+    pub live: Arc<RwLock<LiveConfig>>,
+    pub tab_metadata: Arc<Mutex<HashMap<String, TabMetadata>>>,
+    pub leases: Arc<Mutex<LeaseTable>>,
+    pub console: Arc<Mutex<ConsoleBuffers>>,
+    pub jobs: Arc<Mutex<JobHistory>>,
+    pub session: watch::Receiver<Option<Arc<Session>>>,
+}
+
+impl Shared {
+    /// The running browser, waiting for one while it is being (re)launched.
+    async fn session(&mut self) -> Option<Arc<Session>> {
+        let session = self.session.wait_for(Option::is_some).await.ok()?;
+        session.clone()
+    }
+
+    fn stealth(&self) -> bool {
+        self.live.read().unwrap().config.stealth.enabled
+    }
+}
+
+/// Starts the tracking, reaping, focus, health, console, event, job and control request
+/// tasks. They run until the runtime shuts down.
+pub fn spawn_tasks(shared: &Shared, requests: mpsc::UnboundedReceiver<ControlRequest>) {
+    // Subscribed before anything else starts so no event is published unheard
+    tokio::spawn(events_task(shared.clone(), events::subscribe()));
+    tokio::spawn(track_task(shared.clone()));
+    tokio::spawn(reaper_task(shared.clone()));
+    tokio::spawn(focus_task(shared.clone()));
+    tokio::spawn(health_task(shared.clone()));
+    tokio::spawn(console_task(shared.clone()));
+    tokio::spawn(jobs_task(shared.clone()));
+    tokio::spawn(control_request_task(shared.clone(), requests));
+}
+
+/// Picks up new tabs and notices closed ones and URL changes.
+async fn track_task(mut shared: Shared) {
+    while let Some(session) = shared.session().await {
+        let task = shared.clone();
+        let stealth = shared.stealth();
+        run_blocking("track tabs", move || {
+            track_tabs(
+                &session.browser.tabs(),
+                &task.tab_metadata,
+                &task.leases,
+                session.proxy.as_ref(),
+                stealth,
+            )
+        })
+        .await;
+        sleep(TRACK_INTERVAL).await;
+    }
+}
+
+/// Closes tabs whose lease ended and, with the reaper on, tabs stuck on one URL.
+async fn reaper_task(mut shared: Shared) {
+    while shared.session().await.is_some() {
+        let task = shared.clone();
+        run_blocking("reap tabs", move || {
+            reclaim_leased_tabs(&task.tab_metadata, &task.leases);
+            let reap_timeout = task
+                .live
+                .read()
+                .unwrap()
+                .config
+                .reaper
+                .same_url_timeout_secs;
+            if let Some(timeout) = reap_timeout {
+                reap_stale_tabs(
+                    &task.tab_metadata,
+                    &task.leases,
+                    &task.live,
+                    Duration::from_secs(timeout),
+                );
+            }
+        })
+        .await;
+        sleep(TRACK_INTERVAL).await;
+    }
+}
+
+/// Runs focus rotation passes, `rotation.pass_delay_ms` apart.
+async fn focus_task(mut shared: Shared) {
+    let mut rotation = FocusRotation::default();
+    while let Some(session) = shared.session().await {
+        let task = shared.clone();
+        let pass = spawn_blocking(move || {
+            rotation.tick(
+                &session.browser.tabs(),
+                &task.tab_metadata,
+                &task.leases,
+                &task.live,
+            );
+            rotation
+        });
+        rotation = match pass.await {
+            Ok(rotation) => rotation,
+            Err(e) => {
+                log_message(&format!("Focus rotation failed: {}", e), "ERROR");
+                FocusRotation::default()
+            }
+        };
+        let pass_delay =
+            Duration::from_millis(shared.live.read().unwrap().config.rotation.pass_delay_ms);
+        sleep(pass_delay.max(MIN_PASS_DELAY)).await;
+    }
+}
+
+/// Watches every tab for renderer crashes and, every `health.check_interval_secs`, for hangs,
+/// and recovers the tabs that have either.
+async fn health_task(mut shared: Shared) {
+    let (crashes_tx, mut crashes) = mpsc::unbounded_channel();
+    let mut watched: HashSet<String> = HashSet::new();
+    // Tabs with a probe still waiting on DevTools, so a hung tab isn't asked again and again
+    let probing: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    let mut last_check = Instant::now();
+    while let Some(session) = shared.session().await {
+        let tabs: Vec<(String, Arc<dyn ManagedTab>, u32)> = shared
+            .tab_metadata
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tab_id, metadata)| {
+                (
+                    tab_id.clone(),
+                    Arc::clone(&metadata.tab),
+                    metadata.crash_count,
+                )
+            })
+            .collect();
+        watched.retain(|tab_id| tabs.iter().any(|(id, _, _)| id == tab_id));
+        for (tab_id, tab, _) in &tabs {
+            if watched.insert(tab_id.clone()) {
+                let (tab, crashes_tx) = (Arc::clone(tab), crashes_tx.clone());
+                tokio::spawn(run_blocking("watch for crashes", move || {
+                    if let Err(e) = watch_for_crashes(tab.as_ref(), crashes_tx) {
+                        log_message(&format!("Failed to watch tab for crashes: {}", e), "WARN");
+                    }
+                }));
+            }
+        }
+
+        while let Ok(tab_id) = crashes.try_recv() {
+            tokio::spawn(recover(
+                shared.clone(),
+                Arc::clone(&session),
+                tab_id,
+                "crashed",
+            ));
+        }
+
+        let (check_interval, hang_timeout) = {
+            let health = &shared.live.read().unwrap().config.health;
+            (
+                Duration::from_secs(health.check_interval_secs),
+                Duration::from_secs(health.hang_timeout_secs.max(1)),
+            )
+        };
+        if !check_interval.is_zero() && last_check.elapsed() >= check_interval {
+            last_check = Instant::now();
+            for (tab_id, tab, crash_count) in tabs {
+                if probing.lock().unwrap().insert(tab_id.clone()) {
+                    tokio::spawn(probe(
+                        shared.clone(),
+                        Arc::clone(&session),
+                        Arc::clone(&probing),
+                        tab_id,
+                        tab,
+                        crash_count,
+                        hang_timeout,
+                    ));
+                }
+            }
+        }
+        sleep(TRACK_INTERVAL).await;
+    }
+}
+
+/// Asks the tab to evaluate `1` and recovers it if no answer comes within `hang_timeout`,
+/// unless it was recovered from something else in the meantime.
+async fn probe(
+    shared: Shared,
+    session: Arc<Session>,
+    probing: Arc<Mutex<HashSet<String>>>,
+    tab_id: String,
+    tab: Arc<dyn ManagedTab>,
+    crash_count: u32,
+    hang_timeout: Duration,
+) {
+    let evaluate = {
+        let tab_id = tab_id.clone();
+        spawn_blocking(move || {
+            let _ = tab.evaluate("1");
+            probing.lock().unwrap().remove(&tab_id);
+        })
+    };
+    if timeout(hang_timeout, evaluate).await.is_ok() {
+        return;
+    }
+    let unchanged = shared
+        .tab_metadata
+        .lock()
+        .unwrap()
+        .get(&tab_id)
+        .is_some_and(|metadata| metadata.crash_count == crash_count);
+    if unchanged {
+        recover(shared, session, tab_id, "hung").await;
+    }
+}
+
+async fn recover(shared: Shared, session: Arc<Session>, tab_id: String, problem: &'static str) {
+    run_blocking("recover tab", move || {
+        recover_tab(
+            session.browser.as_ref(),
+            &tab_id,
+            problem,
+            &shared.tab_metadata,
+            &shared.leases,
+            &shared.live,
+            session.proxy.as_ref(),
+        )
+    })
+    .await;
+}
+
+/// With `console.enabled`, subscribes to each tab's console and drops the buffers of closed
+/// tabs.
+async fn console_task(mut shared: Shared) {
+    let mut watched: HashSet<String> = HashSet::new();
+    while shared.session().await.is_some() {
+        let tabs: Vec<(String, Arc<dyn ManagedTab>)> = shared
+            .tab_metadata
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tab_id, metadata)| (tab_id.clone(), Arc::clone(&metadata.tab)))
+            .collect();
+        watched.retain(|tab_id| tabs.iter().any(|(id, _)| id == tab_id));
+        shared
+            .console
+            .lock()
+            .unwrap()
+            .retain(|tab_id| watched.contains(tab_id));
+
+        if shared.live.read().unwrap().config.console.enabled {
+            for (tab_id, tab) in tabs {
+                if watched.insert(tab_id) {
+                    let (buffers, live) = (Arc::clone(&shared.console), Arc::clone(&shared.live));
+                    tokio::spawn(run_blocking("capture console", move || {
+                        if let Err(e) = capture_console(&tab, &buffers, &live) {
+                            log_message(&format!("Failed to capture tab console: {}", e), "WARN");
+                        }
+                    }));
+                }
+            }
+        }
+        sleep(TRACK_INTERVAL).await;
+    }
+}
+
+/// Writes each lifecycle event to `events.file` and hands it to the webhooks that want it.
+async fn events_task(shared: Shared, mut events: broadcast::Receiver<LifecycleEvent>) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                log_message(&format!("Dropped {} lifecycle events", missed), "WARN");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let config = shared.live.read().unwrap().config.events.clone();
+        if let Some(path) = config.file {
+            let event = event.clone();
+            run_blocking("write event", move || {
+                if let Err(e) = append_to_file(&path, &event) {
+                    log_message(
+                        &format!("Failed to write event to {}: {}", path.display(), e),
+                        "ERROR",
+                    );
+                }
+            })
+            .await;
+        }
+        for webhook in config
+            .webhooks
+            .iter()
+            .filter(|webhook| wants(webhook, &event))
+        {
+            tokio::spawn(deliver_webhook(
+                client.clone(),
+                webhook.url.clone(),
+                event.clone(),
+                config.webhook_retries,
+            ));
+        }
+    }
+}
+
+/// Starts the scheduled jobs that are due. A job has at most one run going at a time.
+async fn jobs_task(shared: Shared) {
+    loop {
+        let session = shared.session.borrow().clone();
+        let now = Utc::now();
+        let due: Vec<(JobConfig, DateTime<Utc>)> = {
+            let live = shared.live.read().unwrap();
+            let mut history = shared.jobs.lock().unwrap();
+            live.jobs
+                .iter()
+                .filter_map(|job| {
+                    let scheduled_for = history.plan(job, now, session.is_some())?;
+                    Some((job.config.clone(), scheduled_for))
+                })
+                .collect()
+        };
+        if let Some(session) = &session {
+            for (job, scheduled_for) in due {
+                tokio::spawn(run_scheduled_job(
+                    shared.clone(),
+                    Arc::clone(session),
+                    job,
+                    scheduled_for,
+                ));
+            }
+        }
+        sleep(JOB_INTERVAL).await;
+    }
+}
+
+/// Runs `job` in a leased tab, giving up after `max_runtime_secs`, and records the run.
+async fn run_scheduled_job(
+    shared: Shared,
+    session: Arc<Session>,
+    job: JobConfig,
+    scheduled_for: DateTime<Utc>,
+) {
+    log_message(&format!("Starting job {}", job.name), "INFO");
+    let started_at = Utc::now();
+    let start = {
+        let (task, job) = (shared.clone(), job.clone());
+        spawn_blocking(move || {
+            start_job(
+                session.browser.as_ref(),
+                &job,
+                &session.job_contexts,
+                &task.tab_metadata,
+                &task.leases,
+                &task.live,
+                session.proxy.as_ref(),
+            )
+            .map_err(|e| e.to_string())
+        })
+    };
+    // A run that timed out, whose thread can't be stopped and is still going
+    let mut abandoned = None;
+    let (outcome, detail, result, downloads) = match start.await {
+        Ok(Ok(JobTab {
+            lease,
+            tab,
+            downloads,
+        })) => {
+            let mut run = {
+                let (tab, job, downloads) = (Arc::clone(&tab), job.clone(), downloads.clone());
+                spawn_blocking(move || {
+                    run_job(tab.as_ref(), &job, downloads.as_deref()).map_err(|e| e.to_string())
+                })
+            };
+            let max_runtime = Duration::from_secs(job.max_runtime_secs);
+            let (outcome, detail, result) = match timeout(max_runtime, &mut run).await {
+                Ok(Ok(Ok(result))) => ("ok", None, result),
+                Ok(Ok(Err(e))) => ("failed", Some(e), None),
+                Ok(Err(e)) => ("failed", Some(e.to_string()), None),
+                Err(_) => {
+                    abandoned = Some(run);
+                    (
+                        "timed_out",
+                        Some(format!("still running after {}s", job.max_runtime_secs)),
+                        None,
+                    )
+                }
+            };
+            // Whatever the outcome, so unfinished downloads are cancelled and the staged files
+            // cleaned up
+            let files = match downloads {
+                Some(downloads) => spawn_blocking(move || downloads.collect(tab.as_ref()))
+                    .await
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            };
+            // The reaper closes the tab once its lease is released
+            shared.leases.lock().unwrap().release(&lease.id);
+            (outcome, detail, result, files)
+        }
+        Ok(Err(e)) => ("failed", Some(e), None, Vec::new()),
+        Err(e) => ("failed", Some(e.to_string()), None, Vec::new()),
+    };
+    match &detail {
+        Some(detail) => log_message(
+            &format!("Job {} {}: {}", job.name, outcome, detail),
+            "ERROR",
+        ),
+        None => log_message(&format!("Job {} finished", job.name), "INFO"),
+    }
+    shared.jobs.lock().unwrap().record_run(
+        &job.name,
+        JobRun {
+            scheduled_for,
+            started_at: Some(started_at),
+            finished_at: Some(Utc::now()),
+            outcome,
+            detail,
+            result,
+            downloads,
+        },
+    );
+    // The job counts as running, so no new run overlaps, until the abandoned one has stopped.
+    // Its tab is gone, so its next DevTools call fails.
+    if let Some(run) = abandoned {
+        let _ = run.await;
+        log_message(
+            &format!("Timed-out run of job {} stopped", job.name),
+            "INFO",
+        );
+    }
+    shared.jobs.lock().unwrap().finish(&job.name);
+}
+
+/// Carries out control requests, each on its own blocking thread so a slow one doesn't hold
+/// up the next.
+async fn control_request_task(
+    mut shared: Shared,
+    mut requests: mpsc::UnboundedReceiver<ControlRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let Some(session) = shared.session().await else {
+            return;
+        };
+        let task = shared.clone();
+        let stealth = shared.stealth();
+        tokio::spawn(run_blocking("control request", move || {
+            handle_control_request(
+                session.browser.as_ref(),
+                request,
+                &task.tab_metadata,
+                &task.leases,
+                session.proxy.as_ref(),
+                stealth,
+            )
+        }));
+    }
+}
+
+/// Runs `work` on the blocking pool and logs it if it panicked.
+async fn run_blocking<F: FnOnce() + Send + 'static>(what: &str, work: F) {
+    if let Err(e) = spawn_blocking(work).await {
+        log_message(&format!("Failed to {}: {}", what, e), "ERROR");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, live_config, new_lease};
+    use mock_devtools::{wait_until, MockDevTools};
+    use serde_json::{json, Value};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use tokio::runtime::Runtime;
+    use tokio::sync::oneshot;
+
+    /// The tasks running against `browser` with `config`, plus what keeps them alive.
+    struct Running {
+        runtime: Runtime,
+        shared: Shared,
+        requests: mpsc::UnboundedSender<ControlRequest>,
+        _session: watch::Sender<Option<Arc<Session>>>,
+    }
+
+    fn start(browser: Browser, config: Value) -> Running {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (session_tx, session) = watch::channel(Some(Arc::new(Session {
+            browser: Arc::new(browser),
+            proxy: None,
+            devtools_port: 0,
+            job_contexts: Mutex::default(),
+        })));
+        let shared = Shared {
+            live: Arc::new(live_config(config)),
+            tab_metadata: Arc::new(Mutex::new(HashMap::new())),
+            leases: Arc::new(Mutex::new(LeaseTable::default())),
+            console: Arc::new(Mutex::new(ConsoleBuffers::default())),
+            jobs: Arc::new(Mutex::new(JobHistory::default())),
+            session,
+        };
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        runtime.block_on(async { spawn_tasks(&shared, requests_rx) });
+        Running {
+            runtime,
+            shared,
+            requests,
+            _session: session_tx,
+        }
+    }
+
+    #[test]
+    fn a_hung_tab_does_not_hold_up_lease_requests() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        mock.open_target("http://hung.test/");
+        let (browser, _tabs) = connect(&mock, 2);
+        mock.delay("Page.bringToFront", Duration::from_secs(5));
+        let running = start(browser, json!({ "rotation": { "dwell_ms": 10 } }));
+        assert!(wait_until(Duration::from_secs(5), || !mock
+            .calls_to("Page.bringToFront")
+            .is_empty()));
+
+        let started = Instant::now();
+        let (reply, response) = oneshot::channel();
+        running
+            .requests
+            .send(ControlRequest::OpenTab {
+                lease: new_lease("http://leased.test/"),
+                identity: None,
+                reply,
+            })
+            .unwrap();
+        let tab_id = response.blocking_recv().unwrap().unwrap().tab_id;
+
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(running
+            .shared
+            .tab_metadata
+            .lock()
+            .unwrap()
+            .contains_key(&tab_id));
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn lifecycle_events_are_appended_to_the_event_file() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://first.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let running = start(browser, json!({ "events": { "file": path } }));
+
+        let logged = |kind: &str| {
+            std::fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .any(|event| event["type"] == kind && event["tab_id"] == target_id.as_str())
+        };
+        assert!(wait_until(Duration::from_secs(5), || logged("tab_opened")));
+        mock.close_target(&target_id);
+        assert!(wait_until(Duration::from_secs(5), || logged("tab_closed")));
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn scheduled_jobs_run_in_a_leased_tab_and_record_their_result() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        let running = start(
+            browser,
+            json!({ "jobs": [{
+                "name": "ping",
+                "schedule": "* * * * * *",
+                "url": "http://job.test/",
+                "script": "navigator.userAgent",
+                "missed": "skip",
+            }] }),
+        );
+
+        let finished_run = || {
+            let live = running.shared.live.read().unwrap();
+            let summary =
+                running
+                    .shared
+                    .jobs
+                    .lock()
+                    .unwrap()
+                    .to_json(&live.jobs[0], Utc::now(), true);
+            summary["runs"]
+                .as_array()
+                .and_then(|runs| runs.iter().find(|run| run["outcome"] == "ok").cloned())
+        };
+        assert!(wait_until(Duration::from_secs(10), || finished_run().is_some()));
+        let run = finished_run().unwrap();
+        assert_eq!(run["result"], mock_devtools::HEADLESS_USER_AGENT);
+        assert!(mock
+            .calls_to("Page.navigate")
+            .iter()
+            .any(|call| call.params["url"] == "http://job.test/"));
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn a_timed_out_run_holds_the_job_until_its_thread_stops() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        mock.delay("Runtime.evaluate", Duration::from_secs(3));
+        let running = start(
+            browser,
+            json!({
+                "health": { "check_interval_secs": 0 },
+                "jobs": [{
+                    "name": "slow",
+                    "schedule": "* * * * * *",
+                    "url": "http://job.test/",
+                    "max_runtime_secs": 1,
+                }],
+            }),
+        );
+
+        let summary = || {
+            let live = running.shared.live.read().unwrap();
+            running
+                .shared
+                .jobs
+                .lock()
+                .unwrap()
+                .to_json(&live.jobs[0], Utc::now(), true)
+        };
+        let timed_out = || {
+            summary()["runs"]
+                .as_array()
+                .is_some_and(|runs| runs.iter().any(|run| run["outcome"] == "timed_out"))
+        };
+        assert!(wait_until(Duration::from_secs(10), timed_out));
+        // Its readyState check is still waiting on DevTools
+        assert_eq!(summary()["running"], true);
+        assert_eq!(mock.calls_to("Target.createTarget").len(), 1);
+        assert!(wait_until(Duration::from_secs(10), || summary()["running"] == false));
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn job_downloads_are_kept_or_rejected_and_listed_in_the_run() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://first.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        let dir = tempfile::tempdir().unwrap();
+        let running = start(
+            browser,
+            json!({ "jobs": [{
+                "name": "export",
+                "schedule": "* * * * * *",
+                "url": "http://job.test/",
+                "stay_secs": 2,
+                "downloads": { "dir": dir.path(), "mime_types": ["text/*"] },
+            }] }),
+        );
+
+        assert!(wait_until(Duration::from_secs(10), || !mock
+            .calls_to("Browser.setDownloadBehavior")
+            .is_empty()));
+        let behavior = mock.calls_to("Browser.setDownloadBehavior").remove(0);
+        assert_eq!(behavior.params["behavior"], "allowAndName");
+        let staging = PathBuf::from(behavior.params["downloadPath"].as_str().unwrap());
+        let target_id = behavior.target_id.unwrap();
+        // Only the job's tab is in the context the download settings apply to
+        let context_id = &behavior.params["browserContextId"];
+        assert!(context_id.is_string());
+        let opened = mock.calls_to("Target.createTarget");
+        assert_eq!(opened.len(), 1);
+        assert_eq!(&opened[0].params["browserContextId"], context_id);
+        let mode = std::fs::metadata(&staging).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        for (guid, name) in [("csv-guid", "report.csv"), ("exe-guid", "setup.exe")] {
+            std::fs::write(staging.join(guid), "a,b\n").unwrap();
+            mock.emit(
+                &target_id,
+                "Page.downloadWillBegin",
+                json!({ "frameId": "F", "guid": guid, "url": "http://job.test/x", "suggestedFilename": name }),
+            );
+            mock.emit(
+                &target_id,
+                "Page.downloadProgress",
+                json!({ "guid": guid, "totalBytes": 4.0, "receivedBytes": 4.0, "state": "completed" }),
+            );
+        }
+
+        let finished_run = || {
+            let live = running.shared.live.read().unwrap();
+            let summary =
+                running
+                    .shared
+                    .jobs
+                    .lock()
+                    .unwrap()
+                    .to_json(&live.jobs[0], Utc::now(), true);
+            summary["runs"]
+                .as_array()
+                .and_then(|runs| runs.iter().find(|run| run["downloads"].is_array()).cloned())
+        };
+        assert!(wait_until(Duration::from_secs(10), || finished_run().is_some()));
+        let downloads = finished_run().unwrap()["downloads"].clone();
+        assert_eq!(downloads[0]["state"], "completed");
+        assert_eq!(downloads[0]["mime_type"], "text/csv");
+        assert_eq!(downloads[0]["path"], json!(dir.path().join("report.csv")));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("report.csv")).unwrap(),
+            "a,b\n"
+        );
+        assert_eq!(downloads[1]["state"], "type_not_allowed");
+        assert!(wait_until(Duration::from_secs(5), || !staging.exists()));
+
+        // The next run gets a new staging directory in the same context
+        assert!(wait_until(Duration::from_secs(10), || mock
+            .calls_to("Browser.setDownloadBehavior")
+            .len()
+            > 1));
+        let behavior = mock.calls_to("Browser.setDownloadBehavior").remove(1);
+        assert_eq!(&behavior.params["browserContextId"], context_id);
+        assert_ne!(behavior.params["downloadPath"], json!(staging));
+        assert_eq!(mock.calls_to("Target.createBrowserContext").len(), 1);
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn crash_events_reload_the_tab() {
+        let mock = MockDevTools::start();
+        let target_id = mock.open_target("http://app.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        let running = start(browser, json!({ "health": { "check_interval_secs": 0 } }));
+        assert!(wait_until(Duration::from_secs(5), || !mock
+            .calls_to("Inspector.enable")
+            .is_empty()));
+
+        mock.crash(&target_id);
+
+        assert!(wait_until(Duration::from_secs(5), || !mock
+            .calls_to("Page.reload")
+            .is_empty()));
+        assert_eq!(
+            running.shared.tab_metadata.lock().unwrap()[&target_id].crash_count,
+            1
+        );
+        running.runtime.shutdown_background();
+    }
+
+    #[test]
+    fn hung_tabs_are_recovered() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://stuck.test/");
+        let (browser, _tabs) = connect(&mock, 1);
+        mock.delay("Runtime.evaluate", Duration::from_secs(3));
+        let running = start(
+            browser,
+            json!({
+                "url_rules": [{ "host": "stuck.test", "action": "pin", "on_crash": "close" }],
+                "health": { "check_interval_secs": 1, "hang_timeout_secs": 1 },
+            }),
+        );
+
+        assert!(wait_until(Duration::from_secs(5), || mock
+            .target_ids()
+            .is_empty()));
+        assert!(mock.calls_to("Page.reload").is_empty());
+        running.runtime.shutdown_background();
+    }
+}
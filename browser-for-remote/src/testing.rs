@@ -0,0 +1,51 @@
+// testing.rs
+//
+// Unit test helpers: headless_chrome attached to the mock DevTools endpoint, and the shared
+// state the main loop normally owns.
+
+use crate::config::{Config, LeaseConfig, LiveConfig};
+use crate::leases::{LeaseTable, NewLease};
+use crate::TabMetadata;
+use headless_chrome::{Browser, Tab};
+use mock_devtools::{wait_until, MockDevTools};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Connects to `mock` and waits for a handle to each of its `count` tabs, in the order opened.
+pub fn connect(mock: &MockDevTools, count: usize) -> (Browser, Vec<Arc<Tab>>) {
+    let browser = Browser::connect(mock.ws_url()).unwrap();
+    let attached = wait_until(Duration::from_secs(5), || {
+        browser.get_tabs().lock().unwrap().len() == count
+    });
+    assert!(attached, "browser never attached to {} tabs", count);
+    let tabs = browser.get_tabs().lock().unwrap().clone();
+    (browser, tabs)
+}
+
+/// A live config built from `config`, with defaults for everything it leaves out.
+pub fn live_config(config: Value) -> RwLock<LiveConfig> {
+    let config: Config = serde_json::from_value(config).unwrap();
+    RwLock::new(LiveConfig::new(config).unwrap())
+}
+
+pub fn empty_state() -> (Mutex<HashMap<String, TabMetadata>>, Mutex<LeaseTable>) {
+    (
+        Mutex::new(HashMap::new()),
+        Mutex::new(LeaseTable::default()),
+    )
+}
+
+/// A lease for the client `test` on a new tab at `url`, with the default lease settings.
+pub fn new_lease(url: &str) -> NewLease {
+    NewLease {
+        url: url.to_string(),
+        browser_context_id: None,
+        client: String::from("test"),
+        ttl_secs: None,
+        focus: None,
+        identity: None,
+        config: LeaseConfig::default(),
+    }
+}
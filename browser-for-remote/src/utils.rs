@@ -1,8 +1,11 @@
 // utils.rs
 
-use chrono;
-
-use anyhow::Result;
+use crate::config::{BrowserConfig, Config};
+use crate::discovery::select_browser;
+use crate::display::{resolve_display, DisplayEnv};
+use crate::proxy::{bound_forwarder, next_proxy, ProxySettings};
+use crate::sandbox::{detect_sandbox, SandboxSupport};
+use crate::tasks::Session;
 use headless_chrome::{Browser, LaunchOptions};
 use rand::distributions::Alphanumeric;
 use rand::seq::SliceRandom;
@@ -10,15 +13,30 @@ use rand::{thread_rng, Rng};
 // use serde_json::Value;
 use std::env;
 use std::error::Error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::net::TcpListener;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-const BROWSER_HEADLESS: bool = false;
-// const BROWSER_HEADLESS: bool = true;
-const BROWSER_PATH: &str = "/usr/bin/chromium-browser";
+/// Rank of the lowest level `log_message` prints.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(1);
+
+fn level_rank(level: &str) -> u8 {
+    match level.to_ascii_uppercase().as_str() {
+        "DEBUG" => 0,
+        "INFO" => 1,
+        "WARN" => 2,
+        _ => 3,
+    }
+}
+
+/// Sets the lowest level `log_message` prints; unknown levels are treated as `ERROR`.
+pub fn set_log_level(level: &str) {
+    LOG_LEVEL.store(level_rank(level), Ordering::Relaxed);
+}
 
 /// Logs a message with the current date and time.
 ///
@@ -32,6 +50,9 @@ const BROWSER_PATH: &str = "/usr/bin/chromium-browser";
 /// log_message("Removing cookie consent pop-up.");
 /// ```
 pub fn log_message(message: &str, level: &str) {
+    if level_rank(level) < LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
     println!(
         "[{}][{}] {}",
         level,
@@ -39,30 +60,14 @@ pub fn log_message(message: &str, level: &str) {
         message
     );
 }
-pub fn create_browser() -> Result<Arc<Browser>, Box<dyn Error>> {
-    let browser_profile_path = get_profile_path();
+/// Launches Chromium with the manager's flags.
+///
+/// Returns the browser together with the proxy it was started behind, if any,
+/// so tabs can be set up to answer the proxy's auth challenges, and its DevTools port.
+pub fn create_browser(config: &Config) -> Result<Session, Box<dyn Error>> {
+    let browser_profile_path = get_profile_path(&config.browser);
     // * LAUNCH BROWSER
-    // For later
-    // Replace with your actual IPv6 address
-    // let ipv6_address = Ipv6Addr::new(2001, 0db8, 0, 0, 0, 0, 0, 1);
-
-    // Common paths to check for Chrome/Chromium executables
-    let browser_paths = vec![
-        "/usr/bin/chrome",
-        "/usr/bin/chromium",
-        "/usr/bin/google-chrome",
-        "/usr/bin/chromium-browser",
-        "/opt/google/chrome/chrome",
-        "/snap/bin/chromium",
-        // Add any additional paths you may want to check
-    ];
-
-    // Try to find the browser executable from the list of possible paths
-    let browser_path = browser_paths
-        .iter()
-        .find(|&&path| PathBuf::from(path).exists())
-        .map(|&path| path)
-        .unwrap_or(BROWSER_PATH); // Fallback to default if no paths are found
+    let (browser_path, browser_version) = select_browser(&config.browser)?;
 
     // Create a new headless Chrome browser instance
     let resolutions = [
@@ -155,7 +160,6 @@ pub fn create_browser() -> Result<Arc<Browser>, Box<dyn Error>> {
 
     let no_experiments = &OsString::from("--no-experiments");
     let no_first_run = &OsString::from("--no-first-run");
-    let no_sandbox = &OsString::from("--no-sandbox");
 
     let disable_hang_monitor = &OsString::from("--disable-hang-monitor");
     let disable_background_networking = &OsString::from("--disable-background-networking");
@@ -166,24 +170,46 @@ pub fn create_browser() -> Result<Arc<Browser>, Box<dyn Error>> {
         &OsString::from("--disable-blink-features=AutomationControlled");
 
     let remote_debugging_address = &OsString::from("--remote-debugging-address=127.0.0.1");
-    let remote_debugging_port = &OsString::from("--remote-debugging-port=9222");
-    let mut launch_options = LaunchOptions::default();
-    launch_options.headless = BROWSER_HEADLESS;
-    launch_options.idle_browser_timeout = Duration::from_secs(31536000);
-    launch_options.user_data_dir = Some(PathBuf::from(browser_profile_path));
-    launch_options.window_size = window_size.clone();
+    // With auth on, clients go through the gateway. The random port only keeps them from
+    // guessing Chromium's; any local user can still find it and skip the token.
+    let debugging_port = match config.browser.debugging_port {
+        port if port != 0 && !config.auth.enabled => port,
+        _ => TcpListener::bind("127.0.0.1:0")?.local_addr()?.port(),
+    };
+    let remote_debugging_port =
+        &OsString::from(format!("--remote-debugging-port={}", debugging_port));
 
-    let mut window_size_arg = OsString::from(format!("--window-size=1920,1080"));
+    // Route traffic through the next proxy in the rotation. When a source address is
+    // configured Chromium talks to the local bound forwarder, which relays to the proxy.
+    let proxy = match (config.proxy.bind_address, next_proxy(&config.proxy)?) {
+        // Tabs answer the forwarder's challenge; it signs in to the upstream proxy itself
+        (Some(bind_address), upstream) => Some(bound_forwarder(bind_address, upstream)?),
+        (None, proxy) => proxy,
+    };
+    let proxy_server = proxy.as_ref().map(ProxySettings::server_arg);
+    let proxy_server_arg = proxy_server
+        .as_ref()
+        .map(|server| OsString::from(format!("--proxy-server={}", server)));
+    let proxy_bypass_list_arg = config
+        .proxy
+        .bypass_list
+        .as_ref()
+        .map(|list| OsString::from(format!("--proxy-bypass-list={}", list)));
+
+    let mut window_size_arg = OsString::from("--window-size=1920,1080");
     // If a window size is selected, create the corresponding --window-size argument
-    if let Some((width, height)) = window_size.clone() {
+    if let Some((width, height)) = window_size {
         window_size_arg = OsString::from(format!("--window-size={},{}", width, height));
     }
 
-    launch_options.path = Some(PathBuf::from(browser_path)); // Use found browser path
+    let display = resolve_display(
+        &config.browser,
+        window_size.unwrap_or((1920, 1080)),
+        &DisplayEnv::from_env(),
+    )?;
 
-    launch_options.args = vec![
+    let mut args: Vec<&OsStr> = vec![
         &window_size_arg,
-        no_sandbox,
         disable_translate,
         disable_default_apps,
         // disable_accelerated_2d_canvas,
@@ -241,13 +267,83 @@ pub fn create_browser() -> Result<Arc<Browser>, Box<dyn Error>> {
         force_device_scale_factor,
         disable_automation_controlled,
     ];
+    args.extend(display.args.iter().map(OsString::as_os_str));
+    if let Some(proxy_server_arg) = &proxy_server_arg {
+        args.push(proxy_server_arg);
+    }
+    if let Some(proxy_bypass_list_arg) = &proxy_bypass_list_arg {
+        args.push(proxy_bypass_list_arg);
+    }
+
+    let sandbox = match detect_sandbox(&browser_path) {
+        SandboxSupport::Unavailable(reasons) if config.browser.allow_no_sandbox => {
+            log_message(
+                &format!(
+                    "Launching with --no-sandbox ({}). A page that exploits a renderer bug \
+                     gets the manager user's full access to this host",
+                    reasons.join("; ")
+                ),
+                "WARN",
+            );
+            false
+        }
+        SandboxSupport::Unavailable(reasons) => {
+            return Err(format!(
+                "No usable Chromium sandbox: {}. Allow unprivileged user namespaces, install \
+                 chrome-sandbox setuid root, or set browser.allow_no_sandbox to run unsandboxed",
+                reasons.join("; ")
+            )
+            .into());
+        }
+        support => {
+            log_message(&format!("Chromium runs with the {}", support), "DEBUG");
+            true
+        }
+    };
+
+    let launch_options = LaunchOptions {
+        // Every mode's headless flag, if any, is among the display args
+        headless: false,
+        sandbox,
+        idle_browser_timeout: Duration::from_secs(31536000),
+        user_data_dir: Some(PathBuf::from(&browser_profile_path)),
+        port: Some(debugging_port),
+        window_size,
+        path: Some(browser_path.clone()),
+        args,
+        process_envs: display.envs.clone(),
+        ..LaunchOptions::default()
+    };
     let browser = Browser::new(launch_options)?;
-    Ok(Arc::new(browser.into()))
+    log_message(
+        &format!(
+            "Launched {} ({}, {:?}) with profile {}, DevTools on port {}",
+            browser_version.description,
+            browser_path.display(),
+            display.mode,
+            browser_profile_path,
+            debugging_port
+        ),
+        "INFO",
+    );
+    if let Some(proxy_server) = &proxy_server {
+        log_message(
+            &format!("Browser traffic goes through {}", proxy_server),
+            "INFO",
+        );
+    }
+    Ok(Session {
+        browser: Arc::new(browser),
+        proxy,
+        devtools_port: debugging_port,
+        job_contexts: Mutex::default(),
+    })
 }
 
 /// Returns the path to the profile directory for browser use.
 ///
-/// If the `HOME` environment variable is set, it will return the path
+/// `browser.profile_dir` is used when configured. Otherwise, if the `HOME`
+/// environment variable is set, it will return the path
 /// `~/.browser-for-remote/`. If `HOME` is not set, it will generate a
 /// fallback path in `/tmp/browser-for-remote/` with a random suffix.
 ///
@@ -256,10 +352,11 @@ pub fn create_browser() -> Result<Arc<Browser>, Box<dyn Error>> {
 ///
 /// # Panics
 /// This function will panic if the directory cannot be created.
-pub fn get_profile_path() -> String {
-    let profile_path = match env::var("HOME") {
-        Ok(home_dir) => format!("{}/.browser-for-remote/", home_dir),
-        Err(_) => {
+pub fn get_profile_path(config: &BrowserConfig) -> String {
+    let profile_path = match (&config.profile_dir, env::var("HOME")) {
+        (Some(profile_dir), _) => profile_dir.clone(),
+        (None, Ok(home_dir)) => format!("{}/.browser-for-remote/", home_dir),
+        (None, Err(_)) => {
             // Generate a random string to append to /tmp/browser-for-remote/
             let random_string: String = thread_rng()
                 .sample_iter(&Alphanumeric)
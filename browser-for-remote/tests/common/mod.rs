@@ -0,0 +1,310 @@
+// common/mod.rs
+//
+// Shared harness for the integration tests: a fixture HTTP server and a manager
+// launched headless with its own profile, ports and state file.
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+pub const MANAGER_BIN: &str = env!("CARGO_BIN_EXE_browser-for-remote");
+
+/// True when the manager can find a browser. Tests return early, with a note, when it can't.
+pub fn chromium_available() -> bool {
+    let available = Command::new(MANAGER_BIN)
+        .arg("--list-browsers")
+        .env(
+            "BROWSER_FOR_REMOTE_CONFIG",
+            "/nonexistent/browser-for-remote.json",
+        )
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.starts_with('*'))
+        })
+        .unwrap_or(false);
+    if !available {
+        eprintln!("skipping: no Chrome/Chromium found");
+    }
+    available
+}
+
+/// Polls `check` every 100ms until it returns true or `timeout` passes.
+pub fn wait_until(timeout: Duration, mut check: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if check() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Serves a few static pages on a random local port and records the paths requested.
+pub struct FixtureServer {
+    pub port: u16,
+    pub requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl FixtureServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let seen = Arc::clone(&seen);
+                thread::spawn(move || serve_fixture(stream, &seen));
+            }
+        });
+        FixtureServer { port, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn was_requested(&self, path: &str) -> bool {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|seen| seen == path)
+    }
+}
+
+fn serve_fixture(mut stream: TcpStream, seen: &Mutex<Vec<String>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+    seen.lock().unwrap().push(path.clone());
+
+    let (status, body) = match path.as_str() {
+        "/favicon.ico" => ("404 Not Found", String::new()),
+        "/slow" => {
+            thread::sleep(Duration::from_secs(3));
+            ("200 OK", page("Slow page"))
+        }
+        _ => ("200 OK", page(&format!("Fixture {}", path))),
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+fn page(title: &str) -> String {
+    format!(
+        "<!doctype html><html><head><title>{0}</title></head><body><h1>{0}</h1></body></html>",
+        title
+    )
+}
+
+/// The access token the test managers are configured with.
+pub const TOKEN: &str = "integration-test-token";
+
+/// A running manager with its own temp directory for profile, config and state.
+pub struct Manager {
+    child: Child,
+    pub dir: TempDir,
+    pub control_url: String,
+    /// The DevTools gateway's port.
+    pub debugging_port: u16,
+    pub output: Arc<Mutex<String>>,
+}
+
+impl Manager {
+    /// Starts the manager headless with `extra` merged over the test defaults.
+    pub fn start(extra: Value) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let control_port = free_port();
+        let debugging_port = free_port();
+        let mut config = json!({
+            "control": { "address": format!("127.0.0.1:{}", control_port) },
+            "auth": {
+                "token": TOKEN,
+                "devtools_address": format!("127.0.0.1:{}", debugging_port),
+            },
+            "browser": {
+                "mode": "new-headless",
+                "profile_dir": dir.path().join("profile"),
+                // Test machines and CI containers often run as root
+                "allow_no_sandbox": true,
+            },
+            "state": { "path": dir.path().join("state.json"), "restore": "none" },
+            "log_level": "DEBUG",
+        });
+        merge(&mut config, extra);
+        let config_path = dir.path().join("config.json");
+        std::fs::write(&config_path, config.to_string()).unwrap();
+
+        let mut child = Command::new(MANAGER_BIN)
+            .env("BROWSER_FOR_REMOTE_CONFIG", &config_path)
+            .env_remove("DISPLAY")
+            .env_remove("WAYLAND_DISPLAY")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let output = Arc::new(Mutex::new(String::new()));
+        let mut stdout = child.stdout.take().unwrap();
+        let captured = Arc::clone(&output);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok(read) = stdout.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+                captured
+                    .lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&buffer[..read]));
+            }
+        });
+
+        let manager = Manager {
+            child,
+            dir,
+            control_url: format!("http://127.0.0.1:{}", control_port),
+            debugging_port,
+            output,
+        };
+        let ready = wait_until(Duration::from_secs(30), || {
+            manager.devtools_get("/json/version").is_some()
+        });
+        assert!(ready, "browser never came up:\n{}", manager.log());
+        manager
+    }
+
+    pub fn log(&self) -> String {
+        self.output.lock().unwrap().clone()
+    }
+
+    pub fn config_path(&self) -> std::path::PathBuf {
+        self.dir.path().join("config.json")
+    }
+
+    pub fn devtools_get(&self, path: &str) -> Option<Value> {
+        reqwest::blocking::Client::new()
+            .get(format!("http://127.0.0.1:{}{}", self.debugging_port, path))
+            .bearer_auth(TOKEN)
+            .send()
+            .ok()?
+            .json()
+            .ok()
+    }
+
+    /// Opens a tab behind the manager's back, straight through DevTools.
+    pub fn open_unmanaged_tab(&self, url: &str) -> String {
+        let tab: Value = reqwest::blocking::Client::new()
+            .put(format!(
+                "http://127.0.0.1:{}/json/new?{}",
+                self.debugging_port, url
+            ))
+            .bearer_auth(TOKEN)
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        tab["id"].as_str().unwrap().to_string()
+    }
+
+    pub fn page_ids(&self) -> Vec<String> {
+        self.devtools_get("/json")
+            .and_then(|tabs| tabs.as_array().cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter(|tab| tab["type"] == "page")
+            .filter_map(|tab| tab["id"].as_str().map(String::from))
+            .collect()
+    }
+
+    pub fn control(&self, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let response = reqwest::blocking::Client::new()
+            .request(
+                method.parse().unwrap(),
+                format!("{}{}", self.control_url, path),
+            )
+            .bearer_auth(TOKEN)
+            .json(&body)
+            .timeout(Duration::from_secs(40))
+            .send()
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.json().unwrap_or(Value::Null))
+    }
+
+    /// Sends SIGTERM and waits for the manager to exit.
+    pub fn terminate(&mut self) -> Option<ExitStatus> {
+        Command::new("kill")
+            .args(["-TERM", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(20) {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        None
+    }
+
+    pub fn state_file(&self) -> std::path::PathBuf {
+        self.dir.path().join("state.json")
+    }
+}
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.terminate();
+            let _ = self.child.kill();
+        }
+    }
+}
+
+fn merge(base: &mut Value, extra: Value) {
+    match (base, extra) {
+        (Value::Object(base), Value::Object(extra)) => {
+            for (key, value) in extra {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, extra) => *base = extra,
+    }
+}
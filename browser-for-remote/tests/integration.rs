@@ -0,0 +1,154 @@
+// integration.rs
+//
+// Runs the manager against a real headless Chromium. Each test returns early when
+// no browser is installed.
+
+mod common;
+
+use common::{chromium_available, wait_until, FixtureServer, Manager};
+use serde_json::json;
+use std::fs;
+use std::time::Duration;
+
+#[test]
+fn lease_opens_tab_and_release_closes_it() {
+    if !chromium_available() {
+        return;
+    }
+    let fixtures = FixtureServer::start();
+    let manager = Manager::start(json!({}));
+
+    let (status, lease) = manager.control(
+        "POST",
+        "/leases",
+        json!({ "client": "test", "url": fixtures.url("/leased"), "ttl_secs": 30 }),
+    );
+    assert_eq!(status, 201, "{}", lease);
+    let tab_id = lease["tab_id"].as_str().unwrap().to_string();
+    assert!(wait_until(Duration::from_secs(10), || fixtures
+        .was_requested("/leased")));
+    assert!(manager.page_ids().contains(&tab_id));
+
+    let (status, leases) = manager.control("GET", "/leases", json!(null));
+    assert_eq!(status, 200);
+    assert_eq!(leases.as_array().unwrap().len(), 1);
+
+    let lease_id = lease["lease_id"].as_str().unwrap();
+    let (status, _) = manager.control("DELETE", &format!("/leases/{}", lease_id), json!(null));
+    assert_eq!(status, 200);
+    assert!(
+        wait_until(Duration::from_secs(10), || !manager
+            .page_ids()
+            .contains(&tab_id)),
+        "released tab was not closed:\n{}",
+        manager.log()
+    );
+}
+
+#[test]
+fn requests_without_the_token_are_refused() {
+    if !chromium_available() {
+        return;
+    }
+    let manager = Manager::start(json!({}));
+    let client = reqwest::blocking::Client::new();
+    let control = client
+        .get(format!("{}/leases", manager.control_url))
+        .send()
+        .unwrap();
+    assert_eq!(control.status(), 401);
+    let devtools = client
+        .get(format!("http://127.0.0.1:{}/json", manager.debugging_port))
+        .send()
+        .unwrap();
+    assert_eq!(devtools.status(), 401);
+
+    // The advertised WebSocket URLs lead back through the gateway
+    let version = manager.devtools_get("/json/version").unwrap();
+    let ws_url = version["webSocketDebuggerUrl"].as_str().unwrap();
+    assert!(ws_url.starts_with(&format!("ws://127.0.0.1:{}/", manager.debugging_port)));
+    assert!(ws_url.ends_with(&format!("?token={}", common::TOKEN)));
+}
+
+#[test]
+fn reaper_closes_stale_tabs_but_not_leased_ones() {
+    if !chromium_available() {
+        return;
+    }
+    let fixtures = FixtureServer::start();
+    let manager = Manager::start(json!({ "reaper": { "same_url_timeout_secs": 2 } }));
+
+    let (status, lease) = manager.control(
+        "POST",
+        "/leases",
+        json!({ "url": fixtures.url("/leased"), "ttl_secs": 60 }),
+    );
+    assert_eq!(status, 201, "{}", lease);
+    let leased_tab = lease["tab_id"].as_str().unwrap().to_string();
+    let stale_tab = manager.open_unmanaged_tab(&fixtures.url("/stale"));
+
+    assert!(
+        wait_until(Duration::from_secs(20), || !manager
+            .page_ids()
+            .contains(&stale_tab)),
+        "stale tab was not reaped:\n{}",
+        manager.log()
+    );
+    assert!(manager.page_ids().contains(&leased_tab));
+}
+
+#[test]
+fn rotation_focuses_every_eligible_tab() {
+    if !chromium_available() {
+        return;
+    }
+    let fixtures = FixtureServer::start();
+    let manager =
+        Manager::start(json!({ "rotation": { "policy": "round-robin", "dwell_ms": 200 } }));
+
+    let first = manager.open_unmanaged_tab(&fixtures.url("/one"));
+    let second = manager.open_unmanaged_tab(&fixtures.url("/two"));
+    assert!(
+        wait_until(Duration::from_secs(15), || {
+            let log = manager.log();
+            log.contains(&format!("Focusing tab {}", first))
+                && log.contains(&format!("Focusing tab {}", second))
+        }),
+        "tabs were not both focused:\n{}",
+        manager.log()
+    );
+}
+
+#[test]
+fn sigterm_snapshots_tabs_and_restore_reopens_them() {
+    if !chromium_available() {
+        return;
+    }
+    let fixtures = FixtureServer::start();
+    let state_dir = tempfile::tempdir().unwrap();
+    let state = json!({
+        "state": { "path": state_dir.path().join("state.json"), "restore": "all" }
+    });
+
+    let mut manager = Manager::start(state.clone());
+    manager.open_unmanaged_tab(&fixtures.url("/kept"));
+    assert!(wait_until(Duration::from_secs(10), || fixtures.was_requested("/kept")));
+    // Give the main loop a pass to pick the tab up
+    std::thread::sleep(Duration::from_secs(1));
+
+    let status = manager
+        .terminate()
+        .expect("manager did not exit on SIGTERM");
+    assert!(status.success(), "{:?}\n{}", status, manager.log());
+    assert!(manager.devtools_get("/json/version").is_none());
+    let snapshot = fs::read_to_string(state_dir.path().join("state.json")).unwrap();
+    assert!(snapshot.contains("/kept"), "{}", snapshot);
+
+    fixtures.requests.lock().unwrap().clear();
+    let restarted = Manager::start(state);
+    assert!(
+        wait_until(Duration::from_secs(15), || fixtures.was_requested("/kept")),
+        "tab was not restored:\n{}",
+        restarted.log()
+    );
+}
@@ -0,0 +1,692 @@
+// lib.rs
+//
+// An in-process stand-in for Chromium's DevTools endpoint: the HTTP `/json` routes and enough
+// of the websocket protocol for headless_chrome to connect, attach to tabs and drive them.
+// Tests script the target list, URL changes, crashes and slow replies, then check the calls
+// that were made.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// A DevTools method call the mock received.
+#[derive(Debug, Clone)]
+pub struct Call {
+    /// The tab the call was sent to, `None` for browser-level calls.
+    pub target_id: Option<String>,
+    pub method: String,
+    pub params: Value,
+}
+
+type Reply = Result<Value, String>;
+
+/// What `navigator.userAgent` evaluates to until a tab overrides it.
+pub const HEADLESS_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
+    (KHTML, like Gecko) HeadlessChrome/124.0.0.0 Safari/537.36";
+
+struct Target {
+    id: String,
+    url: String,
+    ready_state: String,
+    user_agent: String,
+    browser_context_id: String,
+    crashed: bool,
+}
+
+impl Target {
+    fn info(&self) -> Value {
+        json!({
+            "targetId": self.id,
+            "type": "page",
+            "title": self.url,
+            "url": self.url,
+            "attached": true,
+            "canAccessOpener": false,
+            "browserContextId": self.browser_context_id,
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    targets: Vec<Target>,
+    next_id: u64,
+    /// Connections that turned on target discovery and get `targetCreated` and friends.
+    discovering: Vec<Sender<String>>,
+    /// Session id to the target it is attached to and the connection that attached.
+    sessions: HashMap<String, (String, Sender<String>)>,
+    /// Replies that replace the built-in ones, by target (`None` for any) and method.
+    scripted: HashMap<(Option<String>, String), Reply>,
+    delays: HashMap<String, Duration>,
+    calls: Vec<Call>,
+    closed: bool,
+}
+
+impl State {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}-{}", prefix, self.next_id)
+    }
+
+    fn target(&mut self, target_id: &str) -> Option<&mut Target> {
+        self.targets
+            .iter_mut()
+            .find(|target| target.id == target_id)
+    }
+
+    fn broadcast(&mut self, method: &str, params: Value) {
+        let event = json!({ "method": method, "params": params }).to_string();
+        self.discovering.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Sends an event to every session attached to `target_id`.
+    fn send_to_sessions(&self, target_id: &str, method: &str, params: Value) {
+        let message = json!({ "method": method, "params": params }).to_string();
+        for (session_id, (target, tx)) in &self.sessions {
+            if target == target_id {
+                let _ = tx.send(wrap_for_session(session_id, target, &message));
+            }
+        }
+    }
+
+    fn open(&mut self, url: &str, browser_context_id: Option<&str>) -> String {
+        let id = self.next_id("target");
+        self.targets.push(Target {
+            id: id.clone(),
+            url: url.to_string(),
+            ready_state: String::from("complete"),
+            user_agent: String::from(HEADLESS_USER_AGENT),
+            browser_context_id: browser_context_id.unwrap_or("default-context").to_string(),
+            crashed: false,
+        });
+        let info = self.targets.last().unwrap().info();
+        self.broadcast("Target.targetCreated", json!({ "targetInfo": info }));
+        id
+    }
+
+    fn close(&mut self, target_id: &str) -> bool {
+        let before = self.targets.len();
+        self.targets.retain(|target| target.id != target_id);
+        if self.targets.len() == before {
+            return false;
+        }
+        self.sessions.retain(|_, (target, _)| target != target_id);
+        self.broadcast("Target.targetDestroyed", json!({ "targetId": target_id }));
+        true
+    }
+
+    fn navigate(&mut self, target_id: &str, url: &str) {
+        let Some(target) = self.target(target_id) else {
+            return;
+        };
+        target.url = url.to_string();
+        let info = target.info();
+        self.broadcast("Target.targetInfoChanged", json!({ "targetInfo": info }));
+        // headless_chrome treats this as the end of a navigation
+        self.send_to_sessions(
+            target_id,
+            "Page.lifecycleEvent",
+            json!({
+                "frameId": target_id,
+                "loaderId": "loader",
+                "name": "networkAlmostIdle",
+                "timestamp": 0.0,
+            }),
+        );
+    }
+
+    fn http_target(&self, target: &Target, port: u16) -> Value {
+        json!({
+            "id": target.id,
+            "type": "page",
+            "title": target.url,
+            "url": target.url,
+            "webSocketDebuggerUrl": format!("ws://127.0.0.1:{}/devtools/page/{}", port, target.id),
+        })
+    }
+}
+
+/// A fake DevTools endpoint on a random local port, served from background threads.
+///
+/// It starts with no tabs. Tabs opened through it, or through DevTools calls, are announced to
+/// every connection that turned on target discovery, as Chromium does.
+pub struct MockDevTools {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockDevTools {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&shared);
+                thread::spawn(move || serve(stream, port, &state));
+            }
+        });
+        MockDevTools { port, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Base URL of the HTTP endpoint, e.g. for `BROWSER_DEVTOOLS_URL`.
+    pub fn http_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// The browser-level websocket, as advertised on `/json/version`.
+    pub fn ws_url(&self) -> String {
+        format!("ws://127.0.0.1:{}/devtools/browser/mock", self.port)
+    }
+
+    /// Opens a tab on `url` and returns its target id.
+    pub fn open_target(&self, url: &str) -> String {
+        self.state.lock().unwrap().open(url, None)
+    }
+
+    /// Closes a tab as if the user had, announcing `targetDestroyed`.
+    pub fn close_target(&self, target_id: &str) {
+        self.state.lock().unwrap().close(target_id);
+    }
+
+    /// Moves a tab to `url` as if it had navigated by itself.
+    pub fn set_url(&self, target_id: &str, url: &str) {
+        self.state.lock().unwrap().navigate(target_id, url);
+    }
+
+    /// What `document.readyState` evaluates to in the tab; `complete` by default.
+    pub fn set_ready_state(&self, target_id: &str, ready_state: &str) {
+        if let Some(target) = self.state.lock().unwrap().target(target_id) {
+            target.ready_state = ready_state.to_string();
+        }
+    }
+
+    /// Crashes the tab's renderer. Calls to it fail until it is reloaded or closed.
+    pub fn crash(&self, target_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(target) = state.target(target_id) {
+            target.crashed = true;
+            state.send_to_sessions(target_id, "Inspector.targetCrashed", json!({}));
+            state.broadcast(
+                "Target.targetCrashed",
+                json!({ "targetId": target_id, "status": "crashed", "errorCode": 139 }),
+            );
+        }
+    }
+
+    /// Sends the event `method` to every session attached to `target_id`.
+    pub fn emit(&self, target_id: &str, method: &str, params: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .send_to_sessions(target_id, method, params);
+    }
+
+    /// Answers `method` with `result` instead of the built-in reply, on every tab.
+    pub fn respond(&self, method: &str, result: Value) {
+        self.script(None, method, Ok(result));
+    }
+
+    /// Answers `method` with `result` on one tab only.
+    pub fn respond_on(&self, target_id: &str, method: &str, result: Value) {
+        self.script(Some(target_id), method, Ok(result));
+    }
+
+    /// Answers `method` with a DevTools error carrying `message`.
+    pub fn fail(&self, method: &str, message: &str) {
+        self.script(None, method, Err(message.to_string()));
+    }
+
+    fn script(&self, target_id: Option<&str>, method: &str, reply: Reply) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .insert((target_id.map(String::from), method.to_string()), reply);
+    }
+
+    /// Holds back replies to `method` for `delay`, without blocking other calls.
+    pub fn delay(&self, method: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .delays
+            .insert(method.to_string(), delay);
+    }
+
+    /// Every call received so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// The calls to `method`, oldest first.
+    pub fn calls_to(&self, method: &str) -> Vec<Call> {
+        self.calls()
+            .into_iter()
+            .filter(|call| call.method == method)
+            .collect()
+    }
+
+    pub fn target_ids(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .targets
+            .iter()
+            .map(|target| target.id.clone())
+            .collect()
+    }
+
+    pub fn target_url(&self, target_id: &str) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .target(target_id)
+            .map(|target| target.url.clone())
+    }
+}
+
+impl Drop for MockDevTools {
+    /// Hangs up every websocket so clients see the browser go away.
+    fn drop(&mut self) {
+        self.state.lock().unwrap().closed = true;
+    }
+}
+
+/// Polls `check` every 10ms until it returns true or `timeout` passes.
+pub fn wait_until(timeout: Duration, mut check: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if check() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    check()
+}
+
+fn serve(stream: TcpStream, port: u16, state: &Arc<Mutex<State>>) {
+    let Some(head) = peek_head(&stream) else {
+        return;
+    };
+    if head.to_ascii_lowercase().contains("upgrade: websocket") {
+        serve_websocket(stream, state);
+    } else {
+        serve_http(stream, &head, port, state);
+    }
+}
+
+/// Looks at the request head without consuming it, so the websocket handshake can read it.
+fn peek_head(stream: &TcpStream) -> Option<String> {
+    let mut buffer = [0u8; 8192];
+    let started = Instant::now();
+    loop {
+        let read = stream.peek(&mut buffer).ok()?;
+        let head = String::from_utf8_lossy(&buffer[..read]);
+        if let Some(end) = head.find("\r\n\r\n") {
+            return Some(head[..end + 4].to_string());
+        }
+        if read == 0 || read == buffer.len() || started.elapsed() > Duration::from_secs(5) {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn serve_http(mut stream: TcpStream, head: &str, port: u16, state: &Mutex<State>) {
+    let mut consumed = vec![0u8; head.len()];
+    if stream.read_exact(&mut consumed).is_err() {
+        return;
+    }
+    let verb = head.split_whitespace().next().unwrap_or("GET");
+    let target = head.split_whitespace().nth(1).unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut state = state.lock().unwrap();
+    let (status, body) = match path {
+        "/json/version" => (
+            "200 OK",
+            json!({
+                "Browser": "HeadlessChrome/124.0.0.0",
+                "Protocol-Version": "1.3",
+                "webSocketDebuggerUrl": format!("ws://127.0.0.1:{}/devtools/browser/mock", port),
+            })
+            .to_string(),
+        ),
+        "/json" | "/json/list" => (
+            "200 OK",
+            Value::from(
+                state
+                    .targets
+                    .iter()
+                    .map(|target| state.http_target(target, port))
+                    .collect::<Vec<_>>(),
+            )
+            .to_string(),
+        ),
+        // Chrome 111 and later refuse to open tabs on a GET.
+        "/json/new" if verb != "PUT" => (
+            "405 Method Not Allowed",
+            String::from("Using unsafe HTTP verb GET to invoke /json/new. This action supports only PUT verb."),
+        ),
+        "/json/new" => {
+            let url = if query.is_empty() {
+                "about:blank"
+            } else {
+                query
+            };
+            let id = state.open(url, None);
+            let target = state.targets.iter().find(|target| target.id == id).unwrap();
+            ("200 OK", state.http_target(target, port).to_string())
+        }
+        _ => match path.rsplit_once('/') {
+            Some(("/json/close", id)) if state.close(id) => {
+                ("200 OK", String::from("Target is closing"))
+            }
+            Some(("/json/activate", id)) if state.target(id).is_some() => {
+                ("200 OK", String::from("Target activated"))
+            }
+            _ => ("404 Not Found", String::from("Unknown route")),
+        },
+    };
+    drop(state);
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+fn serve_websocket(stream: TcpStream, state: &Mutex<State>) {
+    let _ = stream.set_nodelay(true);
+    let Ok(mut socket) = tungstenite::accept(stream) else {
+        return;
+    };
+    // Short reads so queued events and delayed replies go out promptly
+    let _ = socket
+        .get_mut()
+        .set_read_timeout(Some(Duration::from_millis(5)));
+    let (tx, rx) = mpsc::channel::<String>();
+    loop {
+        if state.lock().unwrap().closed {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+            return;
+        }
+        while let Ok(outgoing) = rx.try_recv() {
+            if socket.send(Message::text(outgoing)).is_err() {
+                return;
+            }
+        }
+        match socket.read() {
+            Ok(Message::Text(text)) => handle_message(&text, &tx, state),
+            Ok(Message::Close(_)) => return,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Answers one message from a client, wrapping tab replies the way `sendMessageToTarget` does.
+fn handle_message(text: &str, tx: &Sender<String>, state: &Mutex<State>) {
+    let Ok(message) = serde_json::from_str::<Value>(text) else {
+        return;
+    };
+    let id = message["id"].clone();
+    let method = message["method"].as_str().unwrap_or_default();
+    let params = &message["params"];
+    let mut state = state.lock().unwrap();
+
+    if method == "Target.sendMessageToTarget" {
+        let session_id = params["sessionId"].as_str().unwrap_or_default().to_string();
+        let _ = tx.send(json!({ "id": id, "result": {} }).to_string());
+        let Some((target_id, _)) = state.sessions.get(&session_id).cloned() else {
+            return;
+        };
+        let Ok(inner) = serde_json::from_str::<Value>(params["message"].as_str().unwrap_or("{}"))
+        else {
+            return;
+        };
+        let inner_method = inner["method"].as_str().unwrap_or_default();
+        let reply = call(
+            &mut state,
+            tx,
+            Some(&target_id),
+            inner_method,
+            &inner["params"],
+        );
+        let response = response(&inner["id"], reply).to_string();
+        let delay = state.delays.get(inner_method).copied();
+        send_later(
+            tx,
+            wrap_for_session(&session_id, &target_id, &response),
+            delay,
+        );
+    } else {
+        let reply = call(&mut state, tx, None, method, params);
+        let delay = state.delays.get(method).copied();
+        send_later(tx, response(&id, reply).to_string(), delay);
+    }
+}
+
+fn response(id: &Value, reply: Reply) -> Value {
+    match reply {
+        Ok(result) => json!({ "id": id, "result": result }),
+        Err(message) => json!({ "id": id, "error": { "code": -32000, "message": message } }),
+    }
+}
+
+fn wrap_for_session(session_id: &str, target_id: &str, message: &str) -> String {
+    json!({
+        "method": "Target.receivedMessageFromTarget",
+        "params": { "sessionId": session_id, "message": message, "targetId": target_id },
+    })
+    .to_string()
+}
+
+fn send_later(tx: &Sender<String>, message: String, delay: Option<Duration>) {
+    match delay {
+        Some(delay) => {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                thread::sleep(delay);
+                let _ = tx.send(message);
+            });
+        }
+        None => {
+            let _ = tx.send(message);
+        }
+    }
+}
+
+/// Records a call and works out its reply: scripted if there is one, otherwise built in.
+fn call(
+    state: &mut State,
+    tx: &Sender<String>,
+    target_id: Option<&str>,
+    method: &str,
+    params: &Value,
+) -> Reply {
+    state.calls.push(Call {
+        target_id: target_id.map(String::from),
+        method: method.to_string(),
+        params: params.clone(),
+    });
+    if let Some(target_id) = target_id {
+        let crashed = state.target(target_id).is_some_and(|target| target.crashed);
+        if crashed && method != "Page.reload" && method != "Page.close" {
+            return Err(String::from("Target crashed"));
+        }
+    }
+    let scripted = target_id
+        .and_then(|target_id| {
+            state
+                .scripted
+                .get(&(Some(target_id.to_string()), method.to_string()))
+        })
+        .or_else(|| state.scripted.get(&(None, method.to_string())));
+    if let Some(reply) = scripted {
+        return reply.clone();
+    }
+    match target_id {
+        // Target methods also work through a tab's session, as in Chromium
+        Some(target_id) if !method.starts_with("Target.") => {
+            tab_call(state, target_id, method, params)
+        }
+        _ => browser_call(state, tx, method, params),
+    }
+}
+
+fn browser_call(state: &mut State, tx: &Sender<String>, method: &str, params: &Value) -> Reply {
+    let target_id = params["targetId"].as_str().unwrap_or_default();
+    match method {
+        "Target.setDiscoverTargets" => {
+            if params["discover"] == true {
+                for target in &state.targets {
+                    let event = json!({
+                        "method": "Target.targetCreated",
+                        "params": { "targetInfo": target.info() },
+                    });
+                    let _ = tx.send(event.to_string());
+                }
+                state.discovering.push(tx.clone());
+            }
+            Ok(json!({}))
+        }
+        "Target.getTargets" => Ok(json!({
+            "targetInfos": state.targets.iter().map(Target::info).collect::<Vec<_>>(),
+        })),
+        "Target.attachToTarget" => {
+            if state.target(target_id).is_none() {
+                return Err(String::from("No target with given id found"));
+            }
+            let session_id = state.next_id("session");
+            state
+                .sessions
+                .insert(session_id.clone(), (target_id.to_string(), tx.clone()));
+            Ok(json!({ "sessionId": session_id }))
+        }
+        "Target.createTarget" => {
+            let url = params["url"].as_str().unwrap_or("about:blank");
+            let id = state.open(url, params["browserContextId"].as_str());
+            Ok(json!({ "targetId": id }))
+        }
+        "Target.closeTarget" => {
+            if state.close(target_id) {
+                Ok(json!({ "success": true }))
+            } else {
+                Err(String::from("No target with given id found"))
+            }
+        }
+        "Target.activateTarget" => match state.target(target_id) {
+            Some(_) => Ok(json!({})),
+            None => Err(String::from("No target with given id found")),
+        },
+        "Target.createBrowserContext" => {
+            Ok(json!({ "browserContextId": state.next_id("context") }))
+        }
+        "Target.disposeBrowserContext" => {
+            let context_id = params["browserContextId"].as_str().unwrap_or_default();
+            let in_context: Vec<String> = state
+                .targets
+                .iter()
+                .filter(|target| target.browser_context_id == context_id)
+                .map(|target| target.id.clone())
+                .collect();
+            for target_id in in_context {
+                state.close(&target_id);
+            }
+            Ok(json!({}))
+        }
+        "Browser.getVersion" => Ok(json!({
+            "protocolVersion": "1.3",
+            "product": "HeadlessChrome/124.0.0.0",
+            "revision": "",
+            "userAgent": "",
+            "jsVersion": "",
+        })),
+        _ => Ok(json!({})),
+    }
+}
+
+fn tab_call(state: &mut State, target_id: &str, method: &str, params: &Value) -> Reply {
+    match method {
+        "Page.navigate" => {
+            let url = params["url"].as_str().unwrap_or_default().to_string();
+            state.navigate(target_id, &url);
+            Ok(json!({ "frameId": target_id, "loaderId": "loader" }))
+        }
+        "Page.reload" => {
+            let url = match state.target(target_id) {
+                Some(target) => {
+                    target.crashed = false;
+                    target.url.clone()
+                }
+                None => return Err(String::from("No target with given id found")),
+            };
+            state.navigate(target_id, &url);
+            Ok(json!({}))
+        }
+        "Page.close" => {
+            state.close(target_id);
+            Ok(json!({}))
+        }
+        "Runtime.evaluate" => {
+            let target = state.target(target_id);
+            let value = match params["expression"].as_str() {
+                Some("document.readyState") => target.map(|target| target.ready_state.clone()),
+                Some("navigator.userAgent") => target.map(|target| target.user_agent.clone()),
+                _ => None,
+            };
+            match value {
+                Some(value) => Ok(json!({ "result": { "type": "string", "value": value } })),
+                None => Ok(json!({ "result": { "type": "undefined" } })),
+            }
+        }
+        "Network.setUserAgentOverride" | "Emulation.setUserAgentOverride" => {
+            if let (Some(target), Some(user_agent)) =
+                (state.target(target_id), params["userAgent"].as_str())
+            {
+                target.user_agent = user_agent.to_string();
+            }
+            Ok(json!({}))
+        }
+        "Page.addScriptToEvaluateOnNewDocument" => {
+            Ok(json!({ "identifier": state.next_id("script") }))
+        }
+        "Runtime.callFunctionOn" => Ok(json!({ "result": { "type": "undefined" } })),
+        "DOM.getDocument" => Ok(json!({ "root": node(1, "#document", 9) })),
+        "DOM.querySelector" => Ok(json!({ "nodeId": 2 })),
+        "DOM.describeNode" => Ok(json!({ "node": node(2, "BODY", 1) })),
+        "DOM.resolveNode" => Ok(json!({
+            "object": { "type": "object", "subtype": "node", "objectId": "node-2" },
+        })),
+        _ => Ok(json!({})),
+    }
+}
+
+fn node(id: u32, name: &str, node_type: u32) -> Value {
+    json!({
+        "nodeId": id,
+        "backendNodeId": id,
+        "nodeType": node_type,
+        "nodeName": name,
+        "localName": name.to_ascii_lowercase(),
+        "nodeValue": "",
+        "attributes": [],
+    })
+}
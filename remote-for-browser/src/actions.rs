@@ -0,0 +1,386 @@
+// actions.rs
+
+use crate::browser::JobTab;
+use crate::utils::log_message;
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Sets an input's or textarea's value through the native setter, so frameworks that track
+/// the value see the change, then fires the events typing would.
+const FILL: &str = "function(value) {
+    if (!('value' in this)) return 'is not an input or textarea';
+    const setter = Object.getOwnPropertyDescriptor(Object.getPrototypeOf(this), 'value')?.set;
+    this.focus();
+    setter ? setter.call(this, value) : (this.value = value);
+    this.dispatchEvent(new Event('input', { bubbles: true }));
+    this.dispatchEvent(new Event('change', { bubbles: true }));
+}";
+
+/// Picks the option with this value, or else this label.
+const SELECT: &str = "function(value) {
+    if (this.tagName !== 'SELECT') return 'is not a select';
+    const options = [...this.options];
+    const option = options.find(o => o.value === value) || options.find(o => o.text.trim() === value);
+    if (!option) return 'has no such option';
+    option.selected = true;
+    this.dispatchEvent(new Event('input', { bubbles: true }));
+    this.dispatchEvent(new Event('change', { bubbles: true }));
+}";
+
+/// Clicks a checkbox or radio button if it isn't already in the wanted state, so the page's
+/// own handlers run.
+const CHECK: &str = "function(checked) {
+    if (this.type !== 'checkbox' && this.type !== 'radio') return 'is not a checkbox or radio button';
+    if (this.checked !== checked) this.click();
+    if (this.checked !== checked) return 'did not change';
+}";
+
+const CLICK: &str = "function() { this.click(); }";
+
+/// A step the job takes on the page once it has loaded. Values and file paths may use
+/// `{{name}}` for a job variable and `{{env.NAME}}` for an environment variable.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Types `value` into an input or textarea.
+    Fill {
+        selector: String,
+        value: String,
+    },
+    /// Picks an option of a select by its value or label.
+    Select {
+        selector: String,
+        value: String,
+    },
+    /// Ticks, or with `checked: false` clears, a checkbox or radio button.
+    Check {
+        selector: String,
+        #[serde(default = "default_checked")]
+        checked: bool,
+    },
+    /// Attaches local files to an `<input type=file>`.
+    Upload {
+        selector: String,
+        files: Vec<String>,
+    },
+    Click {
+        selector: String,
+    },
+}
+
+fn default_checked() -> bool {
+    true
+}
+
+/// The `--actions` file.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ActionFile {
+    /// Job variables, for what is fine to keep in the file. `--var` overrides them.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    pub actions: Vec<Action>,
+    /// Relative upload paths are taken from here: the file's directory.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+/// Reads an action file.
+pub fn load_actions(path: &str) -> Result<ActionFile> {
+    let data = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let mut file: ActionFile =
+        serde_json::from_str(&data).with_context(|| format!("Invalid action file {}", path))?;
+    file.base_dir = Path::new(path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    Ok(file)
+}
+
+/// Splits a `--var name=value` argument.
+pub fn parse_var(arg: &str) -> Result<(String, String)> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(anyhow!("--var wants name=value, got {}", arg)),
+    }
+}
+
+/// Fills in the `{{...}}` placeholders of `template`. Fails on an unknown name, naming it but
+/// never a value.
+fn render(
+    template: &str,
+    vars: &HashMap<String, String>,
+    env_var: &impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("Unclosed {{{{ in {}", template))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = match name.strip_prefix("env.") {
+            Some(name) => {
+                env_var(name).ok_or_else(|| anyhow!("Environment variable {} is not set", name))?
+            }
+            None => vars
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Job variable {} is not defined", name))?,
+        };
+        rendered.push_str(&value);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+impl ActionFile {
+    /// The actions with their placeholders filled in from `vars`, the file's variables and
+    /// the environment, and upload paths made absolute. Checked before the job navigates, so
+    /// a missing variable or file fails it early.
+    pub fn resolve(&self, vars: &HashMap<String, String>) -> Result<Vec<Action>> {
+        let mut all_vars = self.vars.clone();
+        all_vars.extend(vars.clone());
+        let env_var = |name: &str| env::var(name).ok();
+        self.actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| {
+                let render = |template: &str| {
+                    render(template, &all_vars, &env_var)
+                        .map_err(|err| anyhow!("Action {}: {}", index + 1, err))
+                };
+                let action = match action {
+                    Action::Fill { selector, value } => Action::Fill {
+                        selector: selector.clone(),
+                        value: render(value)?,
+                    },
+                    Action::Select { selector, value } => Action::Select {
+                        selector: selector.clone(),
+                        value: render(value)?,
+                    },
+                    Action::Upload { selector, files } => Action::Upload {
+                        selector: selector.clone(),
+                        files: files
+                            .iter()
+                            .map(|file| {
+                                let path = self.base_dir.join(render(file)?);
+                                // Chromium reads the files itself, so it needs full paths
+                                fs::canonicalize(&path).map_err(|err| {
+                                    anyhow!(
+                                        "Action {}: cannot upload {}: {}",
+                                        index + 1,
+                                        path.display(),
+                                        err
+                                    )
+                                })
+                            })
+                            .map(|path| Ok(path?.to_string_lossy().into_owned()))
+                            .collect::<Result<_>>()?,
+                    },
+                    other => other.clone(),
+                };
+                Ok(action)
+            })
+            .collect()
+    }
+}
+
+/// Refuses `upload` steps unless the browser at `devtools_url` runs on this host: Chromium reads
+/// the files from its own filesystem, where the paths checked here may not exist or may be other
+/// files. Only a plain `http://` or `ws://` URL on a loopback address counts as this host.
+pub fn check_uploads(actions: &[Action], devtools_url: &str) -> Result<()> {
+    if !actions
+        .iter()
+        .any(|action| matches!(action, Action::Upload { .. }))
+    {
+        return Ok(());
+    }
+    let local = Url::parse(devtools_url).is_ok_and(|url| {
+        let host = url.host_str().unwrap_or_default();
+        let loopback = host.eq_ignore_ascii_case("localhost")
+            || host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok_and(|address| address.is_loopback());
+        matches!(url.scheme(), "http" | "ws") && loopback
+    });
+    if local {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "upload actions need the browser on this host, not behind {}",
+            devtools_url
+        ))
+    }
+}
+
+/// Carries out `actions` in order on the loaded page, stopping at the first that fails.
+/// Logs each step by its selector only, so filled-in values stay out of the logs.
+pub fn run_actions<T: JobTab + ?Sized>(tab: &T, actions: &[Action]) -> Result<()> {
+    for (index, action) in actions.iter().enumerate() {
+        let (verb, selector) = match action {
+            Action::Fill { selector, .. } => ("Filling", selector),
+            Action::Select { selector, .. } => ("Selecting in", selector),
+            Action::Check { selector, checked } if *checked => ("Checking", selector),
+            Action::Check { selector, .. } => ("Unchecking", selector),
+            Action::Upload { selector, .. } => ("Attaching files to", selector),
+            Action::Click { selector } => ("Clicking", selector),
+        };
+        log_message(&format!("{} {}", verb, selector), "INFO");
+        let outcome = match action {
+            Action::Fill { selector, value } => {
+                tab.call_on_element(selector, FILL, vec![json!(value)])
+            }
+            Action::Select { selector, value } => {
+                tab.call_on_element(selector, SELECT, vec![json!(value)])
+            }
+            Action::Check { selector, checked } => {
+                tab.call_on_element(selector, CHECK, vec![json!(checked)])
+            }
+            Action::Upload { selector, files } => {
+                tab.set_input_files(selector, files).map(|_| Value::Null)
+            }
+            Action::Click { selector } => tab.call_on_element(selector, CLICK, Vec::new()),
+        };
+        match outcome {
+            // The page scripts report what is wrong with the element as a string
+            Ok(Value::String(problem)) => {
+                return Err(anyhow!(
+                    "Action {} failed: {} {}",
+                    index + 1,
+                    selector,
+                    problem
+                ))
+            }
+            Ok(_) => {}
+            Err(err) => {
+                return Err(anyhow!(
+                    "Action {} failed: {}: {}",
+                    index + 1,
+                    selector,
+                    err
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn placeholders_come_from_job_variables_or_the_environment() {
+        let env_var = |name: &str| (name == "SITE_PASSWORD").then(|| String::from("hunter2"));
+        let vars = vars(&[("user", "bot@example.com")]);
+
+        assert_eq!(
+            render("{{ user }}:{{env.SITE_PASSWORD}}!", &vars, &env_var).unwrap(),
+            "bot@example.com:hunter2!"
+        );
+        assert_eq!(render("plain", &vars, &env_var).unwrap(), "plain");
+        let err = render("{{env.MISSING}}", &vars, &env_var).unwrap_err();
+        assert_eq!(err.to_string(), "Environment variable MISSING is not set");
+        assert!(render("{{nobody}}", &vars, &env_var).is_err());
+        assert!(render("{{user", &vars, &env_var).is_err());
+    }
+
+    #[test]
+    fn action_files_resolve_variables_and_upload_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("invoice.pdf"), "%PDF").unwrap();
+        let path = dir.path().join("job.json");
+        fs::write(
+            &path,
+            json!({
+                "vars": { "user": "from-file", "plan": "pro" },
+                "actions": [
+                    { "action": "fill", "selector": "#email", "value": "{{user}}" },
+                    { "action": "select", "selector": "#plan", "value": "{{plan}}" },
+                    { "action": "check", "selector": "#terms" },
+                    { "action": "upload", "selector": "#invoice", "files": ["invoice.pdf"] },
+                ],
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let file = load_actions(path.to_str().unwrap()).unwrap();
+        let actions = file.resolve(&vars(&[("user", "from-cli")])).unwrap();
+        assert_eq!(
+            actions[0],
+            Action::Fill {
+                selector: String::from("#email"),
+                value: String::from("from-cli"),
+            }
+        );
+        assert_eq!(
+            actions[2],
+            Action::Check {
+                selector: String::from("#terms"),
+                checked: true,
+            }
+        );
+        let invoice = fs::canonicalize(dir.path().join("invoice.pdf")).unwrap();
+        assert_eq!(
+            actions[3],
+            Action::Upload {
+                selector: String::from("#invoice"),
+                files: vec![invoice.to_string_lossy().into_owned()],
+            }
+        );
+
+        fs::remove_file(dir.path().join("invoice.pdf")).unwrap();
+        assert!(file.resolve(&HashMap::new()).is_err());
+        assert_eq!(
+            parse_var("user=a=b").unwrap(),
+            (String::from("user"), String::from("a=b"))
+        );
+        assert!(parse_var("=x").is_err());
+    }
+
+    #[test]
+    fn uploads_need_the_browser_on_this_host() {
+        let upload = [Action::Upload {
+            selector: String::from("#invoice"),
+            files: vec![String::from("/tmp/invoice.pdf")],
+        }];
+        for local in [
+            "http://localhost:9222",
+            "http://127.0.0.1:9222",
+            "ws://[::1]:9222/x",
+        ] {
+            assert!(check_uploads(&upload, local).is_ok(), "{}", local);
+        }
+        for remote in [
+            "https://browser-host:9443",
+            "wss://127.0.0.1:9443",
+            "http://10.0.0.5:9222",
+            "not a url",
+        ] {
+            assert!(check_uploads(&upload, remote).is_err(), "{}", remote);
+        }
+        let click = [Action::Click {
+            selector: String::from("#go"),
+        }];
+        assert!(check_uploads(&click, "https://browser-host:9443").is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+// browser.rs
+
+use crate::context::new_tab_in_context;
+use crate::session::{import_session, SessionData};
+use crate::utils::find_tab;
+use anyhow::Result;
+use headless_chrome::{Browser, Tab};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// What a job needs from its tab. Implemented for headless_chrome's `Tab`, which the unit
+/// tests connect to the mock DevTools endpoint instead of Chromium.
+pub trait JobTab {
+    /// Answers the tab's proxy auth challenges with these credentials.
+    fn authenticate_proxy(&self, username: Option<String>, password: Option<String>) -> Result<()>;
+    fn set_user_agent(
+        &self,
+        user_agent: &str,
+        accept_language: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<()>;
+    /// Seeds the tab with a saved session; see `session::import_session`.
+    fn import_session(&self, session: &SessionData) -> Result<()>;
+    fn navigate(&self, url: &str) -> Result<()>;
+    /// Waits until the page has a `body`.
+    fn wait_for_body(&self) -> Result<()>;
+    /// Waits for the element matching `selector` and calls the JavaScript `function` on it
+    /// with `args`. Returns what the function returned, `Null` for nothing.
+    fn call_on_element(&self, selector: &str, function: &str, args: Vec<Value>) -> Result<Value>;
+    /// Attaches files, by absolute path, to the `<input type=file>` matching `selector`.
+    fn set_input_files(&self, selector: &str, files: &[String]) -> Result<()>;
+    fn close(&self) -> Result<()>;
+}
+
+/// What a job needs from the browser.
+pub trait JobBrowser {
+    type Tab: JobTab;
+
+    /// Waits for the browser to report the tab with `target_id`.
+    fn find_tab(&self, target_id: &str) -> Result<Arc<Self::Tab>>;
+    /// Opens a blank tab, inside `context_id` when given.
+    fn new_tab_in_context(&self, context_id: Option<&str>) -> Result<Arc<Self::Tab>>;
+}
+
+impl JobTab for Tab {
+    fn authenticate_proxy(&self, username: Option<String>, password: Option<String>) -> Result<()> {
+        self.authenticate(username, password)?
+            .enable_fetch(None, Some(true))?;
+        Ok(())
+    }
+
+    fn set_user_agent(
+        &self,
+        user_agent: &str,
+        accept_language: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<()> {
+        Tab::set_user_agent(self, user_agent, accept_language, platform)
+    }
+
+    fn import_session(&self, session: &SessionData) -> Result<()> {
+        import_session(self, session)
+    }
+
+    fn navigate(&self, url: &str) -> Result<()> {
+        self.navigate_to(url)?;
+        Ok(())
+    }
+
+    fn wait_for_body(&self) -> Result<()> {
+        self.wait_for_element("body")?;
+        Ok(())
+    }
+
+    fn call_on_element(&self, selector: &str, function: &str, args: Vec<Value>) -> Result<Value> {
+        let result = self
+            .wait_for_element(selector)?
+            .call_js_fn(function, args, false)?;
+        Ok(result.value.unwrap_or(Value::Null))
+    }
+
+    fn set_input_files(&self, selector: &str, files: &[String]) -> Result<()> {
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+        self.wait_for_element(selector)?.set_input_files(&files)?;
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()> {
+        self.close_target()?;
+        Ok(())
+    }
+}
+
+impl JobBrowser for Browser {
+    type Tab = Tab;
+
+    fn find_tab(&self, target_id: &str) -> Result<Arc<Tab>> {
+        find_tab(self, target_id)
+    }
+
+    fn new_tab_in_context(&self, context_id: Option<&str>) -> Result<Arc<Tab>> {
+        new_tab_in_context(self, context_id)
+    }
+}
@@ -0,0 +1,54 @@
+// context.rs
+
+use crate::proxy::ProxySettings;
+use crate::utils::send_browser_command;
+use anyhow::{anyhow, Result};
+use headless_chrome::protocol::cdp::Target::CreateTarget;
+use headless_chrome::{Browser, Tab};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Creates an isolated browser context, optionally routed through its own proxy.
+///
+/// The context keeps its own cookies and storage, apart from the persistent profile.
+pub fn create_browser_context(proxy: Option<&ProxySettings>) -> Result<String> {
+    let mut params = json!({});
+    if let Some(proxy) = proxy {
+        params["proxyServer"] = json!(proxy.server);
+    }
+    let result = send_browser_command("Target.createBrowserContext", params)?;
+    result["browserContextId"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("createBrowserContext returned no browserContextId"))
+}
+
+/// Disposes a context created by `create_browser_context`, closing any tabs left in it.
+pub fn dispose_browser_context(context_id: &str) -> Result<()> {
+    send_browser_command(
+        "Target.disposeBrowserContext",
+        json!({ "browserContextId": context_id }),
+    )?;
+    Ok(())
+}
+
+/// Opens a blank tab, inside `context_id` when given.
+pub fn new_tab_in_context(browser: &Browser, context_id: Option<&str>) -> Result<Arc<Tab>> {
+    match context_id {
+        Some(context_id) => browser.new_tab_with_options(CreateTarget {
+            url: "about:blank".to_string(),
+            left: None,
+            top: None,
+            width: None,
+            height: None,
+            window_state: None,
+            browser_context_id: Some(context_id.to_string()),
+            enable_begin_frame_control: None,
+            new_window: None,
+            background: None,
+            for_tab: None,
+            hidden: None,
+        }),
+        None => browser.new_tab(),
+    }
+}
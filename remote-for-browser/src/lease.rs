@@ -0,0 +1,130 @@
+// lease.rs
+
+use crate::utils::{http_client, log_message};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A tab leased from browser-for-remote's control interface.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Lease {
+    pub lease_id: String,
+    pub tab_id: String,
+    pub ttl_secs: u64,
+    pub expires_at: String,
+}
+
+/// Base URL of the manager's control interface, overridable with `BROWSER_FOR_REMOTE_CONTROL`.
+pub fn control_url() -> String {
+    env::var("BROWSER_FOR_REMOTE_CONTROL").unwrap_or_else(|_| String::from("http://127.0.0.1:9223"))
+}
+
+/// Returns true when a manager is answering on the control interface.
+pub fn manager_available() -> bool {
+    http_client()
+        .get(format!("{}/leases", control_url()))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+/// Asks the manager for a fresh tab, inside `browser_context_id` when given and
+/// presenting the named `identity` profile.
+pub fn request_lease(
+    ttl_secs: u64,
+    focus: &str,
+    identity: Option<&str>,
+    browser_context_id: Option<&str>,
+) -> Result<Lease> {
+    let response = http_client()
+        .post(format!("{}/leases", control_url()))
+        .json(&json!({
+            "client": "remote-for-browser",
+            "ttl_secs": ttl_secs,
+            "focus": focus,
+            "identity": identity,
+            "browser_context_id": browser_context_id,
+        }))
+        .timeout(Duration::from_secs(40))
+        .send()?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Lease request refused ({}): {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        ));
+    }
+    Ok(response.json()?)
+}
+
+/// Ends the lease; the manager closes the tab.
+pub fn release_lease(lease_id: &str) -> Result<()> {
+    http_client()
+        .delete(format!("{}/leases/{}", control_url(), lease_id))
+        .timeout(Duration::from_secs(10))
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Renews a lease in the background until dropped.
+pub struct Heartbeat {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Renews `lease` every third of its TTL so a single missed beat doesn't lose the tab.
+    pub fn start(lease: &Lease) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let interval = Duration::from_secs((lease.ttl_secs / 3).max(1));
+        let url = format!("{}/leases/{}/renew", control_url(), lease.lease_id);
+        let thread_stop = Arc::clone(&stop);
+
+        let handle = thread::spawn(move || {
+            let client = http_client();
+            let mut waited = Duration::ZERO;
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(200));
+                waited += Duration::from_millis(200);
+                if waited < interval {
+                    continue;
+                }
+                waited = Duration::ZERO;
+                match client.post(&url).timeout(Duration::from_secs(10)).send() {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => {
+                        log_message(
+                            &format!("Lease renewal refused: {}", response.status()),
+                            "ERROR",
+                        );
+                        break;
+                    }
+                    Err(err) => {
+                        log_message(&format!("Lease renewal failed: {}", err), "WARN");
+                    }
+                }
+            }
+        });
+
+        Heartbeat {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,318 @@
+// session.rs
+
+use anyhow::{anyhow, Result};
+use headless_chrome::protocol::cdp::Network::{CookieParam, GetAllCookies, SetCookies};
+use headless_chrome::protocol::cdp::Page::AddScriptToEvaluateOnNewDocument;
+use headless_chrome::Tab;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// A single cookie as exported from, or imported into, a browser profile.
+///
+/// Field names follow the DevTools protocol so exported JSON can be fed
+/// straight back into `Network.setCookies`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionCookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+    /// Seconds since the epoch, or -1 for a session cookie.
+    pub expires: f64,
+    pub http_only: bool,
+    pub secure: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<String>,
+}
+
+/// Everything needed to move a logged-in session between hosts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionData {
+    /// Origin the storage maps were read from, e.g. `https://chatgpt.com`.
+    pub origin: Option<String>,
+    pub cookies: Vec<SessionCookie>,
+    pub local_storage: BTreeMap<String, String>,
+    pub session_storage: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionFormat {
+    Json,
+    Netscape,
+}
+
+impl SessionFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(SessionFormat::Json),
+            "netscape" | "cookies.txt" => Ok(SessionFormat::Netscape),
+            other => Err(anyhow!("Unknown session format: {}", other)),
+        }
+    }
+}
+
+/// Returns true when a cookie stored for `cookie_domain` would be sent to `domain`
+/// or one of its subdomains.
+fn cookie_matches_domain(cookie_domain: &str, domain: &str) -> bool {
+    let cookie_domain = cookie_domain.trim_start_matches('.');
+    let domain = domain.trim_start_matches('.');
+    cookie_domain == domain
+        || cookie_domain.ends_with(&format!(".{}", domain))
+        || domain.ends_with(&format!(".{}", cookie_domain))
+}
+
+/// Reads the cookies for `domain` plus the local and session storage of the
+/// tab's current origin.
+///
+/// The tab should already be on a page of `domain`, otherwise the storage
+/// maps will belong to whatever origin it is showing.
+pub fn export_session(tab: &Tab, domain: &str) -> Result<SessionData> {
+    let cookies = tab
+        .call_method(GetAllCookies(None))?
+        .cookies
+        .into_iter()
+        .filter(|cookie| cookie_matches_domain(&cookie.domain, domain))
+        .map(|cookie| serde_json::from_value(serde_json::to_value(cookie)?))
+        .collect::<Result<Vec<SessionCookie>, _>>()?;
+
+    let storage = tab.evaluate(
+        "JSON.stringify({ origin: location.origin, \
+         local: Object.assign({}, localStorage), \
+         session: Object.assign({}, sessionStorage) })",
+        false,
+    )?;
+    let storage: Value = match storage.value.as_ref().and_then(Value::as_str) {
+        Some(text) => serde_json::from_str(text)?,
+        None => return Err(anyhow!("Tab did not return its storage")),
+    };
+
+    Ok(SessionData {
+        origin: storage["origin"].as_str().map(String::from),
+        cookies,
+        local_storage: serde_json::from_value(storage["local"].clone()).unwrap_or_default(),
+        session_storage: serde_json::from_value(storage["session"].clone()).unwrap_or_default(),
+    })
+}
+
+/// Seeds a fresh tab with a previously exported session.
+///
+/// Must be called before the tab navigates: cookies are written straight into
+/// the tab's browser context and the storage maps are injected by a script
+/// that runs ahead of any page script on the matching origin.
+pub fn import_session(tab: &Tab, session: &SessionData) -> Result<()> {
+    if !session.cookies.is_empty() {
+        let cookies = session
+            .cookies
+            .iter()
+            .map(to_cookie_param)
+            .collect::<Result<Vec<_>>>()?;
+        tab.call_method(SetCookies { cookies })?;
+    }
+
+    if let Some(origin) = &session.origin {
+        if !session.local_storage.is_empty() || !session.session_storage.is_empty() {
+            tab.call_method(AddScriptToEvaluateOnNewDocument {
+                source: storage_seed_script(origin, session)?,
+                world_name: None,
+                include_command_line_api: None,
+                run_immediately: None,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn to_cookie_param(cookie: &SessionCookie) -> Result<CookieParam> {
+    let mut param = json!({
+        "name": cookie.name,
+        "value": cookie.value,
+        "domain": cookie.domain,
+        "path": cookie.path,
+        "httpOnly": cookie.http_only,
+        "secure": cookie.secure,
+    });
+    // Session cookies carry no expiry at all in Network.setCookies.
+    if cookie.expires > 0.0 {
+        param["expires"] = json!(cookie.expires);
+    }
+    if let Some(same_site) = &cookie.same_site {
+        param["sameSite"] = json!(same_site);
+    }
+    Ok(serde_json::from_value(param)?)
+}
+
+fn storage_seed_script(origin: &str, session: &SessionData) -> Result<String> {
+    Ok(format!(
+        "(function () {{\n\
+         if (location.origin !== {origin}) return;\n\
+         const seed = (store, items) => {{\n\
+         for (const [key, value] of Object.entries(items)) {{\n\
+         if (store.getItem(key) === null) store.setItem(key, value);\n\
+         }}\n\
+         }};\n\
+         seed(localStorage, {local});\n\
+         seed(sessionStorage, {session});\n\
+         }})();",
+        origin = serde_json::to_string(origin)?,
+        local = serde_json::to_string(&session.local_storage)?,
+        session = serde_json::to_string(&session.session_storage)?,
+    ))
+}
+
+/// Writes the session to `path`.
+///
+/// The Netscape format only has room for cookies, so storage is dropped.
+pub fn save_session(session: &SessionData, path: &str, format: SessionFormat) -> Result<()> {
+    let contents = match format {
+        SessionFormat::Json => serde_json::to_string_pretty(session)?,
+        SessionFormat::Netscape => to_netscape(&session.cookies),
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Loads a session written by `save_session`, detecting the format from its contents.
+pub fn load_session(path: &str) -> Result<SessionData> {
+    let contents = fs::read_to_string(path)?;
+    if contents.trim_start().starts_with('{') {
+        Ok(serde_json::from_str(&contents)?)
+    } else {
+        Ok(SessionData {
+            cookies: from_netscape(&contents)?,
+            ..SessionData::default()
+        })
+    }
+}
+
+fn to_netscape(cookies: &[SessionCookie]) -> String {
+    let mut lines = vec![String::from("# Netscape HTTP Cookie File")];
+    for cookie in cookies {
+        let domain = if cookie.http_only {
+            format!("#HttpOnly_{}", cookie.domain)
+        } else {
+            cookie.domain.clone()
+        };
+        lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            domain,
+            if cookie.domain.starts_with('.') {
+                "TRUE"
+            } else {
+                "FALSE"
+            },
+            cookie.path,
+            if cookie.secure { "TRUE" } else { "FALSE" },
+            if cookie.expires > 0.0 {
+                cookie.expires as i64
+            } else {
+                0
+            },
+            cookie.name,
+            cookie.value
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+fn from_netscape(contents: &str) -> Result<Vec<SessionCookie>> {
+    let mut cookies = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+            Some(rest) => (rest, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 {
+            return Err(anyhow!(
+                "Malformed cookies.txt line {}: expected 7 fields, found {}",
+                index + 1,
+                fields.len()
+            ));
+        }
+        let expires: f64 = fields[4].parse()?;
+        cookies.push(SessionCookie {
+            domain: fields[0].to_string(),
+            path: fields[2].to_string(),
+            secure: fields[3] == "TRUE",
+            expires: if expires > 0.0 { expires } else { -1.0 },
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+            http_only,
+            same_site: None,
+        });
+    }
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookie(name: &str, domain: &str, http_only: bool, expires: f64) -> SessionCookie {
+        SessionCookie {
+            name: name.to_string(),
+            value: format!("{}-value", name),
+            domain: domain.to_string(),
+            path: String::from("/"),
+            expires,
+            http_only,
+            secure: true,
+            same_site: None,
+        }
+    }
+
+    #[test]
+    fn netscape_round_trip_keeps_cookies_and_http_only() {
+        let cookies = vec![
+            cookie("sid", ".example.com", true, 1_900_000_000.0),
+            cookie("pref", "app.example.com", false, -1.0),
+        ];
+        let contents = to_netscape(&cookies);
+        assert!(contents.starts_with("# Netscape HTTP Cookie File\n"));
+        assert!(contents.contains("#HttpOnly_.example.com\tTRUE\t/\tTRUE\t1900000000\tsid"));
+
+        let parsed = from_netscape(&contents).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].domain, ".example.com");
+        assert!(parsed[0].http_only);
+        assert_eq!(parsed[0].expires, 1_900_000_000.0);
+        assert_eq!(parsed[1].value, "pref-value");
+        assert!(!parsed[1].http_only);
+        assert_eq!(parsed[1].expires, -1.0);
+
+        assert!(from_netscape("example.com\tFALSE\t/").is_err());
+    }
+
+    #[test]
+    fn json_sessions_round_trip_through_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let path = path.to_str().unwrap();
+        let session = SessionData {
+            origin: Some(String::from("https://app.example.com")),
+            cookies: vec![cookie("sid", ".example.com", true, -1.0)],
+            local_storage: BTreeMap::from([(String::from("theme"), String::from("dark"))]),
+            session_storage: BTreeMap::new(),
+        };
+
+        save_session(&session, path, SessionFormat::Json).unwrap();
+        let loaded = load_session(path).unwrap();
+        assert_eq!(loaded.origin, session.origin);
+        assert_eq!(loaded.cookies[0].name, "sid");
+        assert_eq!(loaded.local_storage["theme"], "dark");
+
+        // cookies.txt keeps only the cookies
+        save_session(&session, path, SessionFormat::Netscape).unwrap();
+        let loaded = load_session(path).unwrap();
+        assert_eq!(loaded.cookies.len(), 1);
+        assert!(loaded.local_storage.is_empty());
+        assert!(cookie_matches_domain(".example.com", "app.example.com"));
+    }
+}
@@ -1,6 +1,23 @@
 // utils.rs
 
-use chrono;
+use anyhow::{anyhow, Result};
+use headless_chrome::{Browser, Tab};
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::Certificate;
+use reqwest::Url;
+use rustls::crypto::ring;
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Connector, Message, WebSocket};
 
 /// Logs a message with the current date and time.
 ///
@@ -21,3 +38,220 @@ pub fn log_message(message: &str, level: &str) {
         message
     );
 }
+
+/// Base URL of the browser's DevTools HTTP endpoint, overridable with `BROWSER_DEVTOOLS_URL`.
+pub fn devtools_url() -> String {
+    env::var("BROWSER_DEVTOOLS_URL").unwrap_or_else(|_| String::from("http://localhost:9222"))
+}
+
+/// The manager's access token: `BROWSER_FOR_REMOTE_TOKEN`, else the contents of the file named
+/// by `BROWSER_FOR_REMOTE_TOKEN_FILE`, else of `~/.browser-for-remote-token` if there is one.
+pub fn auth_token() -> Option<String> {
+    if let Ok(token) = env::var("BROWSER_FOR_REMOTE_TOKEN") {
+        return Some(token);
+    }
+    let path = env::var("BROWSER_FOR_REMOTE_TOKEN_FILE").or_else(|_| {
+        env::var("HOME").map(|home_dir| format!("{}/.browser-for-remote-token", home_dir))
+    });
+    fs::read_to_string(path.ok()?)
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// The PEM certificate named by `BROWSER_FOR_REMOTE_CA`, trusted on top of the usual roots so
+/// a manager's gateway with a self-signed certificate can be reached over TLS.
+pub fn ca_cert() -> Option<Vec<u8>> {
+    let path = env::var("BROWSER_FOR_REMOTE_CA").ok()?;
+    match fs::read(&path) {
+        Ok(pem) => Some(pem),
+        Err(e) => {
+            log_message(&format!("Failed to read CA {}: {}", path, e), "WARN");
+            None
+        }
+    }
+}
+
+/// An HTTP client that presents the access token, when there is one, on every request.
+pub fn http_client() -> Client {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = auth_token() {
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token)) {
+            headers.insert(AUTHORIZATION, value);
+        }
+    }
+    let mut builder = Client::builder().default_headers(headers);
+    if let Some(certificate) = ca_cert().and_then(|pem| Certificate::from_pem(&pem).ok()) {
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder.build().unwrap_or_default()
+}
+
+/// Adds the access token to a DevTools WebSocket URL the client builds itself. URLs taken from
+/// `/json` already carry it.
+pub fn with_token(ws_url: &str) -> String {
+    match auth_token() {
+        Some(token) => format!("{}?token={}", ws_url, token),
+        None => ws_url.to_string(),
+    }
+}
+
+/// Returns the browser-level DevTools websocket advertised on `/json/version`.
+pub fn browser_ws_url() -> Result<String> {
+    let version: Value = http_client()
+        .get(format!("{}/json/version", devtools_url()))
+        .send()?
+        .json()?;
+    version["webSocketDebuggerUrl"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("webSocketDebuggerUrl missing from /json/version"))
+}
+
+/// Connects to the browser-level DevTools endpoint.
+pub fn connect_to_browser() -> Result<Browser> {
+    connect_browser(browser_ws_url()?)
+}
+
+/// Connects to a DevTools WebSocket, `ws://` or `wss://`.
+pub fn connect_browser(ws_url: String) -> Result<Browser> {
+    match ca_cert() {
+        Some(ca) => Browser::connect_with_root_cert(ws_url, ca),
+        None => Browser::connect(ws_url),
+    }
+}
+
+/// Opens a raw WebSocket to `ws_url`, trusting `BROWSER_FOR_REMOTE_CA` for `wss://`.
+fn open_websocket(ws_url: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let Some(ca) = ca_cert() else {
+        return Ok(tungstenite::connect(ws_url)?.0);
+    };
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for cert in rustls_pemfile::certs(&mut ca.as_slice()) {
+        roots.add(cert?)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let url = Url::parse(ws_url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("{} has no host", ws_url))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("{} has no port", ws_url))?;
+    let stream = TcpStream::connect((host, port))?;
+    let connector = Connector::Rustls(Arc::new(config));
+    let (socket, _) = tungstenite::client_tls_with_config(
+        ws_url.into_client_request()?,
+        stream,
+        None,
+        Some(connector),
+    )
+    .map_err(|e| anyhow!("Failed to connect to {}: {}", ws_url, e))?;
+    Ok(socket)
+}
+
+/// Sends a single DevTools command on its own browser-level connection and returns its result.
+///
+/// Used for browser-only methods that `headless_chrome` does not expose with all their parameters.
+pub fn send_browser_command(method: &str, params: Value) -> Result<Value> {
+    let mut socket = open_websocket(&browser_ws_url()?)?;
+    let request = json!({ "id": 1, "method": method, "params": params });
+    socket.send(Message::text(request.to_string()))?;
+    loop {
+        let message = socket.read()?;
+        let response: Value = match message.to_text() {
+            Ok(text) => serde_json::from_str(text)?,
+            Err(_) => continue,
+        };
+        if response["id"] != 1 {
+            continue;
+        }
+        let _ = socket.close(None);
+        if let Some(error) = response.get("error") {
+            return Err(anyhow!("{} failed: {}", method, error));
+        }
+        return Ok(response["result"].clone());
+    }
+}
+
+/// Waits for the browser to report the tab with `target_id` and returns a handle to it.
+pub fn find_tab(browser: &Browser, target_id: &str) -> Result<Arc<Tab>> {
+    for _ in 0..50 {
+        if let Some(tab) = browser
+            .get_tabs()
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|tab| tab.get_target_id() == target_id)
+        {
+            return Ok(Arc::clone(tab));
+        }
+        sleep(Duration::from_millis(100));
+    }
+    Err(anyhow!("Tab {} not found in browser", target_id))
+}
+
+/// Returns the id of the first page tab whose host is `domain` or one of its subdomains.
+pub fn find_page_for_domain(domain: &str) -> Result<Option<String>> {
+    let data: Value = http_client()
+        .get(format!("{}/json", devtools_url()))
+        .send()?
+        .json()?;
+    let pages = data
+        .as_array()
+        .ok_or_else(|| anyhow!("Data is not an array"))?;
+    Ok(page_for_domain(pages, domain))
+}
+
+/// The id of the first page in a `/json` tab list whose host is `domain` or a subdomain.
+fn page_for_domain(pages: &[Value], domain: &str) -> Option<String> {
+    pages
+        .iter()
+        .filter(|tab| tab["type"] == "page")
+        .find(|tab| {
+            tab["url"]
+                .as_str()
+                .and_then(|url| Url::parse(url).ok())
+                .and_then(|url| url.host_str().map(|host| host_in_domain(host, domain)))
+                .unwrap_or(false)
+        })
+        .and_then(|tab| tab["id"].as_str().map(String::from))
+}
+
+/// Whether `host` is `domain` or one of its subdomains. Unlike cookie matching this only goes
+/// one way: a tab on the parent domain shows a different origin.
+fn host_in_domain(host: &str, domain: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_match_the_domain_and_its_subdomains_only() {
+        let pages = vec![
+            json!({ "id": "parent", "type": "page", "url": "https://example.com/" }),
+            json!({ "id": "worker", "type": "service_worker", "url": "https://app.example.com/sw.js" }),
+            json!({ "id": "app", "type": "page", "url": "https://app.example.com/inbox" }),
+        ];
+        assert_eq!(
+            page_for_domain(&pages, "app.example.com").as_deref(),
+            Some("app")
+        );
+        assert_eq!(
+            page_for_domain(&pages, "example.com").as_deref(),
+            Some("parent")
+        );
+        assert_eq!(page_for_domain(&pages, "other.example.com"), None);
+        assert!(host_in_domain("a.b.example.com", ".Example.com"));
+        assert!(!host_in_domain("notexample.com", "example.com"));
+    }
+}
@@ -0,0 +1,186 @@
+// wait.rs
+
+use crate::utils::{devtools_url, http_client, log_message};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const NEWTAB_URL: &str = "chrome://newtab/";
+
+/// Delays that double from `initial` up to `max`, until an optional deadline passes.
+pub struct Backoff {
+    delay: Duration,
+    max: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Backoff {
+    /// `timeout` of `None` retries forever.
+    pub fn new(initial: Duration, max: Duration, timeout: Option<Duration>) -> Self {
+        Backoff {
+            delay: initial,
+            max,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// The next delay, cut short at the deadline, or `None` once the deadline has passed.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let delay = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return None;
+                }
+                self.delay.min(remaining)
+            }
+            None => self.delay,
+        };
+        self.delay = (self.delay * 2).min(self.max);
+        Some(delay)
+    }
+}
+
+/// How the legacy path waits for a `chrome://newtab/` page to claim.
+pub struct NewtabWait {
+    /// Gives up after this long; `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Opens a newtab page through `/json/new` when none is open.
+    pub create: bool,
+}
+
+/// Returns the id of an open `chrome://newtab/` page, waiting with exponential backoff for one
+/// to appear. Fails with the reason of the last attempt once `wait.timeout` passes.
+pub fn wait_for_newtab(wait: &NewtabWait) -> Result<String> {
+    let started = Instant::now();
+    let mut backoff = Backoff::new(
+        Duration::from_millis(500),
+        Duration::from_secs(30),
+        wait.timeout,
+    );
+    loop {
+        let reason = match newtab_page(wait.create) {
+            Ok(tab_id) => return Ok(tab_id),
+            Err(err) => err.to_string(),
+        };
+        match backoff.next_delay() {
+            Some(delay) => {
+                log_message(
+                    &format!("{}, retrying in {:.1}s", reason, delay.as_secs_f64()),
+                    "INFO",
+                );
+                sleep(delay);
+            }
+            None => {
+                return Err(anyhow!(
+                    "Gave up waiting for a {} page after {}s: {}",
+                    NEWTAB_URL,
+                    started.elapsed().as_secs(),
+                    reason
+                ))
+            }
+        }
+    }
+}
+
+/// One attempt: the first open newtab page, or a new one when `create` is set.
+fn newtab_page(create: bool) -> Result<String> {
+    let data: Value = http_client()
+        .get(format!("{}/json", devtools_url()))
+        .send()
+        .and_then(|response| response.json())
+        .map_err(|err| anyhow!("Failed to list tabs: {}", err))?;
+    let pages = data
+        .as_array()
+        .ok_or_else(|| anyhow!("Tab list is not an array"))?;
+    let newtab = pages
+        .iter()
+        .find(|tab| tab["type"] == "page" && tab["url"] == NEWTAB_URL);
+    if let Some(tab) = newtab {
+        return tab["id"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("Tab ID is missing or not a string"));
+    }
+    if !create {
+        return Err(anyhow!("No {} page is open", NEWTAB_URL));
+    }
+
+    // Chrome only opens tabs on a PUT since version 111.
+    let response = http_client()
+        .put(format!("{}/json/new?{}", devtools_url(), NEWTAB_URL))
+        .send()
+        .map_err(|err| anyhow!("Failed to open a {} page: {}", NEWTAB_URL, err))?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Failed to open a {} page: {}",
+            NEWTAB_URL,
+            response.status()
+        ));
+    }
+    let tab: Value = response.json()?;
+    let tab_id = tab["id"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("/json/new returned no tab id"))?;
+    log_message(&format!("Opened {} page {}", NEWTAB_URL, tab_id), "INFO");
+    Ok(tab_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_devtools::MockDevTools;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5), None);
+        let delays: Vec<u64> = (0..5)
+            .map(|_| backoff.next_delay().unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn backoff_stops_at_the_deadline() {
+        let mut backoff = Backoff::new(
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            Some(Duration::from_millis(50)),
+        );
+        assert!(backoff.next_delay().unwrap() <= Duration::from_millis(50));
+        sleep(Duration::from_millis(60));
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    // Both cases share one test: `devtools_url` comes from the process environment.
+    #[test]
+    fn newtab_is_claimed_or_created_and_the_wait_gives_up_with_a_reason() {
+        let mock = MockDevTools::start();
+        mock.open_target("http://busy.test/");
+        std::env::set_var("BROWSER_DEVTOOLS_URL", mock.http_url());
+
+        let err = wait_for_newtab(&NewtabWait {
+            timeout: Some(Duration::from_millis(300)),
+            create: false,
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("Gave up waiting"));
+        assert!(err.to_string().contains("No chrome://newtab/ page is open"));
+
+        let created = wait_for_newtab(&NewtabWait {
+            timeout: Some(Duration::from_secs(5)),
+            create: true,
+        })
+        .unwrap();
+        assert_eq!(mock.target_url(&created).as_deref(), Some(NEWTAB_URL));
+
+        let claimed = wait_for_newtab(&NewtabWait {
+            timeout: Some(Duration::from_secs(5)),
+            create: false,
+        })
+        .unwrap();
+        assert_eq!(claimed, created);
+    }
+}
@@ -0,0 +1,186 @@
+// job.rs
+//
+// Runs a job end to end: a browser-for-remote manager launched headless, this client
+// leasing a tab from it and loading a page from a local fixture server. Skipped when the
+// manager binary or a browser isn't available.
+
+use serde_json::json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The access token the manager is configured with.
+const TOKEN: &str = "job-test-token";
+
+/// The manager binary: `BROWSER_FOR_REMOTE_BIN`, or the sibling crate's debug build.
+fn manager_bin() -> Option<PathBuf> {
+    let path = std::env::var("BROWSER_FOR_REMOTE_BIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../browser-for-remote/target/debug/browser-for-remote")
+        });
+    path.exists().then_some(path)
+}
+
+fn browser_available(manager: &PathBuf) -> bool {
+    Command::new(manager)
+        .arg("--list-browsers")
+        .env(
+            "BROWSER_FOR_REMOTE_CONFIG",
+            "/nonexistent/browser-for-remote.json",
+        )
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.starts_with('*'))
+        })
+        .unwrap_or(false)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Serves a single page on every path and records the paths requested.
+fn start_fixture_server() -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&requests);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            let _ = reader.read_line(&mut request_line);
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            if let Some(path) = request_line.split_whitespace().nth(1) {
+                seen.lock().unwrap().push(path.to_string());
+            }
+            let body = "<!doctype html><html><body><h1>Job fixture</h1></body></html>";
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    (port, requests)
+}
+
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = Command::new("kill")
+            .args(["-TERM", &self.0.id().to_string()])
+            .status();
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(15) {
+            if let Ok(Some(_)) = self.0.try_wait() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = self.0.kill();
+    }
+}
+
+#[test]
+fn job_leases_a_tab_loads_the_page_and_releases_it() {
+    let Some(manager_bin) = manager_bin() else {
+        eprintln!("skipping: browser-for-remote is not built");
+        return;
+    };
+    if !browser_available(&manager_bin) {
+        eprintln!("skipping: no Chrome/Chromium found");
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let control_port = free_port();
+    let debugging_port = free_port();
+    let config = json!({
+        "control": { "address": format!("127.0.0.1:{}", control_port) },
+        "auth": {
+            "token": TOKEN,
+            "devtools_address": format!("127.0.0.1:{}", debugging_port),
+        },
+        "browser": {
+            "mode": "new-headless",
+            "profile_dir": dir.path().join("profile"),
+            "allow_no_sandbox": true,
+        },
+        "state": { "path": dir.path().join("state.json"), "restore": "none" },
+    });
+    let config_path = dir.path().join("config.json");
+    std::fs::write(&config_path, config.to_string()).unwrap();
+    let _manager = KillOnDrop(
+        Command::new(&manager_bin)
+            .env("BROWSER_FOR_REMOTE_CONFIG", &config_path)
+            .env_remove("DISPLAY")
+            .env_remove("WAYLAND_DISPLAY")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let devtools_url = format!("http://127.0.0.1:{}", debugging_port);
+    let control_url = format!("http://127.0.0.1:{}", control_port);
+    let started = Instant::now();
+    let client = reqwest::blocking::Client::new();
+    // The gateway answers 503 until the browser is up
+    while !client
+        .get(format!("{}/json/version", devtools_url))
+        .bearer_auth(TOKEN)
+        .send()
+        .is_ok_and(|response| response.status().is_success())
+    {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "browser never came up"
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let (fixture_port, requests) = start_fixture_server();
+    let output = Command::new(env!("CARGO_BIN_EXE_remote-for-browser"))
+        .args([
+            "--url",
+            &format!("http://127.0.0.1:{}/job", fixture_port),
+            "--stay",
+            "1",
+        ])
+        .env("BROWSER_FOR_REMOTE_CONTROL", &control_url)
+        .env("BROWSER_DEVTOOLS_URL", &devtools_url)
+        .env("BROWSER_FOR_REMOTE_TOKEN", TOKEN)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "job failed:\n{}", stdout);
+    assert!(requests.lock().unwrap().iter().any(|path| path == "/job"));
+
+    let leases: serde_json::Value = client
+        .get(format!("{}/leases", control_url))
+        .bearer_auth(TOKEN)
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(leases, json!([]), "lease was not released");
+}